@@ -5,6 +5,7 @@ use std::io::Write;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
@@ -14,13 +15,13 @@ use pulldown_cmark::{Event, Parser as MdParser, Tag, TagEnd};
 use regex::Regex;
 use notify::{RecursiveMode, Watcher, Config as NotifyConfig};
 use rusqlite::{Connection, params};
-use toml;
 use glob::glob;
 use serde::Serialize;
 use serde_json::json;
 use tantivy::collector::TopDocs;
 use tantivy::query::QueryParser;
-use tantivy::schema::{Field, Schema, STORED, STRING, TEXT, FAST, Value};
+use tantivy::schema::{Facet, FacetOptions, Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, STORED, STRING, TEXT, FAST, Value};
+use tantivy::tokenizer::{BoxTokenStream, Language, LowerCaser, RawTokenizer, RemoveLongFilter, SimpleTokenizer, Stemmer, StopWordFilter, TextAnalyzer, Token, TokenStream, Tokenizer};
 use tantivy::{doc, Index, IndexReader, TantivyDocument, Term};
 use walkdir::WalkDir;
 
@@ -41,6 +42,9 @@ enum Commands {
         vault: String,
         #[arg(long, default_value = "./.obsidx")]
         index: String,
+        /// Text analyzer: default, auto, cjk or a stemming language such as english
+        #[arg(long)]
+        analyzer: Option<String>,
     },
     /// Build or update the index
     Index {
@@ -52,6 +56,16 @@ enum Commands {
         incremental: bool,
         #[arg(long)]
         collection: Option<String>,
+        /// Parser threads (0 = all cores)
+        #[arg(long, default_value_t = 0)]
+        jobs: usize,
+        /// Report scan progress on stderr
+        #[arg(long, default_value_t = false)]
+        progress: bool,
+        /// Text analyzer: default, auto, cjk or a stemming language such as english.
+        /// Changing it rebuilds the index from scratch.
+        #[arg(long)]
+        analyzer: Option<String>,
     },
     /// Search the index
     Search {
@@ -65,12 +79,49 @@ enum Commands {
         json: bool,
         #[arg(long)]
         collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
         #[arg(long, default_value_t = 0.0)]
         min_score: f32,
         #[arg(long, default_value_t = false)]
         files: bool,
         #[arg(long, default_value_t = false)]
         all: bool,
+        /// Snippet length in characters (0 disables snippets)
+        #[arg(long, default_value_t = 160)]
+        snippet_len: usize,
+        /// Marker inserted before each highlighted match
+        #[arg(long, default_value = "**")]
+        highlight_pre: String,
+        /// Marker inserted after each highlighted match
+        #[arg(long, default_value = "**")]
+        highlight_post: String,
+        /// Per-field boosts, e.g. `title=2,heading=1.5` (overrides config.toml)
+        #[arg(long)]
+        boost: Option<String>,
+        /// Typo tolerance: match terms within this edit distance (0-2)
+        #[arg(long, default_value_t = 0)]
+        fuzzy: u8,
+        /// Treat each term as a word prefix (`proj` matches `project`)
+        #[arg(long, default_value_t = false)]
+        prefix: bool,
+        /// Recover from query syntax errors instead of failing (issues are reported)
+        #[arg(long, default_value_t = false)]
+        lenient: bool,
+        /// Facet counts over all matches: any of tags,folder,collection,year
+        #[arg(long)]
+        facets: Option<String>,
+        /// Result order: relevance, mtime (newest first), title or path
+        #[arg(long, value_enum, default_value_t = SortOrder::Relevance)]
+        sort: SortOrder,
+        /// Skip this many results; pages shift if the index changes in between
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Include how each score was computed
+        #[arg(long, default_value_t = false)]
+        explain: bool,
     },
     /// Get a note by path
     Get {
@@ -92,6 +143,10 @@ enum Commands {
         index: String,
         #[arg(long, default_value_t = false)]
         json: bool,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
     },
     /// Link graph queries
     Links {
@@ -110,6 +165,10 @@ enum Commands {
         index: String,
         #[arg(long, default_value_t = false)]
         json: bool,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
     },
     /// Watch vault and incrementally reindex
     Watch {
@@ -119,6 +178,9 @@ enum Commands {
         index: String,
         #[arg(long, default_value_t = 500)]
         debounce_ms: u64,
+        /// Run `optimize` once the vault has been quiet this long after a reindex (0 = never)
+        #[arg(long, default_value_t = 0)]
+        optimize_idle_secs: u64,
     },
     /// Build embeddings index (SQLite)
     EmbedIndex {
@@ -126,9 +188,9 @@ enum Commands {
         vault: String,
         #[arg(long, default_value = "./.obsidx")]
         index: String,
-        #[arg(long, default_value_t = 1500)]
+        #[arg(long, default_value_t = DEFAULT_MAX_CHARS)]
         max_chars: usize,
-        #[arg(long, default_value_t = 200)]
+        #[arg(long, default_value_t = DEFAULT_OVERLAP)]
         overlap: usize,
         #[arg(long, default_value_t = false)]
         incremental: bool,
@@ -138,8 +200,30 @@ enum Commands {
         embed_backend: EmbeddingBackend,
         #[arg(long)]
         embed_model: Option<String>,
+        /// Embedding size (hash backend; checked against other backends). Defaults to
+        /// [embedding] dims in the config, the index's current size with --incremental, or 256.
+        #[arg(long)]
+        embed_dims: Option<usize>,
         #[arg(long, value_enum, default_value_t = VectorBackend::Bruteforce)]
         vector_backend: VectorBackend,
+        /// Stored vector format (default: keep the index's current one, f32 for new indexes)
+        #[arg(long, value_enum)]
+        vector_encoding: Option<VectorEncoding>,
+        /// HNSW links per node (used when the graph is built from scratch)
+        #[arg(long, default_value_t = HnswOptions::default().m)]
+        hnsw_m: usize,
+        /// HNSW candidate list size while building
+        #[arg(long, default_value_t = HnswOptions::default().ef_construction)]
+        hnsw_ef_construction: usize,
+        /// Default HNSW search breadth stored with the graph
+        #[arg(long, default_value_t = HnswOptions::default().ef)]
+        hnsw_ef: usize,
+        /// Parser threads (0 = all cores)
+        #[arg(long, default_value_t = 0)]
+        jobs: usize,
+        /// Report scan progress on stderr
+        #[arg(long, default_value_t = false)]
+        progress: bool,
     },
     /// Vector search over embeddings
     EmbedSearch {
@@ -153,18 +237,34 @@ enum Commands {
         json: bool,
         #[arg(long)]
         collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
         #[arg(long, default_value_t = 0.0)]
         min_score: f32,
         #[arg(long, default_value_t = false)]
         files: bool,
         #[arg(long, default_value_t = false)]
         all: bool,
+        /// Result order: relevance, mtime (newest first), title or path
+        #[arg(long, value_enum, default_value_t = SortOrder::Relevance)]
+        sort: SortOrder,
+        /// Skip this many results; pages shift if the index changes in between
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Include how each score was computed
+        #[arg(long, default_value_t = false)]
+        explain: bool,
         #[arg(long, value_enum, default_value_t = EmbeddingBackend::Hash)]
         embed_backend: EmbeddingBackend,
         #[arg(long)]
         embed_model: Option<String>,
         #[arg(long, value_enum, default_value_t = VectorBackend::Bruteforce)]
         vector_backend: VectorBackend,
+        /// HNSW search breadth (default: the value stored with the graph)
+        #[arg(long)]
+        hnsw_ef: Option<usize>,
     },
     /// Hybrid search (BM25 + Vector) with RRF
     Hybrid {
@@ -184,6 +284,10 @@ enum Commands {
         json: bool,
         #[arg(long)]
         collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
         #[arg(long, default_value_t = 0.0)]
         min_score: f32,
         #[arg(long, default_value_t = false)]
@@ -192,12 +296,48 @@ enum Commands {
         all: bool,
         #[arg(long, default_value_t = 2)]
         expand: u32,
+        /// Snippet length in characters (0 disables snippets)
+        #[arg(long, default_value_t = 160)]
+        snippet_len: usize,
+        /// Marker inserted before each highlighted match
+        #[arg(long, default_value = "**")]
+        highlight_pre: String,
+        /// Marker inserted after each highlighted match
+        #[arg(long, default_value = "**")]
+        highlight_post: String,
+        /// Per-field boosts, e.g. `title=2,heading=1.5` (overrides config.toml)
+        #[arg(long)]
+        boost: Option<String>,
+        /// Typo tolerance: match terms within this edit distance (0-2)
+        #[arg(long, default_value_t = 0)]
+        fuzzy: u8,
+        /// Treat each term as a word prefix (`proj` matches `project`)
+        #[arg(long, default_value_t = false)]
+        prefix: bool,
+        /// Recover from query syntax errors instead of failing (issues are reported)
+        #[arg(long, default_value_t = false)]
+        lenient: bool,
+        /// Facet counts over all matches: any of tags,folder,collection,year
+        #[arg(long)]
+        facets: Option<String>,
+        /// Result order: relevance, mtime (newest first), title or path
+        #[arg(long, value_enum, default_value_t = SortOrder::Relevance)]
+        sort: SortOrder,
+        /// Skip this many results; pages shift if the index changes in between
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Include how each score was computed
+        #[arg(long, default_value_t = false)]
+        explain: bool,
         #[arg(long, value_enum, default_value_t = EmbeddingBackend::Hash)]
         embed_backend: EmbeddingBackend,
         #[arg(long)]
         embed_model: Option<String>,
         #[arg(long, value_enum, default_value_t = VectorBackend::Bruteforce)]
         vector_backend: VectorBackend,
+        /// HNSW search breadth (default: the value stored with the graph)
+        #[arg(long)]
+        hnsw_ef: Option<usize>,
     },
     /// Create a note (optionally from stdin)
     NoteCreate {
@@ -213,10 +353,12 @@ enum Commands {
         reindex: bool,
         #[arg(long, default_value = "./.obsidx")]
         index: String,
-        #[arg(long, default_value_t = 1500)]
-        max_chars: usize,
-        #[arg(long, default_value_t = 200)]
-        overlap: usize,
+        /// Defaults to the chunk size recorded by the last `embed-index`.
+        #[arg(long)]
+        max_chars: Option<usize>,
+        /// Defaults to the overlap recorded by the last `embed-index`.
+        #[arg(long)]
+        overlap: Option<usize>,
     },
     /// Append to a note (optionally from stdin)
     NoteAppend {
@@ -232,10 +374,12 @@ enum Commands {
         reindex: bool,
         #[arg(long, default_value = "./.obsidx")]
         index: String,
-        #[arg(long, default_value_t = 1500)]
-        max_chars: usize,
-        #[arg(long, default_value_t = 200)]
-        overlap: usize,
+        /// Defaults to the chunk size recorded by the last `embed-index`.
+        #[arg(long)]
+        max_chars: Option<usize>,
+        /// Defaults to the overlap recorded by the last `embed-index`.
+        #[arg(long)]
+        overlap: Option<usize>,
     },
     /// Manage collections
     CollectionAdd {
@@ -261,10 +405,113 @@ enum Commands {
         json: bool,
         #[arg(long)]
         collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
+    },
+    /// Notes similar to a given note: more-like-this terms plus embeddings, fused with RRF
+    Related {
+        /// Note path, or `#<doc_id>`
+        #[arg(long)]
+        path: String,
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+        #[arg(long, default_value_t = 60)]
+        rrf_k: u32,
+        #[arg(long, default_value_t = false)]
+        json: bool,
+        #[arg(long)]
+        collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
+        /// Leave out notes this note already links to
+        #[arg(long, default_value_t = false)]
+        exclude_linked: bool,
+        /// How the note's chunk embeddings are compared with other notes' chunks
+        #[arg(long, value_enum, default_value_t = RelatedVectorMode::Centroid)]
+        vector_mode: RelatedVectorMode,
+        #[arg(long, default_value_t = false)]
+        files: bool,
+    },
+    /// Exact regex or literal matches over indexed content, line by line
+    Grep {
+        /// Regular expression (Rust syntax), or a literal with --fixed-strings
+        #[arg(long)]
+        pattern: String,
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        /// Treat the pattern as a literal string
+        #[arg(long, default_value_t = false)]
+        fixed_strings: bool,
+        #[arg(long, default_value_t = false)]
+        ignore_case: bool,
+        /// Lines of context before and after each match
+        #[arg(long, default_value_t = 0)]
+        context: usize,
+        /// Maximum matching lines to return
+        #[arg(long, default_value_t = 200)]
+        limit: usize,
+        #[arg(long, default_value_t = false)]
+        json: bool,
+        #[arg(long)]
+        collection: Option<String>,
+        /// Only notes under this folder (relative to the collection root, or absolute);
+        /// prefix with `!` to exclude. Repeatable.
+        #[arg(long = "folder", allow_hyphen_values = true)]
+        folder: Vec<String>,
+        /// Only list the notes that match
+        #[arg(long, default_value_t = false)]
+        files: bool,
     },
     /// MCP stdio server
     Mcp {},
+    /// Check that the vault, tantivy index, catalog and embeddings agree
+    Verify {
+        #[arg(long)]
+        vault: String,
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        #[arg(long)]
+        collection: Option<String>,
+        /// Fix what can be fixed incrementally
+        #[arg(long, default_value_t = false)]
+        repair: bool,
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Merge segments, collect garbage and vacuum the SQLite stores
+    Optimize {
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Export the index (notes, chunks, embeddings) as JSONL
+    Export {
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        /// Output file (stdout if omitted)
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Rebuild an index from an `export` dump
+    Import {
+        #[arg(long, default_value = "./.obsidx")]
+        index: String,
+        /// Input file (stdin if omitted)
+        #[arg(long)]
+        input: Option<String>,
+        /// Vault the exported notes live in now (defaults to the exported vault root)
+        #[arg(long)]
+        vault: Option<String>,
+    },
     /// Index stats
+    #[command(alias = "status")]
     Stats {
         #[arg(long, default_value = "./.obsidx")]
         index: String,
@@ -283,24 +530,396 @@ enum Commands {
     },
 }
 
+#[derive(Debug, Serialize)]
+struct FacetValue {
+    value: String,
+    count: u64,
+}
+
 #[derive(Debug, Serialize)]
 struct SearchResult {
     path: String,
     title: String,
     score: f32,
     doc_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heading: Option<String>,
+    /// tantivy's score breakdown, with `--explain`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explain: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct HybridResult {
+    path: String,
+    score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heading: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explain: Option<HybridExplain>,
+}
+
+/// One ranked list's vote for a note in hybrid reciprocal rank fusion.
+#[derive(Debug, Clone, Serialize)]
+struct RrfContribution {
+    query: String,
+    /// `bm25` or `vector`.
+    signal: &'static str,
+    /// 1-based position in that list.
+    rank: usize,
+    /// The list's own score: BM25, or cosine similarity of the chunk.
+    score: f32,
+    /// 2 for the original query, 1 for expansions.
+    weight: f32,
+    /// `weight / (rrf_k + rank)`.
+    rrf: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct HybridExplain {
+    /// Best ranks for the original query; None when absent from that list.
+    bm25_rank: Option<usize>,
+    vector_rank: Option<usize>,
+    rrf_k: u32,
+    contributions: Vec<RrfContribution>,
+    /// What the original query's double weight added over an expansion.
+    original_query_bonus: f32,
+    score: f32,
+}
+
+#[derive(Debug, Clone)]
+struct SnippetOptions {
+    /// Maximum fragment length in characters; 0 disables snippets.
+    len: usize,
+    pre: String,
+    post: String,
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        SnippetOptions { len: 160, pre: "**".to_string(), post: "**".to_string() }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum SortOrder {
+    #[default]
+    Relevance,
+    /// Most recently modified first
+    Mtime,
+    Title,
+    Path,
+}
+
+/// Most results a single `--all` page returns; more are reported via `truncated`.
+const MAX_PAGE_RESULTS: usize = 10_000;
+
+#[derive(Debug, Clone, Default)]
+struct PageOptions {
+    sort: SortOrder,
+    offset: usize,
+}
+
+impl PageOptions {
+    fn page_size(&self, limit: usize, all: bool) -> usize {
+        if all { MAX_PAGE_RESULTS } else { limit }
+    }
+
+    fn page_info(&self, total_hits: usize, returned: usize) -> PageInfo {
+        let end = self.offset + returned;
+        let truncated = end < total_hits;
+        PageInfo {
+            total_hits,
+            offset: self.offset,
+            truncated,
+            next_offset: truncated.then_some(end),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PageInfo {
+    total_hits: usize,
+    offset: usize,
+    truncated: bool,
+    /// `--offset` for the next page. Offsets are positions in the current result
+    /// list, so notes indexed between requests can shift or repeat hits.
+    next_offset: Option<usize>,
+}
+
+impl PageInfo {
+    fn merge_into(self, data: &mut serde_json::Value) {
+        if let (Some(map), Ok(serde_json::Value::Object(page))) = (data.as_object_mut(), serde_json::to_value(self)) {
+            map.extend(page);
+        }
+    }
+}
+
+/// What a hit is ordered by; ties fall back to path so pages stay stable.
+struct SortKeys {
+    score: f32,
+    mtime: i64,
+    title: String,
+    path: String,
+}
+
+fn compare_sort_keys(a: &SortKeys, b: &SortKeys, sort: SortOrder) -> std::cmp::Ordering {
+    let primary = match sort {
+        SortOrder::Relevance => b.score.total_cmp(&a.score),
+        SortOrder::Mtime => b.mtime.cmp(&a.mtime),
+        SortOrder::Title => a.title.to_lowercase().cmp(&b.title.to_lowercase()),
+        SortOrder::Path => std::cmp::Ordering::Equal,
+    };
+    primary.then_with(|| a.path.cmp(&b.path))
+}
+
+/// Sorts path-keyed hits from embed-search and hybrid, taking title and mtime from the catalog.
+fn sort_path_hits<T>(index_dir: &str, hits: &mut [T], sort: SortOrder, key: impl Fn(&T) -> (&str, f32)) -> Result<()> {
+    let meta: HashMap<String, (String, i64)> = if sort == SortOrder::Title || sort == SortOrder::Mtime {
+        catalog_note_meta(index_dir)?
+    } else {
+        HashMap::new()
+    };
+    let keys = |item: &T| {
+        let (path, score) = key(item);
+        let (title, mtime) = meta.get(path).cloned().unwrap_or_default();
+        SortKeys { score, mtime, title, path: path.to_string() }
+    };
+    hits.sort_by(|a, b| compare_sort_keys(&keys(a), &keys(b), sort));
+    Ok(())
+}
+
+fn catalog_note_meta(index_dir: &str) -> Result<HashMap<String, (String, i64)>> {
+    if !catalog_path(index_dir).exists() {
+        return Ok(HashMap::new());
+    }
+    let conn = open_catalog(index_dir)?;
+    let mut stmt = conn.prepare("SELECT path, title, mtime FROM notes")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, (row.get::<_, String>(1)?, row.get::<_, i64>(2)?))))?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// The first `want` of `matched`, ordered by title or path. Keys come from the
+/// catalog and each note is mapped to its hit through the `path` term, so stored
+/// documents are only read for hits the catalog doesn't know about.
+fn catalog_sorted_hits(
+    index_dir: &str,
+    searcher: &tantivy::Searcher,
+    matched: &std::collections::HashSet<tantivy::DocAddress>,
+    sort: SortOrder,
+    want: usize,
+) -> Result<Vec<tantivy::DocAddress>> {
+    use tantivy::DocSet;
+
+    let path_field = searcher.schema().get_field("path")?;
+    let mut notes: Vec<SortKeys> = catalog_note_meta(index_dir)?
+        .into_iter()
+        .map(|(path, (title, mtime))| SortKeys { score: 0.0, mtime, title, path })
+        .collect();
+    notes.sort_by(|a, b| compare_sort_keys(a, b, sort));
+
+    let mut ordered = Vec::new();
+    for note in &notes {
+        if ordered.len() >= want || ordered.len() == matched.len() {
+            return Ok(ordered);
+        }
+        let term = Term::from_field_text(path_field, &note.path);
+        for (ord, segment) in searcher.segment_readers().iter().enumerate() {
+            let Some(mut postings) = segment.inverted_index(path_field)?.read_postings(&term, IndexRecordOption::Basic)? else {
+                continue;
+            };
+            while postings.doc() != tantivy::TERMINATED {
+                let addr = tantivy::DocAddress::new(ord as u32, postings.doc());
+                if matched.contains(&addr) {
+                    ordered.push(addr);
+                }
+                postings.advance();
+            }
+        }
+    }
+
+    // Hits missing from the catalog (not built yet, or lagging) go last.
+    let found: std::collections::HashSet<_> = ordered.iter().copied().collect();
+    let title_field = searcher.schema().get_field("title")?;
+    let mut rest = Vec::new();
+    for addr in matched.iter().filter(|a| !found.contains(a)) {
+        let doc: TantivyDocument = searcher.doc(*addr)?;
+        let text = |f: Field| doc.get_first(f).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        rest.push((SortKeys { score: 0.0, mtime: 0, title: text(title_field), path: text(path_field) }, *addr));
+    }
+    rest.sort_by(|a, b| compare_sort_keys(&a.0, &b.0, sort));
+    ordered.extend(rest.into_iter().map(|(_, addr)| addr));
+    Ok(ordered)
+}
+
+fn page_slice<T>(items: Vec<T>, page: &PageOptions, size: usize) -> Vec<T> {
+    items.into_iter().skip(page.offset).take(size).collect()
 }
 
 #[derive(clap::ValueEnum, Clone, Debug)]
 enum EmbeddingBackend {
     Hash,
-    Ort,
+    /// Local sentence-transformer run in-process with candle (`--embed-model <dir>`).
+    #[value(alias = "ort")]
+    Candle,
+    /// External program from `[embedding.command]`: JSON texts on stdin, vectors on stdout.
+    Command,
+    /// OpenAI- or Ollama-compatible endpoint from `[embedding.http]`.
+    Http,
+}
+
+/// Layout of the `chunks.embedding` BLOB, recorded as `vector_encoding` in the
+/// embeddings meta table.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum VectorEncoding {
+    /// Little-endian f32 per dimension.
+    F32,
+    /// Little-endian IEEE half floats: half the size, about three significant digits.
+    F16,
+    /// A little-endian f32 scale, then one i8 per dimension (value = i8 * scale).
+    Int8,
+}
+
+impl VectorEncoding {
+    fn encode(self, v: &[f32]) -> Vec<u8> {
+        match self {
+            VectorEncoding::F32 => v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            VectorEncoding::F16 => v.iter().flat_map(|x| half::f16::from_f32(*x).to_le_bytes()).collect(),
+            VectorEncoding::Int8 => {
+                let max = v.iter().fold(0f32, |m, x| m.max(x.abs()));
+                let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                let mut out = scale.to_le_bytes().to_vec();
+                out.extend(v.iter().map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8 as u8));
+                out
+            }
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Vec<f32> {
+        match self {
+            VectorEncoding::F32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+            VectorEncoding::F16 => bytes.chunks_exact(2).map(|b| half::f16::from_le_bytes([b[0], b[1]]).to_f32()).collect(),
+            VectorEncoding::Int8 => {
+                let Some((scale, values)) = bytes.split_first_chunk::<4>() else {
+                    return Vec::new();
+                };
+                let scale = f32::from_le_bytes(*scale);
+                values.iter().map(|b| *b as i8 as f32 * scale).collect()
+            }
+        }
+    }
+
+    /// Dimensions stored in a BLOB of `len` bytes.
+    fn dims(self, len: usize) -> usize {
+        match self {
+            VectorEncoding::F32 => len / 4,
+            VectorEncoding::F16 => len / 2,
+            VectorEncoding::Int8 => len.saturating_sub(4),
+        }
+    }
+}
+
+/// The index's vector encoding; f32 when none is recorded. Never writes, so it is
+/// safe on read-only connections and without the write lock.
+fn stored_vector_encoding(conn: &Connection) -> Result<VectorEncoding> {
+    if let Some(name) = get_meta(conn, "vector_encoding") {
+        return <VectorEncoding as clap::ValueEnum>::from_str(&name, true)
+            .map_err(|_| anyhow::anyhow!("Unknown vector_encoding {name} in embeddings.db"));
+    }
+    let legacy = conn
+        .query_row("SELECT 1 FROM chunks WHERE typeof(embedding) = 'text' LIMIT 1", [], |_| Ok(()))
+        .is_ok();
+    if legacy {
+        anyhow::bail!("embeddings.db stores JSON vectors from an older version; run `obsidx embed-index --incremental` to convert them");
+    }
+    Ok(VectorEncoding::F32)
+}
+
+/// Converts JSON-text embeddings from before BLOB storage to f32 BLOBs and records
+/// the encoding. Rows that don't hold a JSON array of numbers are deleted along with
+/// their note's mtime, so the next embed-index embeds that note again; their ids are
+/// returned. Callers hold the write lock.
+fn migrate_json_embeddings(conn: &Connection) -> Result<Vec<i64>> {
+    if get_meta(conn, "vector_encoding").is_some() {
+        return Ok(Vec::new());
+    }
+    let tx = conn.unchecked_transaction()?;
+    let rows: Vec<(i64, String, String)> = conn
+        .prepare("SELECT id, path, embedding FROM chunks WHERE typeof(embedding) = 'text'")?
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, Option<String>>(1)?.unwrap_or_default(), row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let migrated = rows.len();
+    let mut malformed = Vec::new();
+    let mut update = conn.prepare("UPDATE chunks SET embedding = ?1 WHERE id = ?2")?;
+    for (id, path, text) in rows {
+        match serde_json::from_str::<Vec<f32>>(&text) {
+            Ok(emb) if !emb.is_empty() => {
+                update.execute(params![VectorEncoding::F32.encode(&emb), id])?;
+            }
+            _ => {
+                conn.execute("DELETE FROM chunks WHERE id = ?1", params![id])?;
+                conn.execute("DELETE FROM notes WHERE path = ?1", params![path])?;
+                malformed.push(id);
+            }
+        }
+    }
+    drop(update);
+    set_meta(conn, "vector_encoding", "f32")?;
+    tx.commit()?;
+    if migrated > 0 {
+        // Give back the space the JSON text took.
+        conn.execute_batch("VACUUM;")?;
+    }
+    Ok(malformed)
 }
 
 #[derive(clap::ValueEnum, Clone, Debug)]
 enum VectorBackend {
     Bruteforce,
     Vss,
+    Hnsw,
+    /// sqlite-vec's `vec0` virtual table.
+    Vec,
+}
+
+#[derive(Debug, Serialize)]
+struct GrepMatch {
+    /// 1-based line number in the note file.
+    line: usize,
+    text: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    before: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    after: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct GrepFile {
+    path: String,
+    title: String,
+    matches: Vec<GrepMatch>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RelatedVectorMode {
+    /// Cosine against the mean of the note's chunk embeddings
+    Centroid,
+    /// Best cosine between any of the note's chunks and a candidate's chunks
+    MaxSim,
+}
+
+#[derive(Debug, Serialize)]
+struct RelatedResult {
+    path: String,
+    title: String,
+    score: f32,
+    /// 1-based rank in the more-like-this and embedding lists, when present.
+    bm25_rank: Option<usize>,
+    vector_rank: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
@@ -338,24 +957,53 @@ struct NoteDoc {
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    match cli.command {
-        Commands::Init { vault, index } => init_index(&vault, &index),
+    let result = match cli.command {
+        Commands::Init { vault, index, analyzer } => analyzer
+            .as_deref()
+            .map(Analyzer::parse)
+            .transpose()
+            .and_then(|analyzer| init_index(&vault, &index, analyzer)),
         Commands::Index {
             vault,
             index,
             incremental,
             collection,
-        } => build_index(&vault, &index, incremental, collection),
+            jobs,
+            progress,
+            analyzer,
+        } => analyzer
+            .as_deref()
+            .map(Analyzer::parse)
+            .transpose()
+            .and_then(|analyzer| build_index(&vault, &index, incremental, collection, analyzer, &ScanOptions::cli(jobs, progress))),
         Commands::Search {
             query,
             index,
             limit,
             json,
             collection,
+            folder,
             min_score,
             files,
             all,
-        } => search_index(&index, &query, limit, json, collection, min_score, files, all),
+            snippet_len,
+            highlight_pre,
+            highlight_post,
+            boost,
+            fuzzy,
+            prefix,
+            lenient,
+            facets,
+            sort,
+            offset,
+            explain,
+        } => QueryOptions::with_boosts(boost.as_deref()).and_then(|o| o.with_matching(fuzzy, prefix)).and_then(|query_opts| {
+            let query_opts = QueryOptions { lenient, ..query_opts };
+            let facets = parse_facet_names(facets.as_deref())?;
+            let page = PageOptions { sort, offset };
+            let scope = FolderScope::resolve(&index, &folder)?;
+            search_index(&index, &query, limit, json, collection, &scope, min_score, files, all, &SnippetOptions { len: snippet_len, pre: highlight_pre, post: highlight_post }, &query_opts, &facets, &page, explain)
+        }),
         Commands::Get {
             path,
             index,
@@ -363,10 +1011,10 @@ fn main() -> Result<()> {
             content,
             collection,
         } => get_note(&index, &path, json, content, collection),
-        Commands::Tags { index, json } => list_tags(&index, json),
+        Commands::Tags { index, json, folder } => FolderScope::resolve(&index, &folder).and_then(|scope| list_tags(&index, json, &scope)),
         Commands::Links { from, index, json } => list_links(&index, &from, json),
-        Commands::Backlinks { to, index, json } => list_backlinks(&index, &to, json),
-        Commands::Watch { vault, index, debounce_ms } => watch_vault(&vault, &index, debounce_ms),
+        Commands::Backlinks { to, index, json, folder } => FolderScope::resolve(&index, &folder).and_then(|scope| list_backlinks(&index, &to, json, &scope)),
+        Commands::Watch { vault, index, debounce_ms, optimize_idle_secs } => watch_vault(&vault, &index, debounce_ms, optimize_idle_secs),
         Commands::EmbedIndex {
             vault,
             index,
@@ -376,27 +1024,171 @@ fn main() -> Result<()> {
             collection,
             embed_backend,
             embed_model,
+            embed_dims,
             vector_backend,
-        } => embed_index(&vault, &index, max_chars, overlap, incremental, collection, embed_backend, embed_model.as_deref(), vector_backend),
-        Commands::EmbedSearch { query, index, limit, json, collection, min_score, files, all, embed_backend, embed_model, vector_backend } => embed_search(&index, &query, limit, json, collection, min_score, files, all, embed_backend, embed_model.as_deref(), vector_backend),
-        Commands::Hybrid { query, index, limit, rrf_k, bm25_limit, vec_limit, json, collection, min_score, files, all, expand, embed_backend, embed_model, vector_backend } => hybrid_search(&index, &query, limit, rrf_k, bm25_limit, vec_limit, json, collection, min_score, files, all, expand, embed_backend, embed_model.as_deref(), vector_backend),
+            vector_encoding,
+            hnsw_m,
+            hnsw_ef_construction,
+            hnsw_ef,
+            jobs,
+            progress,
+        } => embed_index(&vault, &index, max_chars, overlap, incremental, collection, embed_backend, embed_model.as_deref(), embed_dims, vector_backend, vector_encoding, &HnswOptions { m: hnsw_m, ef_construction: hnsw_ef_construction, ef: hnsw_ef }, &ScanOptions::cli(jobs, progress)),
+        Commands::EmbedSearch { query, index, limit, json, collection, folder, min_score, files, all, sort, offset, explain, embed_backend, embed_model, vector_backend, hnsw_ef } => FolderScope::resolve(&index, &folder).and_then(|scope| {
+            let page = PageOptions { sort, offset };
+            embed_search(&index, &query, limit, json, collection, &scope, min_score, files, all, embed_backend, embed_model.as_deref(), vector_backend, hnsw_ef, &page, explain)
+        }),
+        Commands::Hybrid { query, index, limit, rrf_k, bm25_limit, vec_limit, json, collection, folder, min_score, files, all, expand, snippet_len, highlight_pre, highlight_post, boost, fuzzy, prefix, lenient, facets, sort, offset, explain, embed_backend, embed_model, vector_backend, hnsw_ef } => QueryOptions::with_boosts(boost.as_deref()).and_then(|o| o.with_matching(fuzzy, prefix)).and_then(|query_opts| {
+            let query_opts = QueryOptions { lenient, ..query_opts };
+            let facets = parse_facet_names(facets.as_deref())?;
+            let page = PageOptions { sort, offset };
+            let scope = FolderScope::resolve(&index, &folder)?;
+            hybrid_search(&index, &query, limit, rrf_k, bm25_limit, vec_limit, json, collection, &scope, min_score, files, all, expand, embed_backend, embed_model.as_deref(), vector_backend, hnsw_ef, &SnippetOptions { len: snippet_len, pre: highlight_pre, post: highlight_post }, &query_opts, &facets, &page, explain)
+        }),
         Commands::NoteCreate { vault, path, content, stdin, reindex, index, max_chars, overlap } => note_create(&vault, &path, content, stdin, reindex, &index, max_chars, overlap),
         Commands::NoteAppend { vault, path, content, stdin, reindex, index, max_chars, overlap } => note_append(&vault, &path, content, stdin, reindex, &index, max_chars, overlap),
-        Commands::MultiGet { paths, glob, index, json, collection } => multi_get(&index, paths, glob, json, collection),
+        Commands::MultiGet { paths, glob, index, json, collection, folder } => FolderScope::resolve(&index, &folder).and_then(|scope| multi_get(&index, paths, glob, json, collection, &scope)),
+        Commands::Related { path, index, limit, rrf_k, json, collection, folder, exclude_linked, vector_mode, files } => FolderScope::resolve(&index, &folder)
+            .and_then(|scope| related_notes(&index, &path, limit, rrf_k, json, collection, &scope, exclude_linked, vector_mode, files)),
+        Commands::Grep { pattern, index, fixed_strings, ignore_case, context, limit, json, collection, folder, files } => FolderScope::resolve(&index, &folder)
+            .and_then(|scope| grep_notes(&index, &pattern, fixed_strings, ignore_case, context, limit, json, collection, &scope, files)),
         Commands::CollectionAdd { name, path } => collection_add(&name, &path),
         Commands::CollectionList {} => collection_list(),
         Commands::CollectionRemove { name } => collection_remove(&name),
         Commands::Mcp {} => mcp_server(),
+        Commands::Verify { vault, index, collection, repair, json } => verify_index(&vault, &index, collection, repair, json),
+        Commands::Optimize { index, json } => optimize(&index, json),
+        Commands::Export { index, output } => export_index(&index, output.as_deref()),
+        Commands::Import { index, input, vault } => import_index(&index, input.as_deref(), vault.as_deref()),
         Commands::Stats { index, json } => stats(&index, json),
         Commands::Schema { pretty } => print_schema(pretty),
         Commands::ToolSpec { pretty } => print_tool_spec(pretty),
+    };
+
+    if let Err(e) = &result
+        && let Some(err) = e.downcast_ref::<ObsidxError>()
+    {
+        println!("{}", json_response(err.to_json()));
+        std::process::exit(1);
+    }
+    result
+}
+
+/// Errors with a stable `code` that callers (CLI `--json`, MCP) can match on.
+#[derive(Debug, thiserror::Error)]
+enum ObsidxError {
+    #[error("index is locked by another process ({path}); waited {waited_ms}ms")]
+    IndexLocked { path: String, waited_ms: u64 },
+    #[error("invalid query syntax: {message}")]
+    QuerySyntax { message: String, position: Option<usize>, query: String },
+    #[error("index schema is outdated (no `{field}` field); run `obsidx index` to rebuild")]
+    SchemaOutdated { field: String },
+}
+
+impl ObsidxError {
+    fn code(&self) -> &'static str {
+        match self {
+            ObsidxError::IndexLocked { .. } => "index_locked",
+            ObsidxError::QuerySyntax { .. } => "query_syntax",
+            ObsidxError::SchemaOutdated { .. } => "schema_outdated",
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut error = json!({"code": self.code(), "message": self.to_string()});
+        if let ObsidxError::QuerySyntax { position, query, .. } = self {
+            error["position"] = json!(position);
+            error["query"] = json!(query);
+        }
+        json!({ "error": error })
+    }
+}
+
+fn error_json(e: &anyhow::Error) -> serde_json::Value {
+    match e.downcast_ref::<ObsidxError>() {
+        Some(err) => err.to_json(),
+        None => json!({"error": {"code": "exception", "message": e.to_string()}}),
     }
 }
 
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 struct ObsidxConfig {
+    #[serde(default)]
     collections: std::collections::HashMap<String, String>,
+    /// Default per-field query boosts, e.g. `title = 2.0`.
+    #[serde(default)]
+    boosts: std::collections::HashMap<String, f32>,
+    /// Analyzer for newly created indexes (`default`, `english`, `cjk`, `auto`, ...).
+    #[serde(default)]
+    analyzer: Option<String>,
+    /// What `auto` uses for text too short to detect; defaults to `english`.
+    #[serde(default)]
+    auto_fallback: Option<String>,
+    /// Settings for the `command` and `http` embedding backends.
+    #[serde(default)]
+    embedding: EmbeddingConfig,
+    /// Paths of loadable SQLite extensions.
+    #[serde(default)]
+    extensions: ExtensionsConfig,
+}
+
+/// `[extensions]`: e.g. `sqlite_vec = "/opt/sqlite-vec/vec0.so"`. `OBSIDX_VEC0` overrides it.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct ExtensionsConfig {
+    sqlite_vec: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct EmbeddingConfig {
+    /// Vector size for new indexes: sets the hash backend's size and is checked
+    /// against what model-backed backends return. `--embed-dims` overrides it.
+    dims: Option<usize>,
+    command: Option<CommandEmbeddingConfig>,
+    http: Option<HttpEmbeddingConfig>,
+}
+
+/// `[embedding.command]`: runs `program args...` once per batch.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct CommandEmbeddingConfig {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Passed through as `model` in the request; `--embed-model` overrides it.
+    model: Option<String>,
+    #[serde(default = "default_embed_timeout_secs")]
+    timeout_secs: u64,
+    #[serde(default = "default_embed_retries")]
+    retries: u32,
+    #[serde(default = "default_embed_batch_size")]
+    batch_size: usize,
+}
+
+/// `[embedding.http]`: e.g. `http://127.0.0.1:11434/api/embed` (Ollama) or
+/// `http://127.0.0.1:8080/v1/embeddings` (llama.cpp, vLLM, LM Studio).
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct HttpEmbeddingConfig {
+    url: String,
+    model: Option<String>,
+    /// Environment variable holding a bearer token, for servers that want one.
+    api_key_env: Option<String>,
+    #[serde(default = "default_embed_timeout_secs")]
+    timeout_secs: u64,
+    #[serde(default = "default_embed_retries")]
+    retries: u32,
+    #[serde(default = "default_embed_batch_size")]
+    batch_size: usize,
+}
+
+fn default_embed_timeout_secs() -> u64 {
+    30
+}
+
+fn default_embed_retries() -> u32 {
+    2
+}
+
+fn default_embed_batch_size() -> usize {
+    32
 }
 
 fn config_path() -> PathBuf {
@@ -424,6 +1216,9 @@ fn save_config(cfg: &ObsidxConfig) -> Result<()> {
 }
 
 fn collection_add(name: &str, path: &str) -> Result<()> {
+    // Stored absolute so the collection resolves the same from any working directory.
+    let path = fs::canonicalize(path).with_context(|| format!("Collection path not found: {path}"))?;
+    let path = path.to_string_lossy();
     let mut cfg = load_config();
     cfg.collections.insert(name.to_string(), path.to_string());
     save_config(&cfg)?;
@@ -459,6 +1254,17 @@ fn resolve_collection_path(collection: &Option<String>) -> Result<Option<PathBuf
     Ok(None)
 }
 
+/// Directory to scan and the collection name it is indexed under: the named
+/// collection's configured path, else `vault` as the default collection. The root
+/// is canonicalized, so note paths and the recorded root don't depend on how the
+/// vault was spelled or where obsidx ran from.
+fn resolve_scan_root(vault: &str, collection: Option<String>) -> Result<(PathBuf, String)> {
+    let (root, name) = match resolve_collection_path(&collection)? {
+        Some(p) => (p, collection.unwrap()),
+        None => (PathBuf::from(vault), "default".to_string()),
+    };
+    Ok((fs::canonicalize(&root).unwrap_or(root), name))
+}
 
 struct DocLookup {
     is_doc_id: bool,
@@ -472,78 +1278,944 @@ fn resolve_doc_id(input: &str) -> DocLookup {
     DocLookup { is_doc_id: false, value: input.to_string() }
 }
 
-fn schema() -> Schema {
+fn schema(analyzer: Analyzer) -> Schema {
     let mut schema_builder = Schema::builder();
     schema_builder.add_text_field("path", STRING | STORED);
     schema_builder.add_text_field("collection", STRING | STORED);
     schema_builder.add_text_field("doc_id", STRING | STORED);
-    schema_builder.add_text_field("title", TEXT | STORED);
-    schema_builder.add_text_field("content", TEXT | STORED);
+    schema_builder.add_text_field("title", analyzed_text(analyzer, TEXT | STORED));
+    schema_builder.add_text_field("content", code_text(analyzer, TEXT | STORED));
     schema_builder.add_text_field("tags", TEXT | STORED);
     schema_builder.add_text_field("links", TEXT | STORED);
     schema_builder.add_text_field("links_term", TEXT);
     schema_builder.add_text_field("headings", TEXT | STORED);
     schema_builder.add_text_field("frontmatter", TEXT | STORED);
     schema_builder.add_i64_field("mtime", FAST | STORED);
+    // Query-only fields behind the friendly `heading:`, `path:`, `tag:` and `alias:` scopes.
+    schema_builder.add_text_field("heading", analyzed_text(analyzer, TEXT));
+    schema_builder.add_text_field("path_text", TEXT);
+    schema_builder.add_text_field(
+        "tag",
+        TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(TAG_TOKENIZER)
+                .set_index_option(IndexRecordOption::Basic),
+        ),
+    );
+    schema_builder.add_text_field("alias", analyzed_text(analyzer, TEXT));
+    // Fenced and inline code only, identifiers split but never stemmed.
+    schema_builder.add_text_field("code", code_text(Analyzer::Default, TEXT));
+    // `/tag/..`, `/folder/<absolute dir>`, `/collection/..` and `/year/..` for facet counts.
+    schema_builder.add_facet_field("facets", FacetOptions::default());
     schema_builder.build()
 }
 
-fn init_index(vault: &str, index_dir: &str) -> Result<()> {
-    let index_path = PathBuf::from(index_dir);
-    if !index_path.exists() {
-        fs::create_dir_all(&index_path)
-            .with_context(|| format!("Failed to create index dir: {index_dir}"))?;
-    }
-    let schema = schema();
-    let _index = Index::create_in_dir(&index_path, schema)
-        .with_context(|| "Failed to create Tantivy index")?;
-
-    let out = json_response(json!({
-        "message": "index initialized",
-        "vault": vault,
-        "index": index_dir
-    }));
-    println!("{out}");
-    Ok(())
+const TAG_TOKENIZER: &str = "obsidx_tag";
+
+/// How title, content, heading and alias text is tokenized. The choice is stored in
+/// the tantivy schema as the fields' tokenizer name, so it travels with the index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Analyzer {
+    /// tantivy's `default`: split on non-alphanumerics and lowercase.
+    Default,
+    /// Stopword removal and Snowball stemming for one language.
+    Stemmed(Language),
+    /// Words as usual plus overlapping bigrams over Chinese, Japanese and Korean runs.
+    Cjk,
+    /// Detects the language of each text and applies one of the above.
+    Auto,
 }
 
-fn build_index(vault: &str, index_dir: &str, incremental: bool, collection: Option<String>) -> Result<()> {
-    let index_path = PathBuf::from(index_dir);
-    if !index_path.exists() {
-        fs::create_dir_all(&index_path)
-            .with_context(|| format!("Failed to create index dir: {index_dir}"))?;
+const STEM_LANGUAGES: &[(&str, Language)] = &[
+    ("arabic", Language::Arabic),
+    ("danish", Language::Danish),
+    ("dutch", Language::Dutch),
+    ("english", Language::English),
+    ("finnish", Language::Finnish),
+    ("french", Language::French),
+    ("german", Language::German),
+    ("greek", Language::Greek),
+    ("hungarian", Language::Hungarian),
+    ("italian", Language::Italian),
+    ("norwegian", Language::Norwegian),
+    ("portuguese", Language::Portuguese),
+    ("romanian", Language::Romanian),
+    ("russian", Language::Russian),
+    ("spanish", Language::Spanish),
+    ("swedish", Language::Swedish),
+    ("tamil", Language::Tamil),
+    ("turkish", Language::Turkish),
+];
+
+impl Analyzer {
+    fn parse(name: &str) -> Result<Analyzer> {
+        let name = name.trim().to_lowercase();
+        match name.as_str() {
+            "default" => Ok(Analyzer::Default),
+            "cjk" => Ok(Analyzer::Cjk),
+            "auto" => Ok(Analyzer::Auto),
+            _ => STEM_LANGUAGES
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, lang)| Analyzer::Stemmed(*lang))
+                .ok_or_else(|| {
+                    let langs: Vec<&str> = STEM_LANGUAGES.iter().map(|(n, _)| *n).collect();
+                    anyhow::anyhow!("Unknown analyzer: {name} (expected default, cjk, auto or one of {})", langs.join(", "))
+                }),
+        }
     }
 
-    let schema = schema();
-    let index = if let Ok(idx) = Index::open_in_dir(&index_path) {
-        idx
-    } else {
-        Index::create_in_dir(&index_path, schema.clone())?
-    };
+    fn name(&self) -> &'static str {
+        match self {
+            Analyzer::Default => "default",
+            Analyzer::Cjk => "cjk",
+            Analyzer::Auto => "auto",
+            Analyzer::Stemmed(lang) => STEM_LANGUAGES.iter().find(|(_, l)| l == lang).map(|(n, _)| *n).unwrap_or("default"),
+        }
+    }
 
-    let mut writer = index.writer(50_000_000)?;
+    /// `default` keeps tantivy's built-in tokenizer so pre-analyzer indexes stay valid.
+    fn tokenizer_name(&self) -> String {
+        match self {
+            Analyzer::Default => "default".to_string(),
+            other => format!("obsidx_{}", other.name()),
+        }
+    }
 
-    if !incremental {
-        writer.delete_all_documents()?;
+    /// Content tokenizer: the same analysis with code identifiers kept whole and split.
+    fn code_tokenizer_name(&self) -> String {
+        format!("obsidx_code_{}", self.name())
     }
 
-    let collection_path = resolve_collection_path(&collection)?;
-    let (scan_root, collection_name) = if let Some(p) = collection_path { (p, collection.unwrap()) } else { (PathBuf::from(vault), "default".to_string()) };
-    let docs = scan_vault(&scan_root, &collection_name)?;
-    let total_docs = docs.len();
-    let fields = schema_fields(&index);
+    fn from_schema(schema: &Schema) -> Analyzer {
+        schema
+            .get_field("title")
+            .ok()
+            .and_then(|f| match schema.get_field_entry(f).field_type() {
+                tantivy::schema::FieldType::Str(opts) => opts.get_indexing_options().map(|i| i.tokenizer().to_string()),
+                _ => None,
+            })
+            .and_then(|name| {
+                let name = name.strip_prefix("obsidx_").unwrap_or(&name).to_string();
+                Analyzer::parse(&name).ok()
+            })
+            .unwrap_or(Analyzer::Default)
+    }
 
-    // Build a quick mtime map for incremental indexing
-    let mut existing_mtimes: HashMap<String, i64> = HashMap::new();
-    if incremental {
-        let reader = index.reader()?;
-        let searcher = reader.searcher();
+    fn text_analyzer(&self, fallback: Analyzer, identifiers: bool) -> TextAnalyzer {
+        let words = WordTokenizer { identifiers, cjk_bigrams: false };
+        match self {
+            Analyzer::Default if !identifiers => TextAnalyzer::builder(SimpleTokenizer::default())
+                .filter(RemoveLongFilter::limit(40))
+                .filter(LowerCaser)
+                .build(),
+            Analyzer::Default => TextAnalyzer::builder(words).filter(RemoveLongFilter::limit(40)).filter(LowerCaser).build(),
+            Analyzer::Stemmed(lang) => {
+                let builder = TextAnalyzer::builder(words)
+                    .filter(RemoveLongFilter::limit(40))
+                    .filter(LowerCaser);
+                match StopWordFilter::new(*lang) {
+                    Some(stop) => builder.filter(stop).filter(Stemmer::new(*lang)).build(),
+                    None => builder.filter(Stemmer::new(*lang)).build(),
+                }
+            }
+            Analyzer::Cjk => TextAnalyzer::builder(WordTokenizer { identifiers, cjk_bigrams: true })
+                .filter(RemoveLongFilter::limit(40))
+                .filter(LowerCaser)
+                .build(),
+            Analyzer::Auto => TextAnalyzer::from(AutoLanguageTokenizer::new(fallback, identifiers)),
+        }
+    }
+}
+
+fn analyzed_text(analyzer: Analyzer, base: TextOptions) -> TextOptions {
+    if analyzer == Analyzer::Default {
+        return base;
+    }
+    let indexing = base.get_indexing_options().cloned().unwrap_or_default().set_tokenizer(&analyzer.tokenizer_name());
+    base.set_indexing_options(indexing)
+}
+
+fn code_text(analyzer: Analyzer, base: TextOptions) -> TextOptions {
+    let indexing = base.get_indexing_options().cloned().unwrap_or_default().set_tokenizer(&analyzer.code_tokenizer_name());
+    base.set_indexing_options(indexing)
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x11FF | 0x3040..=0x30FF | 0x3130..=0x318F | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xF900..=0xFAFF | 0x20000..=0x2A6DF)
+}
+
+/// Splits on non-alphanumerics like tantivy's simple tokenizer, optionally
+/// emitting overlapping character bigrams for CJK runs (which have no spaces
+/// between words) and expanding code identifiers.
+///
+/// With `identifiers`, `parseConfigFile`, `max_retry_count`, `retry-count` and
+/// `k8s.io/api` are indexed whole and as their parts, the parts on consecutive
+/// positions so `"retry count"` also matches as a phrase.
+#[derive(Clone)]
+struct WordTokenizer {
+    identifiers: bool,
+    cjk_bigrams: bool,
+}
+
+fn is_identifier_joiner(c: char) -> bool {
+    matches!(c, '_' | '-' | '.' | '/')
+}
+
+/// Byte ranges of the parts of `ident`: split on joiners, then on camelCase
+/// (`parseConfig`) and acronym (`HTTPServer`) boundaries. Digits never split.
+fn identifier_parts(ident: &str) -> Vec<(usize, usize)> {
+    let mut parts = Vec::new();
+    let chars: Vec<(usize, char)> = ident.char_indices().collect();
+    let mut start: Option<usize> = None;
+    for (i, &(offset, c)) in chars.iter().enumerate() {
+        if is_identifier_joiner(c) {
+            if let Some(s) = start.take() {
+                parts.push((s, offset));
+            }
+            continue;
+        }
+        if let Some(s) = start
+            && i > 0
+        {
+            let prev = chars[i - 1].1;
+            let next_lower = chars.get(i + 1).is_some_and(|(_, n)| n.is_lowercase());
+            let boundary = (prev.is_lowercase() && c.is_uppercase()) || (prev.is_uppercase() && c.is_uppercase() && next_lower);
+            if boundary && s < offset {
+                parts.push((s, offset));
+                start = Some(offset);
+            }
+        }
+        start.get_or_insert(offset);
+    }
+    if let Some(s) = start {
+        parts.push((s, ident.len()));
+    }
+    parts
+}
+
+struct VecTokenStream {
+    tokens: Vec<Token>,
+    cursor: usize,
+}
+
+impl TokenStream for VecTokenStream {
+    fn advance(&mut self) -> bool {
+        self.cursor += 1;
+        self.cursor <= self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.cursor - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.cursor - 1]
+    }
+}
+
+impl Tokenizer for WordTokenizer {
+    type TokenStream<'a> = VecTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> VecTokenStream {
+        let mut tokens: Vec<Token> = Vec::new();
+        let mut position = 0;
+        let mut push = |position: usize, from: usize, to: usize, span: usize| {
+            tokens.push(Token { offset_from: from, offset_to: to, position, text: text[from..to].to_string(), position_length: span });
+        };
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let end_of = |i: usize| chars.get(i).map(|(o, _)| *o).unwrap_or(text.len());
+        let is_word = |c: char| c.is_alphanumeric() && !(self.cjk_bigrams && is_cjk(c));
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i].1;
+            if self.cjk_bigrams && is_cjk(c) {
+                let start = i;
+                while i < chars.len() && is_cjk(chars[i].1) {
+                    i += 1;
+                }
+                if i - start == 1 {
+                    push(position, chars[start].0, end_of(i), 1);
+                    position += 1;
+                }
+                for pair in chars[start..i].windows(2) {
+                    push(position, pair[0].0, pair[1].0 + pair[1].1.len_utf8(), 1);
+                    position += 1;
+                }
+            } else if is_word(c) {
+                let start = i;
+                // A joiner only continues an identifier when a word character follows it.
+                while i < chars.len()
+                    && (is_word(chars[i].1)
+                        || (self.identifiers && is_identifier_joiner(chars[i].1) && chars.get(i + 1).is_some_and(|(_, n)| is_word(*n))))
+                {
+                    i += 1;
+                }
+                let (from, to) = (chars[start].0, end_of(i));
+                let parts = if self.identifiers { identifier_parts(&text[from..to]) } else { vec![(0, to - from)] };
+                if parts.len() > 1 {
+                    push(position, from, to, parts.len());
+                }
+                for (a, b) in parts {
+                    push(position, from + a, from + b, 1);
+                    position += 1;
+                }
+            } else {
+                i += 1;
+            }
+        }
+        VecTokenStream { tokens, cursor: 0 }
+    }
+}
+
+/// Picks an analyzer per text from its detected language; text too short to
+/// classify (titles, most queries) uses the index's fallback analyzer.
+#[derive(Clone)]
+struct AutoLanguageTokenizer {
+    fallback: Analyzer,
+    analyzers: Vec<(Analyzer, TextAnalyzer)>,
+}
+
+impl AutoLanguageTokenizer {
+    fn new(fallback: Analyzer, identifiers: bool) -> AutoLanguageTokenizer {
+        let fallback = if fallback == Analyzer::Auto { Analyzer::Default } else { fallback };
+        let mut choices = vec![Analyzer::Default, Analyzer::Cjk];
+        choices.extend(STEM_LANGUAGES.iter().map(|(_, lang)| Analyzer::Stemmed(*lang)));
+        let analyzers = choices.into_iter().map(|a| (a, a.text_analyzer(fallback, identifiers))).collect();
+        AutoLanguageTokenizer { fallback, analyzers }
+    }
+}
+
+impl Tokenizer for AutoLanguageTokenizer {
+    type TokenStream<'a> = BoxTokenStream<'a>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> BoxTokenStream<'a> {
+        let choice = detect_language(text).map(|(_, analyzer)| analyzer).unwrap_or(self.fallback);
+        let slot = self.analyzers.iter().position(|(a, _)| *a == choice).unwrap_or(0);
+        self.analyzers[slot].1.token_stream(text)
+    }
+}
+
+/// ISO 639-3 code and analyzer for `text`, or None when detection is unreliable.
+/// CJK scripts are recognised from the script alone.
+fn detect_language(text: &str) -> Option<(&'static str, Analyzer)> {
+    let sample = match text.char_indices().nth(2000) {
+        Some((idx, _)) => &text[..idx],
+        None => text,
+    };
+    let info = whatlang::detect(sample)?;
+    let cjk = matches!(info.script(), whatlang::Script::Mandarin | whatlang::Script::Hiragana | whatlang::Script::Katakana | whatlang::Script::Hangul);
+    if cjk {
+        return Some((info.lang().code(), Analyzer::Cjk));
+    }
+    if !info.is_reliable() {
+        return None;
+    }
+    use whatlang::Lang;
+    let analyzer = match info.lang() {
+        Lang::Ara => Analyzer::Stemmed(Language::Arabic),
+        Lang::Dan => Analyzer::Stemmed(Language::Danish),
+        Lang::Nld => Analyzer::Stemmed(Language::Dutch),
+        Lang::Eng => Analyzer::Stemmed(Language::English),
+        Lang::Fin => Analyzer::Stemmed(Language::Finnish),
+        Lang::Fra => Analyzer::Stemmed(Language::French),
+        Lang::Deu => Analyzer::Stemmed(Language::German),
+        Lang::Ell => Analyzer::Stemmed(Language::Greek),
+        Lang::Hun => Analyzer::Stemmed(Language::Hungarian),
+        Lang::Ita => Analyzer::Stemmed(Language::Italian),
+        Lang::Nob => Analyzer::Stemmed(Language::Norwegian),
+        Lang::Por => Analyzer::Stemmed(Language::Portuguese),
+        Lang::Ron => Analyzer::Stemmed(Language::Romanian),
+        Lang::Rus => Analyzer::Stemmed(Language::Russian),
+        Lang::Spa => Analyzer::Stemmed(Language::Spanish),
+        Lang::Swe => Analyzer::Stemmed(Language::Swedish),
+        Lang::Tam => Analyzer::Stemmed(Language::Tamil),
+        Lang::Tur => Analyzer::Stemmed(Language::Turkish),
+        _ => Analyzer::Default,
+    };
+    Some((info.lang().code(), analyzer))
+}
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// `<index>/manifest.json`: the analyzer an index was built with and the
+/// languages detected across its notes.
+#[derive(Debug, Default, Serialize, serde::Deserialize)]
+struct IndexManifest {
+    analyzer: String,
+    /// Analyzer `auto` uses for text too short to classify.
+    fallback_analyzer: String,
+    languages: std::collections::BTreeMap<String, usize>,
+    updated_at: String,
+}
+
+fn read_manifest(index_dir: &Path) -> Option<IndexManifest> {
+    let raw = fs::read_to_string(index_dir.join(MANIFEST_FILE)).ok()?;
+    serde_json::from_str(&raw).ok()
+}
+
+fn write_manifest(index_dir: &str, analyzer: Analyzer, fallback: Analyzer, catalog: &Connection) -> Result<()> {
+    let mut stmt = catalog.prepare("SELECT COALESCE(language, 'und'), COUNT(*) FROM notes GROUP BY 1")?;
+    let languages = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let manifest = IndexManifest {
+        analyzer: analyzer.name().to_string(),
+        fallback_analyzer: fallback.name().to_string(),
+        languages,
+        updated_at: Utc::now().to_rfc3339(),
+    };
+    fs::write(Path::new(index_dir).join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest)?)?;
+    Ok(())
+}
+
+/// The analyzer `auto` falls back to: fixed in the manifest once an index exists,
+/// otherwise `auto_fallback` from config.toml, otherwise English.
+fn auto_fallback(index_dir: &Path) -> Analyzer {
+    read_manifest(index_dir)
+        .and_then(|m| Analyzer::parse(&m.fallback_analyzer).ok())
+        .or_else(|| load_config().auto_fallback.and_then(|name| Analyzer::parse(&name).ok()))
+        .filter(|a| *a != Analyzer::Auto)
+        .unwrap_or(Analyzer::Stemmed(Language::English))
+}
+
+fn register_tokenizers(index: &Index, index_dir: &Path) {
+    let tag = TextAnalyzer::builder(RawTokenizer::default()).filter(LowerCaser).build();
+    index.tokenizers().register(TAG_TOKENIZER, tag);
+    let fallback = auto_fallback(index_dir);
+    let mut analyzers = vec![Analyzer::Default, Analyzer::Cjk, Analyzer::Auto];
+    analyzers.extend(STEM_LANGUAGES.iter().map(|(_, lang)| Analyzer::Stemmed(*lang)));
+    for analyzer in analyzers {
+        if analyzer != Analyzer::Default {
+            index.tokenizers().register(&analyzer.tokenizer_name(), analyzer.text_analyzer(fallback, false));
+        }
+        index.tokenizers().register(&analyzer.code_tokenizer_name(), analyzer.text_analyzer(fallback, true));
+    }
+}
+
+/// Opens an existing index with obsidx's custom tokenizers registered.
+fn open_index<P: AsRef<Path>>(index_dir: P) -> tantivy::Result<Index> {
+    let index = Index::open_in_dir(index_dir.as_ref())?;
+    register_tokenizers(&index, index_dir.as_ref());
+    Ok(index)
+}
+
+/// Opens an existing index for reading, failing with `schema_outdated` rather than
+/// panicking later if it was built before fields this version queries.
+fn open_existing_index(index_dir: &str) -> Result<Index> {
+    let index = open_index(index_dir).with_context(|| format!("Index not found: {index_dir}"))?;
+    schema_fields(&index)?;
+    Ok(index)
+}
+
+/// Opens the index at `index_path`, creating it if missing. The analyzer is
+/// `requested`, else the existing index's, else config.toml's, else `default`.
+/// An index whose schema doesn't match is wiped and recreated; the returned flag
+/// is true in that case (and for a fresh index) so callers can do a full rebuild.
+fn open_or_create_index(index_path: &Path, requested: Option<Analyzer>) -> Result<(Index, bool)> {
+    let configured = || load_config().analyzer.and_then(|name| Analyzer::parse(&name).ok());
+    let mut analyzer = requested.or_else(configured).unwrap_or(Analyzer::Default);
+    if let Ok(index) = open_index(index_path) {
+        analyzer = requested.unwrap_or_else(|| Analyzer::from_schema(&index.schema()));
+        if index.schema() == schema(analyzer) {
+            return Ok((index, false));
+        }
+        drop(index);
+        reset_tantivy_dir(index_path)?;
+    }
+    let index = Index::create_in_dir(index_path, schema(analyzer))?;
+    register_tokenizers(&index, index_path);
+    Ok((index, true))
+}
+
+/// Removes tantivy's files from an index directory, leaving obsidx's own stores.
+fn reset_tantivy_dir(index_path: &Path) -> Result<()> {
+    for entry in fs::read_dir(index_path)?.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let ours = name.contains(".db") || name == MANIFEST_FILE || name == WRITE_LOCK_FILE || name == WATCH_LOCK_FILE || name.starts_with(REINDEX_QUEUE_FILE);
+        if !ours && entry.path().is_file() {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Friendly query-scope names and the schema fields they search.
+const FIELD_ALIASES: &[(&str, &str)] = &[
+    ("title", "title"),
+    ("content", "content"),
+    ("heading", "heading"),
+    ("headings", "heading"),
+    ("path", "path_text"),
+    ("tag", "tag"),
+    ("tags", "tag"),
+    ("link", "links_term"),
+    ("links", "links_term"),
+    ("alias", "alias"),
+    ("aliases", "alias"),
+    ("code", "code"),
+];
+
+fn resolve_field_alias(name: &str) -> Option<&'static str> {
+    FIELD_ALIASES.iter().find(|(alias, _)| *alias == name).map(|(_, field)| *field)
+}
+
+/// Rewrites `scope:` prefixes outside quoted phrases to their schema field names.
+fn rewrite_field_aliases(query: &str) -> String {
+    let mut out = String::with_capacity(query.len());
+    let mut in_quotes = false;
+    let mut word = String::new();
+    for ch in query.chars() {
+        if ch == '"' {
+            in_quotes = !in_quotes;
+        }
+        if !in_quotes && ch == ':' && !word.is_empty() {
+            let name = word.trim_start_matches(['+', '-', '(']);
+            if let Some(field) = resolve_field_alias(name) {
+                let prefix_len = word.len() - name.len();
+                word.truncate(prefix_len);
+                word.push_str(field);
+            }
+        }
+        if ch.is_whitespace() || in_quotes || ch == '"' || ch == ':' {
+            out.push_str(&word);
+            word.clear();
+            out.push(ch);
+        } else {
+            word.push(ch);
+        }
+    }
+    out.push_str(&word);
+    out
+}
+
+#[derive(Debug, Clone, Default)]
+struct QueryOptions {
+    /// Per-field boosts keyed by friendly scope name (`title`, `heading`, ...).
+    boosts: HashMap<String, f32>,
+    /// Levenshtein distance allowed for each term (0 = exact).
+    fuzzy: u8,
+    /// Match terms as word prefixes.
+    prefix: bool,
+    /// Recover from syntax errors with `parse_query_lenient` instead of failing.
+    lenient: bool,
+}
+
+/// Text fields that `--fuzzy` and `--prefix` apply to.
+const FUZZY_FIELDS: &[&str] = &["title", "content", "heading", "alias", "path_text", "code"];
+const MAX_FUZZY_DISTANCE: u8 = 2;
+
+impl QueryOptions {
+    /// Boosts from `~/.obsidx/config.toml`, overridden by a `title=2,heading=1.5` list.
+    fn with_boosts(cli: Option<&str>) -> Result<QueryOptions> {
+        let mut boosts = load_config().boosts;
+        if let Some(list) = cli {
+            for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let (name, value) = part
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid boost '{part}', expected field=weight"))?;
+                let weight: f32 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid boost weight in '{part}'"))?;
+                boosts.insert(name.trim().to_string(), weight);
+            }
+        }
+        for name in boosts.keys() {
+            if resolve_field_alias(name).is_none() {
+                anyhow::bail!("Unknown boost field: {name}");
+            }
+        }
+        Ok(QueryOptions { boosts, ..QueryOptions::default() })
+    }
+
+    fn with_matching(self, fuzzy: u8, prefix: bool) -> Result<QueryOptions> {
+        if fuzzy > MAX_FUZZY_DISTANCE {
+            anyhow::bail!("--fuzzy supports distances up to {MAX_FUZZY_DISTANCE}");
+        }
+        Ok(QueryOptions { fuzzy, prefix, ..self })
+    }
+}
+
+/// Parses a user query over title, content and tags, with friendly field scopes
+/// and configured boosts applied.
+fn parse_user_query(index: &Index, query: &str, opts: &QueryOptions) -> Result<Box<dyn tantivy::query::Query>> {
+    Ok(parse_user_query_reporting(index, query, opts)?.0)
+}
+
+/// A syntax problem that lenient parsing recovered from.
+#[derive(Debug, Serialize)]
+struct QueryIssue {
+    message: String,
+    /// Character offset into the query, when it can be pinned down.
+    position: Option<usize>,
+}
+
+/// Like `parse_user_query`, but also returns the issues lenient mode skipped over.
+/// Strict mode fails with `ObsidxError::QuerySyntax` instead.
+fn parse_user_query_reporting(index: &Index, query: &str, opts: &QueryOptions) -> Result<(Box<dyn tantivy::query::Query>, Vec<QueryIssue>)> {
+    let schema = index.schema();
+    let default_fields = ["title", "content", "tags"]
+        .iter()
+        .filter_map(|name| schema.get_field(name).ok())
+        .collect();
+    let mut parser = QueryParser::for_index(index, default_fields);
+    for (name, weight) in &opts.boosts {
+        if let Some(field) = resolve_field_alias(name).and_then(|f| schema.get_field(f).ok()) {
+            parser.set_field_boost(field, *weight);
+        }
+    }
+    if opts.fuzzy > 0 || opts.prefix {
+        for field in FUZZY_FIELDS.iter().filter_map(|name| schema.get_field(name).ok()) {
+            parser.set_field_fuzzy(field, opts.prefix, opts.fuzzy, true);
+        }
+    }
+    if opts.boosts.contains_key("tag") || opts.boosts.contains_key("tags") {
+        // Unscoped terms still hit the legacy JSON `tags` field.
+        if let (Ok(tags), Some(weight)) = (schema.get_field("tags"), opts.boosts.get("tag").or(opts.boosts.get("tags"))) {
+            parser.set_field_boost(tags, *weight);
+        }
+    }
+    let err = match parser.parse_query(&rewrite_field_aliases(query)) {
+        Ok(q) => return Ok((q, Vec::new())),
+        Err(err) => err,
+    };
+    if !opts.lenient {
+        return Err(ObsidxError::QuerySyntax {
+            message: err.to_string(),
+            position: query_error_offset(query, &err).map(|i| query[..i].chars().count()),
+            query: query.to_string(),
+        }
+        .into());
+    }
+
+    // Blank out each offending (ASCII) character in turn so the rest of the query keeps
+    // its meaning: `"foo bar` becomes the terms `foo bar`, `nofield:x` the terms `nofield x`.
+    let mut text = query.to_string();
+    let mut issues = Vec::new();
+    let mut err = err;
+    for _ in 0..MAX_QUERY_REPAIRS {
+        let Some(offset) = query_error_offset(&text, &err) else {
+            break;
+        };
+        issues.push(QueryIssue { message: err.to_string(), position: Some(query[..offset].chars().count()) });
+        text.replace_range(offset..offset + 1, " ");
+        match parser.parse_query(&rewrite_field_aliases(&text)) {
+            Ok(q) => return Ok((q, issues)),
+            Err(e) => err = e,
+        }
+    }
+
+    // Nothing left to pin down: keep whatever parts the lenient grammar accepts.
+    let (q, errors) = parser.parse_query_lenient(&rewrite_field_aliases(&text));
+    let errors = if errors.is_empty() { vec![err] } else { errors };
+    issues.extend(errors.iter().map(|e| QueryIssue { message: e.to_string(), position: None }));
+    Ok((q, issues))
+}
+
+const MAX_QUERY_REPAIRS: usize = 16;
+
+/// Best-effort byte offset of the character behind a parse error; tantivy's errors
+/// carry no usable offsets. For unknown fields this is the `:` after the name.
+fn query_error_offset(query: &str, err: &tantivy::query::QueryParserError) -> Option<usize> {
+    use tantivy::query::QueryParserError as E;
+    match err {
+        E::FieldDoesNotExist(field) | E::FieldNotIndexed(field) | E::FieldDoesNotHavePositionsIndexed(field) => {
+            let scoped = format!("{field}:");
+            query
+                .match_indices(&scoped)
+                .map(|(i, _)| i)
+                .find(|&i| query[..i].chars().last().is_none_or(|c| c.is_whitespace() || matches!(c, '(' | '+' | '-')))
+                .map(|i| i + field.len())
+        }
+        _ => syntax_error_offset(query),
+    }
+}
+
+/// Byte offset of the first construct the query grammar cannot close: an unbalanced
+/// quote or parenthesis, a field with no value, a second `:` in one term, or a
+/// dangling `+`/`-` operator.
+fn syntax_error_offset(query: &str) -> Option<usize> {
+    let mut open_quote = None;
+    let mut parens = Vec::new();
+    let mut scoped = false;
+    let mut chars = query.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if open_quote.is_some() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => open_quote = None,
+                _ => {}
+            }
+            continue;
+        }
+        let next = chars.peek().map(|&(_, n)| n);
+        match c {
+            '"' => open_quote = Some(i),
+            '(' => parens.push(i),
+            ')' if parens.pop().is_none() => return Some(i),
+            ':' if scoped || next.is_none_or(|n| n.is_whitespace() || n == ')') => return Some(i),
+            ':' => scoped = true,
+            '+' | '-' if next.is_none_or(char::is_whitespace) && query[..i].chars().last().is_none_or(char::is_whitespace) => return Some(i),
+            c if c.is_whitespace() => scoped = false,
+            _ => {}
+        }
+    }
+    open_quote.or_else(|| parens.first().copied())
+}
+
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 30_000;
+const LOCK_POLL: Duration = Duration::from_millis(100);
+const WRITE_LOCK_FILE: &str = "write.lock";
+const WATCH_LOCK_FILE: &str = "watch.lock";
+const REINDEX_QUEUE_FILE: &str = "reindex.queue";
+const REINDEX_QUEUE_LOCK_FILE: &str = "reindex.queue.lock";
+const WATCH_QUEUE_POLL: Duration = Duration::from_secs(1);
+
+fn lock_timeout() -> Duration {
+    let ms = env::var("OBSIDX_LOCK_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT_MS);
+    Duration::from_millis(ms)
+}
+
+/// Exclusive advisory lock on an index directory; released on drop.
+struct IndexLock {
+    _file: fs::File,
+}
+
+fn open_lock_file(index_dir: &str, name: &str) -> Result<fs::File> {
+    fs::create_dir_all(index_dir)
+        .with_context(|| format!("Failed to create index dir: {index_dir}"))?;
+    let path = Path::new(index_dir).join(name);
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open lock file: {}", path.display()))
+}
+
+/// Takes the index write lock, waiting up to `OBSIDX_LOCK_TIMEOUT_MS` for other
+/// writers (`index`, `embed-index`, `watch`, `note-* --reindex`) to finish.
+fn lock_index(index_dir: &str) -> Result<IndexLock> {
+    let file = open_lock_file(index_dir, WRITE_LOCK_FILE)?;
+    let timeout = lock_timeout();
+    let start = std::time::Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(IndexLock { _file: file }),
+            Err(fs::TryLockError::WouldBlock) if start.elapsed() < timeout => std::thread::sleep(LOCK_POLL),
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(ObsidxError::IndexLocked {
+                    path: Path::new(index_dir).join(WRITE_LOCK_FILE).display().to_string(),
+                    waited_ms: start.elapsed().as_millis() as u64,
+                }
+                .into());
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Opens the tantivy writer, reporting a held tantivy lock as `index_locked`.
+fn index_writer(index: &Index, index_dir: &str) -> Result<tantivy::IndexWriter> {
+    match index.writer(50_000_000) {
+        Ok(w) => Ok(w),
+        Err(tantivy::TantivyError::LockFailure(_, _)) => Err(ObsidxError::IndexLocked {
+            path: index_dir.to_string(),
+            waited_ms: 0,
+        }
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Opens a SQLite store in WAL mode so readers never block the single writer.
+fn open_sqlite(path: &Path) -> Result<Connection> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    conn.busy_timeout(lock_timeout())?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    Ok(conn)
+}
+
+/// Held by a running `watch` for its whole lifetime.
+struct WatchRegistration {
+    _file: fs::File,
+}
+
+fn register_watcher(index_dir: &str) -> Result<WatchRegistration> {
+    let file = open_lock_file(index_dir, WATCH_LOCK_FILE)?;
+    match file.try_lock() {
+        Ok(()) => Ok(WatchRegistration { _file: file }),
+        Err(fs::TryLockError::WouldBlock) => anyhow::bail!("Another watch is already running for {index_dir}"),
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn watcher_running(index_dir: &str) -> bool {
+    let path = Path::new(index_dir).join(WATCH_LOCK_FILE);
+    let Ok(file) = fs::OpenOptions::new().write(true).open(path) else {
+        return false;
+    };
+    matches!(file.try_lock_shared(), Err(fs::TryLockError::WouldBlock))
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct ReindexRequest {
+    path: String,
+    /// Vault the note was written to; the root of the default collection.
+    vault: String,
+    /// Named collection whose recorded root holds `path`; None for the default one.
+    collection: Option<String>,
+    max_chars: usize,
+    overlap: usize,
+}
+
+/// Held while appending to or taking the reindex queue. A writer checks
+/// `watcher_running` and enqueues under it, and the watcher drops its
+/// registration under it, so a request is never queued for a watcher that has
+/// already drained the queue for the last time.
+fn lock_reindex_queue(index_dir: &str) -> Result<fs::File> {
+    let file = open_lock_file(index_dir, REINDEX_QUEUE_LOCK_FILE)?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Hands a reindex request to the running `watch` process. The caller holds
+/// `lock_reindex_queue`.
+fn enqueue_reindex(index_dir: &str, req: &ReindexRequest) -> Result<()> {
+    let queue = Path::new(index_dir).join(REINDEX_QUEUE_FILE);
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(queue)?;
+    writeln!(file, "{}", serde_json::to_string(req)?)?;
+    Ok(())
+}
+
+/// Atomically takes every queued reindex request. The caller holds
+/// `lock_reindex_queue`.
+fn take_reindex_queue(index_dir: &str) -> Vec<ReindexRequest> {
+    let queue = Path::new(index_dir).join(REINDEX_QUEUE_FILE);
+    let taken = Path::new(index_dir).join(format!("{REINDEX_QUEUE_FILE}.taken"));
+    if fs::rename(&queue, &taken).is_err() {
+        return vec![];
+    }
+    let reqs = fs::read_to_string(&taken)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect();
+    fs::remove_file(&taken).ok();
+    reqs
+}
+
+/// Reindexes after a note write, or queues it for a running `watch`.
+/// Returns true when the reindex was handed off.
+fn reindex_after_write(vault: &str, index_dir: &str, full_path: &Path, max_chars: Option<usize>, overlap: Option<usize>) -> Result<bool> {
+    let (recorded_max_chars, recorded_overlap) = recorded_chunking(index_dir);
+    let req = ReindexRequest {
+        path: full_path.to_string_lossy().to_string(),
+        vault: vault.to_string(),
+        collection: note_collection(index_dir, full_path),
+        max_chars: max_chars.unwrap_or(recorded_max_chars),
+        overlap: overlap.unwrap_or(recorded_overlap),
+    };
+    {
+        let _queue = lock_reindex_queue(index_dir)?;
+        if watcher_running(index_dir) {
+            enqueue_reindex(index_dir, &req)?;
+            return Ok(true);
+        }
+    }
+    reindex_incremental(index_dir, &req)?;
+    Ok(false)
+}
+
+/// Named collection whose root, as recorded in the catalog, contains `full_path`.
+/// The innermost root wins when collections nest; None means the default collection.
+fn note_collection(index_dir: &str, full_path: &Path) -> Option<String> {
+    if !catalog_path(index_dir).exists() {
+        return None;
+    }
+    let conn = open_catalog(index_dir).ok()?;
+    let mut stmt = conn.prepare("SELECT name, root FROM collections WHERE name != 'default'").ok()?;
+    let roots: Vec<(String, String)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).ok()?.filter_map(|r| r.ok()).collect();
+    let canonical = |p: &Path| fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf());
+    let path = canonical(full_path);
+    roots
+        .into_iter()
+        .map(|(name, root)| (name, canonical(Path::new(&root))))
+        .filter(|(_, root)| path.starts_with(root))
+        .max_by_key(|(_, root)| root.components().count())
+        .map(|(name, _)| name)
+}
+
+/// Incremental index and embed of the collection `req` belongs to, chunked as it asks.
+fn reindex_incremental(index_dir: &str, req: &ReindexRequest) -> Result<()> {
+    build_index(&req.vault, index_dir, true, req.collection.clone(), None, &ScanOptions::default())?;
+    let (embed_backend, embed_model) = recorded_embedding(index_dir)?;
+    let vector_backend = recorded_vector_backend(index_dir).unwrap_or(VectorBackend::Bruteforce);
+    embed_index(&req.vault, index_dir, req.max_chars, req.overlap, true, req.collection.clone(), embed_backend, embed_model.as_deref(), None, vector_backend, None, &HnswOptions::default(), &ScanOptions::default())
+}
+
+fn init_index(vault: &str, index_dir: &str, analyzer: Option<Analyzer>) -> Result<()> {
+    let index_path = PathBuf::from(index_dir);
+    // Taking the lock creates the directory, so concurrent first runs can't both
+    // create the tantivy index.
+    let _lock = lock_index(index_dir)?;
+    let (index, _) = open_or_create_index(&index_path, analyzer).with_context(|| "Failed to create Tantivy index")?;
+    let analyzer = Analyzer::from_schema(&index.schema());
+    write_manifest(index_dir, analyzer, auto_fallback(&index_path), &open_catalog(index_dir)?)?;
+
+    let out = json_response(json!({
+        "message": "index initialized",
+        "vault": vault,
+        "index": index_dir,
+        "analyzer": analyzer.name()
+    }));
+    println!("{out}");
+    Ok(())
+}
+
+fn build_index(vault: &str, index_dir: &str, incremental: bool, collection: Option<String>, analyzer: Option<Analyzer>, scan_opts: &ScanOptions) -> Result<()> {
+    let index_path = PathBuf::from(index_dir);
+    // Taking the lock creates the directory, so concurrent first runs can't both
+    // create the tantivy index.
+    let _lock = lock_index(index_dir)?;
+    let (index, fresh) = open_or_create_index(&index_path, analyzer)?;
+    // A new or schema-migrated index has nothing to be incremental against.
+    let incremental = incremental && !fresh;
+    let mut writer = index_writer(&index, index_dir)?;
+    let mut catalog = open_catalog(index_dir)?;
+    let tx = catalog.transaction()?;
+
+    if !incremental {
+        writer.delete_all_documents()?;
+        catalog_clear(&tx)?;
+    }
+
+    let (scan_root, collection_name) = resolve_scan_root(vault, collection)?;
+    let fields = schema_fields(&index)?;
+
+    // Build a quick mtime map for incremental indexing
+    let mut existing_mtimes: HashMap<String, i64> = HashMap::new();
+    // Notes of this collection already indexed; whatever the scan doesn't see was deleted.
+    let mut indexed_paths: std::collections::HashSet<String> = std::collections::HashSet::new();
+    if incremental {
+        let reader = index.reader()?;
+        let searcher = reader.searcher();
         let schema = index.schema();
         let path_field = schema.get_field("path").unwrap();
         let mtime_field = schema.get_field("mtime").unwrap();
+        let collection_field = schema.get_field("collection").unwrap();
         for segment_reader in searcher.segment_readers() {
             let store_reader = segment_reader.get_store_reader(0)?;
-            for doc_id in 0..segment_reader.max_doc() {
+            for doc_id in segment_reader.doc_ids_alive() {
                 let doc: TantivyDocument = store_reader.get(doc_id)?;
                 let path = doc
                     .get_first(path_field)
@@ -555,78 +2227,395 @@ fn build_index(vault: &str, index_dir: &str, incremental: bool, collection: Opti
                     .and_then(|v| v.as_i64())
                     .unwrap_or(0);
                 if !path.is_empty() {
+                    if doc.get_first(collection_field).and_then(|v| v.as_str()) == Some(collection_name.as_str()) {
+                        indexed_paths.insert(path.clone());
+                    }
                     existing_mtimes.insert(path, mtime);
                 }
             }
         }
+        let mut stmt = tx.prepare("SELECT path FROM notes WHERE collection = ?1")?;
+        let catalog_paths = stmt.query_map(params![collection_name], |row| row.get::<_, String>(0))?;
+        indexed_paths.extend(catalog_paths.filter_map(|r| r.ok()));
     }
 
-    for doc in docs {
+    let total_docs = scan_vault(&scan_root, &collection_name, scan_opts, |doc| {
         if incremental {
-            if let Some(old) = existing_mtimes.get(&doc.path) {
-                if *old >= doc.mtime {
-                    continue;
-                }
+            indexed_paths.remove(&doc.path);
+            if let Some(old) = existing_mtimes.get(&doc.path)
+                && *old >= doc.mtime
+            {
+                return Ok(());
             }
             let term = Term::from_field_text(fields.path, &doc.path);
             writer.delete_term(term);
         }
 
-        let mut tdoc = doc! {
-            fields.path => doc.path,
-            fields.collection => doc.collection,
-            fields.doc_id => doc.doc_id,
-            fields.title => doc.title,
-            fields.content => doc.content,
-            fields.tags => serde_json::to_string(&doc.tags).unwrap_or_else(|_| "[]".to_string()),
-            fields.links => serde_json::to_string(&doc.links).unwrap_or_else(|_| "[]".to_string()),
-            fields.headings => serde_json::to_string(&doc.headings).unwrap_or_else(|_| "[]".to_string()),
-            fields.frontmatter => doc.frontmatter_json,
-            fields.mtime => doc.mtime,
-        };
-        for link in &doc.links {
-            tdoc.add_text(fields.links_term, link);
-        }
-        writer.add_document(tdoc)?;
+        catalog_upsert(&tx, &doc)?;
+        writer.add_document(tantivy_doc(&fields, doc))?;
+        Ok(())
+    })?;
+
+    // Left over after an incremental scan: notes removed from the vault.
+    for path in &indexed_paths {
+        writer.delete_term(Term::from_field_text(fields.path, path));
+        catalog_delete(&tx, path)?;
     }
 
     writer.commit()?;
+    tx.execute(
+        "INSERT INTO collections (name, root, indexed_at) VALUES (?1, ?2, ?3)\
+         ON CONFLICT(name) DO UPDATE SET root=excluded.root, indexed_at=excluded.indexed_at",
+        params![collection_name, scan_root.to_string_lossy(), Utc::now().timestamp()],
+    )?;
+    tx.commit()?;
+    let analyzer = Analyzer::from_schema(&index.schema());
+    write_manifest(index_dir, analyzer, auto_fallback(&index_path), &catalog)?;
 
     let out = json_response(json!({
         "message": "index built",
         "vault": vault,
         "index": index_dir,
-        "documents": total_docs
+        "documents": total_docs,
+        "deleted": indexed_paths.len(),
+        "analyzer": analyzer.name()
     }));
     println!("{out}");
     Ok(())
 }
 
-fn search_index(index_dir: &str, query: &str, limit: usize, json_out: bool, collection: Option<String>, min_score: f32, files: bool, all: bool) -> Result<()> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-
-    let schema = index.schema();
-    let path_field = schema.get_field("path").unwrap();
-    let title_field = schema.get_field("title").unwrap();
-    let content_field = schema.get_field("content").unwrap();
-    let docid_field = schema.get_field("doc_id").unwrap();
-    let tags_field = schema.get_field("tags").unwrap();
-    let collection_field = schema.get_field("collection").unwrap();
-
-    let query_parser = QueryParser::for_index(&index, vec![title_field, content_field, tags_field]);
-    let q = query_parser.parse_query(query)?;
-    let limit = if all { 10_000 } else { limit };
-    let top_docs = if let Some(name) = collection {
-        let term = Term::from_field_text(collection_field, &name);
-        let filter = tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
-        let combined = tantivy::query::BooleanQuery::intersection(vec![Box::new(q), Box::new(filter)]);
-        searcher.search(&combined, &TopDocs::with_limit(limit))?
-    } else {
-        searcher.search(&q, &TopDocs::with_limit(limit))?
+fn tantivy_doc(fields: &SchemaFields, doc: NoteDoc) -> TantivyDocument {
+    let path_text = doc.path.clone();
+    let aliases = frontmatter_aliases(&doc.frontmatter_json);
+    let code = code_spans(&doc.content);
+    let facets = note_facets(&doc);
+    let mut tdoc = doc! {
+        fields.path => doc.path,
+        fields.collection => doc.collection,
+        fields.doc_id => doc.doc_id,
+        fields.title => doc.title,
+        fields.content => doc.content,
+        fields.tags => serde_json::to_string(&doc.tags).unwrap_or_else(|_| "[]".to_string()),
+        fields.links => serde_json::to_string(&doc.links).unwrap_or_else(|_| "[]".to_string()),
+        fields.headings => serde_json::to_string(&doc.headings).unwrap_or_else(|_| "[]".to_string()),
+        fields.frontmatter => doc.frontmatter_json,
+        fields.mtime => doc.mtime,
     };
+    for link in &doc.links {
+        tdoc.add_text(fields.links_term, link);
+    }
+    for tag in &doc.tags {
+        tdoc.add_text(fields.tag, tag);
+    }
+    for heading in &doc.headings {
+        tdoc.add_text(fields.heading, heading);
+    }
+    tdoc.add_text(fields.path_text, path_text);
+    for alias in aliases {
+        tdoc.add_text(fields.alias, alias);
+    }
+    for span in code {
+        tdoc.add_text(fields.code, span);
+    }
+    for facet in facets {
+        tdoc.add_facet(fields.facets, facet);
+    }
+    tdoc
+}
+
+fn note_facets(doc: &NoteDoc) -> Vec<Facet> {
+    let mut facets: Vec<Facet> = doc
+        .tags
+        .iter()
+        .map(|tag| Facet::from_path(std::iter::once("tag").chain(tag.split('/').filter(|p| !p.is_empty()))))
+        .collect();
+    facets.push(Facet::from_path(["collection", doc.collection.as_str()]));
+    if let Some(dir) = Path::new(&doc.path).parent() {
+        facets.push(Facet::from_path(std::iter::once("folder".to_string()).chain(path_components(dir))));
+    }
+    if let Some(ts) = chrono::DateTime::from_timestamp(doc.mtime, 0) {
+        facets.push(Facet::from_path(["year".to_string(), ts.format("%Y").to_string()]));
+    }
+    facets
+}
+
+fn path_components(path: &Path) -> impl Iterator<Item = String> + '_ {
+    path.components().filter_map(|c| match c {
+        std::path::Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+        _ => None,
+    })
+}
+
+const FACET_NAMES: &[&str] = &["tags", "folder", "collection", "year"];
+
+/// Parses `--facets tags,folder,collection,year`.
+fn parse_facet_names(list: Option<&str>) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for name in list.unwrap_or("").split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let name = if name == "tag" { "tags" } else { name };
+        if !FACET_NAMES.contains(&name) {
+            anyhow::bail!("Unknown facet: {name} (expected one of {})", FACET_NAMES.join(", "));
+        }
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Facet counts over every note matching `query`, not just the returned page.
+/// Folders are the top-level directories under each collection root.
+fn facet_counts(index_dir: &str, query: &str, collection: Option<&str>, scope: &FolderScope, query_opts: &QueryOptions, names: &[String]) -> Result<Option<serde_json::Value>> {
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let fields = schema_fields(&index)?;
+    let mut q = parse_user_query(&index, query, query_opts)?;
+    if let Some(name) = collection {
+        let term = Term::from_field_text(fields.collection, name);
+        let filter = tantivy::query::TermQuery::new(term, IndexRecordOption::Basic);
+        q = Box::new(tantivy::query::BooleanQuery::intersection(vec![q, Box::new(filter)]));
+    }
+    let q = scope.restrict(q, fields.facets);
+
+    let mut out = serde_json::Map::new();
+    let prefix_of = |name: &str| match name {
+        "tags" => "/tag",
+        "collection" => "/collection",
+        _ => "/year",
+    };
+    let mut collector = tantivy::collector::FacetCollector::for_field("facets");
+    for name in names.iter().filter(|n| *n != "folder") {
+        collector.add_facet(prefix_of(name));
+    }
+    let counts = searcher.search(&q, &collector)?;
+    for name in names.iter().filter(|n| *n != "folder") {
+        let prefix = prefix_of(name);
+        let values = counts.get(prefix).map(|(facet, count)| FacetValue { value: facet_label(facet, prefix), count }).collect();
+        out.insert(name.clone(), serde_json::to_value(sorted_facet_values(values))?);
+    }
+
+    if names.iter().any(|n| n == "folder") {
+        let mut folders: HashMap<String, u64> = HashMap::new();
+        for root in collection_roots(index_dir, collection)? {
+            let root_facet = Facet::from_path(std::iter::once("folder".to_string()).chain(path_components(&root)));
+            let mut collector = tantivy::collector::FacetCollector::for_field("facets");
+            collector.add_facet(root_facet.clone());
+            let counts = searcher.search(&q, &collector)?;
+            let prefix = root_facet.to_path_string();
+            for (facet, count) in counts.get(root_facet) {
+                *folders.entry(facet_label(facet, &prefix)).or_insert(0) += count;
+            }
+        }
+        let values = folders.into_iter().map(|(value, count)| FacetValue { value, count }).collect();
+        out.insert("folder".to_string(), serde_json::to_value(sorted_facet_values(values))?);
+    }
+    Ok(Some(serde_json::Value::Object(out)))
+}
+
+fn facet_label(facet: &Facet, prefix: &str) -> String {
+    facet.to_path_string().strip_prefix(prefix).unwrap_or_default().trim_start_matches('/').to_string()
+}
+
+fn sorted_facet_values(mut values: Vec<FacetValue>) -> Vec<FacetValue> {
+    values.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    values
+}
+
+/// `--folder` scoping: notes under any `include` directory and none of the `exclude` ones.
+#[derive(Debug, Clone, Default)]
+struct FolderScope {
+    include: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+}
+
+impl FolderScope {
+    /// Relative prefixes are resolved against every collection root; `!` or `-` negates.
+    fn resolve(index_dir: &str, folders: &[String]) -> Result<FolderScope> {
+        let mut scope = FolderScope::default();
+        if folders.is_empty() {
+            return Ok(scope);
+        }
+        let roots = collection_roots(index_dir, None)?;
+        for folder in folders {
+            let (negated, prefix) = match folder.strip_prefix(['!', '-']) {
+                Some(rest) => (true, rest),
+                None => (false, folder.as_str()),
+            };
+            if prefix.trim_matches('/').is_empty() {
+                anyhow::bail!("Empty folder prefix: {folder:?}");
+            }
+            let prefix = Path::new(prefix);
+            let dirs: Vec<PathBuf> = if prefix.is_absolute() || roots.is_empty() {
+                vec![prefix.to_path_buf()]
+            } else {
+                roots.iter().map(|root| root.join(prefix)).collect()
+            };
+            if negated { scope.exclude.extend(dirs) } else { scope.include.extend(dirs) }
+        }
+        Ok(scope)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// The `[start, end)` range of path strings under the folder, when the scope is
+    /// exactly one included folder, so it can be pushed into a range lookup.
+    fn single_folder_range(&self) -> Option<(String, String)> {
+        match (self.include.as_slice(), self.exclude.is_empty()) {
+            ([dir], true) => {
+                let dir = dir.to_str()?.trim_end_matches('/');
+                // '0' sorts right after '/'.
+                Some((format!("{dir}/"), format!("{dir}0")))
+            }
+            _ => None,
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        (self.include.is_empty() || self.include.iter().any(|d| path.starts_with(d)))
+            && !self.exclude.iter().any(|d| path.starts_with(d))
+    }
+
+    /// Narrows `query` with `/folder/..` facet terms, which match every note below a directory.
+    fn restrict(&self, query: Box<dyn tantivy::query::Query>, facets: Field) -> Box<dyn tantivy::query::Query> {
+        use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, TermQuery};
+        if self.is_empty() {
+            return query;
+        }
+        let folder_term = |dir: &PathBuf| -> Box<dyn Query> {
+            let facet = Facet::from_path(std::iter::once("folder".to_string()).chain(path_components(dir)));
+            Box::new(TermQuery::new(Term::from_facet(facets, &facet), IndexRecordOption::Basic))
+        };
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![(Occur::Must, query)];
+        if !self.include.is_empty() {
+            let any = self.include.iter().map(|d| (Occur::Should, folder_term(d))).collect();
+            clauses.push((Occur::Must, Box::new(BooleanQuery::new(any))));
+        } else {
+            clauses.push((Occur::Must, Box::new(AllQuery)));
+        }
+        clauses.extend(self.exclude.iter().map(|d| (Occur::MustNot, folder_term(d))));
+        Box::new(BooleanQuery::new(clauses))
+    }
+}
+
+/// Collection roots recorded by `obsidx index`, optionally narrowed to one collection.
+fn collection_roots(index_dir: &str, collection: Option<&str>) -> Result<Vec<PathBuf>> {
+    if !catalog_path(index_dir).exists() {
+        return Ok(Vec::new());
+    }
+    let conn = open_catalog(index_dir)?;
+    let mut stmt = conn.prepare("SELECT name, root FROM collections ORDER BY name")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut roots = Vec::new();
+    for row in rows {
+        let (name, root) = row?;
+        if collection.is_none_or(|c| c == name) {
+            roots.push(PathBuf::from(root));
+        }
+    }
+    Ok(roots)
+}
+
+fn print_query_issues(issues: &[QueryIssue]) {
+    for issue in issues {
+        match issue.position {
+            Some(pos) => eprintln!("query issue at {pos}: {}", issue.message),
+            None => eprintln!("query issue: {}", issue.message),
+        }
+    }
+}
+
+fn print_facets(facets: &serde_json::Value) {
+    if let Some(map) = facets.as_object() {
+        for (name, values) in map {
+            for v in values.as_array().into_iter().flatten() {
+                println!("facet:{name}\t{}\t{}", v["value"].as_str().unwrap_or(""), v["count"]);
+            }
+        }
+    }
+}
+
+/// Frontmatter `aliases:` (or `alias:`), as a list or a single string.
+/// Fenced code blocks and inline code spans of a markdown body.
+fn code_spans(body: &str) -> Vec<String> {
+    let mut spans = Vec::new();
+    let mut block: Option<String> = None;
+    for event in MdParser::new(body) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => block = Some(String::new()),
+            Event::End(TagEnd::CodeBlock) => spans.extend(block.take()),
+            Event::Text(t) if block.is_some() => block.get_or_insert_default().push_str(&t),
+            Event::Code(t) => spans.push(t.to_string()),
+            _ => {}
+        }
+    }
+    spans
+}
+
+fn frontmatter_aliases(frontmatter_json: &str) -> Vec<String> {
+    let Ok(serde_json::Value::Object(map)) = serde_json::from_str::<serde_json::Value>(frontmatter_json) else {
+        return Vec::new();
+    };
+    match map.get("aliases").or_else(|| map.get("alias")) {
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        Some(serde_json::Value::Array(items)) => items.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn search_index(index_dir: &str, query: &str, limit: usize, json_out: bool, collection: Option<String>, scope: &FolderScope, min_score: f32, files: bool, all: bool, snippet_opts: &SnippetOptions, query_opts: &QueryOptions, facets: &[String], page: &PageOptions, explain: bool) -> Result<()> {
+    let facet_json = facet_counts(index_dir, query, collection.as_deref(), scope, query_opts, facets)?;
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+
+    let schema = index.schema();
+    let path_field = schema.get_field("path").unwrap();
+    let title_field = schema.get_field("title").unwrap();
+    let content_field = schema.get_field("content").unwrap();
+    let docid_field = schema.get_field("doc_id").unwrap();
+    let collection_field = schema.get_field("collection").unwrap();
+
+    let (mut q, issues) = parse_user_query_reporting(&index, query, query_opts)?;
+    let snippets = snippet_generator(&searcher, &*q, content_field, snippet_opts)?;
+    if let Some(name) = collection {
+        let term = Term::from_field_text(collection_field, &name);
+        let filter = tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
+        q = Box::new(tantivy::query::BooleanQuery::intersection(vec![q, Box::new(filter)]));
+    }
+    let q = scope.restrict(q, schema.get_field("facets").unwrap());
+    let page_size = page.page_size(limit, all);
+    let total_hits = searcher.search(&q, &tantivy::collector::Count)?;
+    let top_docs: Vec<(f32, tantivy::DocAddress)> = match page.sort {
+        SortOrder::Relevance => searcher.search(&q, &TopDocs::with_limit(page_size.max(1)).and_offset(page.offset))?,
+        SortOrder::Mtime => {
+            let by_mtime = TopDocs::with_limit(page_size.max(1))
+                .and_offset(page.offset)
+                .order_by_fast_field::<i64>("mtime", tantivy::Order::Desc);
+            let hits = searcher.search(&q, &by_mtime)?;
+            hits.into_iter()
+                .map(|(_, addr)| Ok((q.explain(&searcher, addr)?.value(), addr)))
+                .collect::<tantivy::Result<_>>()?
+        }
+        SortOrder::Title | SortOrder::Path => {
+            let matched = searcher.search(&q, &tantivy::collector::DocSetCollector)?;
+            let ordered = catalog_sorted_hits(index_dir, &searcher, &matched, page.sort, page.offset + page_size)?;
+            page_slice(ordered, page, page_size)
+                .into_iter()
+                .map(|addr| Ok((q.explain(&searcher, addr)?.value(), addr)))
+                .collect::<tantivy::Result<_>>()?
+        }
+    };
+    let top_docs = if page_size == 0 { Vec::new() } else { top_docs };
+    let page_info = page.page_info(total_hits, top_docs.len());
+    let suggestion = if total_hits == 0 { did_you_mean(&searcher, &schema, query)? } else { None };
 
     let mut results = Vec::new();
     for (score, doc_address) in top_docs {
@@ -649,937 +2638,4145 @@ fn search_index(index_dir: &str, query: &str, limit: usize, json_out: bool, coll
             .and_then(|v| v.as_str())
             .unwrap_or("")
             .to_string();
-        results.push(SearchResult { path, title, score, doc_id });
+        let (snippet, heading) = match snippets.as_ref() {
+            Some(generator) => note_snippet(generator, &retrieved, content_field, snippet_opts),
+            None => (None, None),
+        };
+        let explain = if explain { Some(serde_json::to_value(q.explain(&searcher, doc_address)?)?) } else { None };
+        results.push(SearchResult { path, title, score, doc_id, snippet, heading, explain });
+    }
+
+    if json_out {
+        let mut data = if files {
+            let files_out: Vec<String> = results.iter().map(|r| r.path.clone()).collect();
+            json!({"query": query, "files": files_out})
+        } else {
+            json!({
+                "query": query,
+                "results": results
+            })
+        };
+        page_info.merge_into(&mut data);
+        if let Some(f) = facet_json {
+            data["facets"] = f;
+        }
+        if let Some(s) = &suggestion {
+            data["did_you_mean"] = json!(s);
+        }
+        if !issues.is_empty() {
+            data["query_issues"] = json!(issues);
+        }
+        println!("{}", json_response(data));
+    } else {
+        for r in results {
+            println!("{}\t{}\t{:.2}", r.path, r.title, r.score);
+            if let Some(e) = &r.explain {
+                print_explanation(e, 1);
+            }
+        }
+        if let Some(s) = suggestion {
+            println!("did you mean: {s}");
+        }
+        print_query_issues(&issues);
+        if let Some(f) = facet_json {
+            print_facets(&f);
+        }
+    }
+
+    Ok(())
+}
+
+/// Words shorter than this are never corrected: one edit away from almost anything.
+const MIN_SUGGEST_CHARS: usize = 4;
+
+/// Lets the term dictionary stream only the terms a Levenshtein DFA accepts.
+struct LevenshteinDfa(levenshtein_automata::DFA);
+
+impl tantivy_fst::Automaton for LevenshteinDfa {
+    type State = u32;
+
+    fn start(&self) -> u32 {
+        self.0.initial_state()
+    }
+
+    fn is_match(&self, state: &u32) -> bool {
+        matches!(self.0.distance(*state), levenshtein_automata::Distance::Exact(_))
+    }
+
+    fn can_match(&self, state: &u32) -> bool {
+        *state != levenshtein_automata::SINK_STATE
+    }
+
+    fn accept(&self, state: &u32, byte: u8) -> u32 {
+        self.0.transition(*state, byte)
+    }
+}
+
+/// Rewrites unknown plain words of at least `MIN_SUGGEST_CHARS` in `query` to the
+/// closest indexed title/content term (edit distance 1 for four-letter words, else
+/// up to 2; most frequent wins). None when nothing would change.
+fn did_you_mean(searcher: &tantivy::Searcher, schema: &Schema, query: &str) -> Result<Option<String>> {
+    let fields: Vec<Field> = ["title", "content"].iter().filter_map(|n| schema.get_field(n).ok()).collect();
+    let is_plain = |w: &str| {
+        w.chars().count() >= MIN_SUGGEST_CHARS && w.chars().all(|c| c.is_alphanumeric()) && !matches!(w, "AND" | "OR" | "NOT")
+    };
+
+    let mut unknown: Vec<String> = Vec::new();
+    for word in query.split_whitespace().map(|w| w.trim_start_matches(['+', '-'])) {
+        if !is_plain(word) {
+            continue;
+        }
+        let lower = word.to_lowercase();
+        let mut known = false;
+        for field in &fields {
+            // Stemmed or bigrammed fields store analyzed forms, not the raw word;
+            // a word the analyzer drops entirely (a stopword) counts as known.
+            let mut analyzer = searcher.index().tokenizer_for_field(*field)?;
+            let mut stream = analyzer.token_stream(&lower);
+            let mut terms = Vec::new();
+            while stream.advance() {
+                terms.push(stream.token().text.clone());
+            }
+            if terms.is_empty() {
+                known = true;
+            }
+            for segment in searcher.segment_readers() {
+                for term in &terms {
+                    if segment.inverted_index(*field)?.terms().get(term.as_bytes())?.is_some() {
+                        known = true;
+                    }
+                }
+            }
+        }
+        if !known && !unknown.contains(&lower) {
+            unknown.push(lower);
+        }
+    }
+    if unknown.is_empty() {
+        return Ok(None);
+    }
+
+    // (distance, doc_freq, term) of the best candidate per unknown word.
+    let builders = [1, MAX_FUZZY_DISTANCE].map(|d| levenshtein_automata::LevenshteinAutomatonBuilder::new(d, true));
+    let mut best: HashMap<String, (u8, u32, String)> = HashMap::new();
+    for word in &unknown {
+        let dfa = LevenshteinDfa(builders[usize::from(word.chars().count() > MIN_SUGGEST_CHARS)].build_dfa(word));
+        for segment in searcher.segment_readers() {
+            for field in &fields {
+                let inverted = segment.inverted_index(*field)?;
+                let mut stream = inverted.terms().search(&dfa).into_stream()?;
+                while stream.advance() {
+                    let Ok(term) = std::str::from_utf8(stream.key()) else { continue };
+                    let levenshtein_automata::Distance::Exact(distance) = dfa.0.eval(term) else { continue };
+                    if distance == 0 {
+                        continue;
+                    }
+                    let doc_freq = stream.value().doc_freq;
+                    let better = best.get(word).is_none_or(|(d, f, _)| distance < *d || (distance == *d && doc_freq > *f));
+                    if better {
+                        best.insert(word.clone(), (distance, doc_freq, term.to_string()));
+                    }
+                }
+            }
+        }
+    }
+    if best.is_empty() {
+        return Ok(None);
+    }
+
+    let rewritten: Vec<String> = query
+        .split_whitespace()
+        .map(|token| {
+            let word = token.trim_start_matches(['+', '-']);
+            match best.get(&word.to_lowercase()) {
+                Some((_, _, term)) if is_plain(word) => format!("{}{term}", &token[..token.len() - word.len()]),
+                _ => token.to_string(),
+            }
+        })
+        .collect();
+    Ok(Some(rewritten.join(" ")))
+}
+
+fn get_note(index_dir: &str, path: &str, json_out: bool, include_content: bool, collection: Option<String>) -> Result<()> {
+    let lookup = resolve_doc_id(path);
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let schema = index.schema();
+    let path_field = schema.get_field("path").unwrap();
+
+    let term = if lookup.is_doc_id {
+        Term::from_field_text(schema.get_field("doc_id").unwrap(), &lookup.value)
+    } else {
+        Term::from_field_text(path_field, &lookup.value)
+    };
+    let doc_opt: Option<TantivyDocument> = searcher
+        .search(
+            &tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic),
+            &TopDocs::with_limit(1),
+        )?
+        .into_iter()
+        .next()
+        .map(|(_, addr)| searcher.doc(addr))
+        .transpose()?;
+
+    if let Some(doc) = doc_opt {
+        if let Some(name) = collection.as_ref() {
+            let coll = doc
+                .get_first(schema.get_field("collection").unwrap())
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            if coll != name {
+                if json_out {
+                    let out = json_response(json!({"error": {"code": "not_found", "message": "Not in collection"}}));
+                    println!("{out}");
+                }
+                return Ok(());
+            }
+        }
+        let title = doc
+            .get_first(schema.get_field("title").unwrap())
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        let tags = doc
+            .get_first(schema.get_field("tags").unwrap())
+            .and_then(|v| v.as_str())
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .unwrap_or_default();
+        let headings = doc
+            .get_first(schema.get_field("headings").unwrap())
+            .and_then(|v| v.as_str())
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .unwrap_or_default();
+        let links = doc
+            .get_first(schema.get_field("links").unwrap())
+            .and_then(|v| v.as_str())
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .unwrap_or_default();
+        let frontmatter = doc
+            .get_first(schema.get_field("frontmatter").unwrap())
+            .and_then(|v| v.as_str())
+            .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok())
+            .unwrap_or_else(|| json!({}));
+        let mtime = doc
+            .get_first(schema.get_field("mtime").unwrap())
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        let content = if include_content {
+            doc.get_first(schema.get_field("content").unwrap())
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string()
+        } else {
+            "".to_string()
+        };
+
+        let detail = NoteDetail {
+            path: path.to_string(),
+            title: title.to_string(),
+            content,
+            tags,
+            headings,
+            links,
+            frontmatter,
+            mtime,
+        };
+
+        if json_out {
+            let out = json_response(json!({ "note": detail }));
+            println!("{out}");
+        } else {
+            println!("{}\t{}", detail.path, detail.title);
+        }
+    } else if json_out {
+        let out = json_response(json!({
+            "error": {
+                "code": "not_found",
+                "message": format!("No note found for path: {path}")
+            }
+        }));
+        println!("{out}");
+    }
+
+    Ok(())
+}
+
+fn list_tags(index_dir: &str, json_out: bool, scope: &FolderScope) -> Result<()> {
+    let conn = open_existing_catalog(index_dir)?;
+    let results: Vec<TagCount> = if scope.is_empty() {
+        let mut stmt = conn.prepare(
+            "SELECT tag, COUNT(*) AS n FROM tags GROUP BY tag ORDER BY n DESC, tag ASC",
+        )?;
+        stmt.query_map([], |row| {
+            let tag: String = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            Ok(TagCount { tag, count: count as usize })
+        })?
+        .filter_map(|r| r.ok())
+        .collect()
+    } else {
+        let mut stmt = conn.prepare("SELECT path, tag FROM tags")?;
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for row in stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))? {
+            let (path, tag) = row?;
+            if scope.matches(&path) {
+                *counts.entry(tag).or_insert(0) += 1;
+            }
+        }
+        let mut results: Vec<TagCount> = counts.into_iter().map(|(tag, count)| TagCount { tag, count }).collect();
+        results.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        results
+    };
+
+    if json_out {
+        let out = json_response(json!({ "results": results }));
+        println!("{out}");
+    } else {
+        for r in results {
+            println!("{}\t{}", r.tag, r.count);
+        }
+    }
+
+    Ok(())
+}
+
+fn list_links(index_dir: &str, from: &str, json_out: bool) -> Result<()> {
+    let conn = open_existing_catalog(index_dir)?;
+    let mut stmt = conn.prepare("SELECT target FROM links WHERE source = ?1 ORDER BY target")?;
+    let links: Vec<String> = stmt
+        .query_map(params![from], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    if json_out {
+        let out = json_response(json!({ "from": from, "links": links }));
+        println!("{out}");
+    } else {
+        for l in links {
+            println!("{l}");
+        }
+    }
+
+    Ok(())
+}
+
+
+fn list_backlinks(index_dir: &str, to: &str, json_out: bool, scope: &FolderScope) -> Result<()> {
+    let conn = open_existing_catalog(index_dir)?;
+    let mut stmt = conn.prepare("SELECT DISTINCT source FROM links WHERE target = ?1 ORDER BY source")?;
+    let results: Vec<String> = stmt
+        .query_map(params![to], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .filter(|source: &String| scope.matches(source))
+        .collect();
+
+    if json_out {
+        let out = json_response(json!({ "to": to, "backlinks": results }));
+        println!("{out}");
+    } else {
+        for r in results {
+            println!("{r}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Candidates taken from each of the two lists `related` fuses.
+const RELATED_CANDIDATES: usize = 50;
+
+#[allow(clippy::too_many_arguments)]
+fn related_notes(index_dir: &str, path: &str, limit: usize, rrf_k: u32, json_out: bool, collection: Option<String>, scope: &FolderScope, exclude_linked: bool, vector_mode: RelatedVectorMode, files: bool) -> Result<()> {
+    let lookup = resolve_doc_id(path);
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let fields = schema_fields(&index)?;
+
+    let term = if lookup.is_doc_id {
+        Term::from_field_text(fields.doc_id, &lookup.value)
+    } else {
+        Term::from_field_text(fields.path, &lookup.value)
+    };
+    let hit = searcher
+        .search(&tantivy::query::TermQuery::new(term, IndexRecordOption::Basic), &TopDocs::with_limit(1))?
+        .into_iter()
+        .next();
+    let Some((_, addr)) = hit else {
+        if json_out {
+            let out = json_response(json!({
+                "error": {
+                    "code": "not_found",
+                    "message": format!("No note found for path: {path}")
+                }
+            }));
+            println!("{out}");
+        }
+        return Ok(());
+    };
+    let doc: TantivyDocument = searcher.doc(addr)?;
+    let text = |f: Field| doc.get_first(f).and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let source = text(fields.path);
+
+    let mut excluded: std::collections::HashSet<String> = std::collections::HashSet::from([source.clone()]);
+    if exclude_linked {
+        excluded.extend(linked_note_paths(index_dir, &source)?);
+    }
+
+    // Lexical side: the note's most distinctive title and content terms that
+    // also occur somewhere else in the index.
+    let mlt = tantivy::query::MoreLikeThisQuery::builder()
+        .with_min_doc_frequency(2)
+        .with_min_term_frequency(1)
+        .with_min_word_length(3)
+        .with_max_query_terms(25)
+        .with_document_fields(vec![
+            (fields.title, vec![tantivy::schema::OwnedValue::Str(text(fields.title))]),
+            (fields.content, vec![tantivy::schema::OwnedValue::Str(text(fields.content))]),
+        ]);
+    let mut q: Box<dyn tantivy::query::Query> = Box::new(mlt);
+    if let Some(name) = collection.as_deref() {
+        let filter = tantivy::query::TermQuery::new(Term::from_field_text(fields.collection, name), IndexRecordOption::Basic);
+        q = Box::new(tantivy::query::BooleanQuery::intersection(vec![q, Box::new(filter)]));
+    }
+    let q = scope.restrict(q, fields.facets);
+    let mut bm25: Vec<(String, f32)> = Vec::new();
+    for (score, addr) in searcher.search(&q, &TopDocs::with_limit(RELATED_CANDIDATES + excluded.len()))? {
+        let hit: TantivyDocument = searcher.doc(addr)?;
+        let hit_path = hit.get_first(fields.path).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        if !excluded.contains(&hit_path) {
+            bm25.push((hit_path, score));
+        }
+    }
+    bm25.truncate(RELATED_CANDIDATES);
+
+    let mut vector = related_by_embedding(index_dir, &source, collection.as_deref(), scope, vector_mode)?;
+    vector.retain(|(p, _)| !excluded.contains(p));
+    vector.truncate(RELATED_CANDIDATES);
+
+    let mut fusion = RrfFusion::new(rrf_k);
+    fusion.add(&source, "bm25", 1.0, bm25);
+    fusion.add(&source, "vector", 1.0, vector);
+    let mut fused = fusion.scores();
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+    fused.truncate(limit);
+
+    let meta = catalog_note_meta(index_dir)?;
+    let rank = |path: &str, signal: &str| fusion.contributions.get(path).and_then(|c| c.iter().find(|c| c.signal == signal)).map(|c| c.rank);
+    let results: Vec<RelatedResult> = fused
+        .into_iter()
+        .map(|(path, score)| RelatedResult {
+            title: meta.get(&path).map(|(t, _)| t.clone()).unwrap_or_default(),
+            bm25_rank: rank(&path, "bm25"),
+            vector_rank: rank(&path, "vector"),
+            path,
+            score,
+        })
+        .collect();
+
+    if json_out {
+        let data = if files {
+            let files_out: Vec<String> = results.iter().map(|r| r.path.clone()).collect();
+            json!({ "path": source, "files": files_out })
+        } else {
+            json!({ "path": source, "results": results })
+        };
+        println!("{}", json_response(data));
+    } else {
+        for r in results {
+            println!("{}\t{}\t{:.4}", r.path, r.title, r.score);
+        }
+    }
+    Ok(())
+}
+
+/// Other notes ranked by embedding similarity to `source`'s chunks, keeping each
+/// note's best chunk. Empty when `source` has no embeddings.
+fn related_by_embedding(index_dir: &str, source: &str, collection: Option<&str>, scope: &FolderScope, mode: RelatedVectorMode) -> Result<Vec<(String, f32)>> {
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    if !db_path.exists() {
+        return Ok(Vec::new());
+    }
+    let conn = open_sqlite(&db_path)?;
+    let encoding = stored_vector_encoding(&conn)?;
+    let mut stmt = conn.prepare("SELECT path, collection, embedding FROM chunks")?;
+    let rows: Vec<(String, Option<String>, Vec<f32>)> = stmt
+        .query_map([], |row| {
+            let blob: Vec<u8> = row.get(2)?;
+            Ok((row.get(0)?, row.get(1)?, encoding.decode(&blob)))
+        })?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    let own: Vec<&Vec<f32>> = rows.iter().filter(|(p, _, e)| p == source && !e.is_empty()).map(|(_, _, e)| e).collect();
+    let Some(first) = own.first() else {
+        return Ok(Vec::new());
+    };
+    let dims = first.len();
+    let mut centroid = vec![0.0f32; dims];
+    for emb in own.iter().filter(|e| e.len() == dims) {
+        for (c, v) in centroid.iter_mut().zip(emb.iter()) {
+            *c += v;
+        }
+    }
+
+    let mut best: HashMap<String, f32> = HashMap::new();
+    for (path, coll, emb) in &rows {
+        if path == source || !scope.matches(path) || collection.is_some_and(|c| coll.as_deref() != Some(c)) {
+            continue;
+        }
+        let score = match mode {
+            RelatedVectorMode::Centroid => cosine_sim(&centroid, emb),
+            RelatedVectorMode::MaxSim => own.iter().map(|o| cosine_sim(o, emb)).fold(f32::MIN, f32::max),
+        };
+        let entry = best.entry(path.clone()).or_insert(f32::MIN);
+        *entry = entry.max(score);
+    }
+    let mut ranked: Vec<(String, f32)> = best.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.0.cmp(&b.0)));
+    Ok(ranked)
+}
+
+/// Catalog paths of the notes `source` links to. Targets resolve the way Obsidian
+/// does: `[[name]]` by file name, `[[dir/name]]` and `[text](dir/name.md)` by path suffix.
+fn linked_note_paths(index_dir: &str, source: &str) -> Result<std::collections::HashSet<String>> {
+    let conn = open_existing_catalog(index_dir)?;
+    let targets: Vec<String> = conn
+        .prepare("SELECT target FROM links WHERE source = ?1")?
+        .query_map(params![source], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .collect();
+    let paths: Vec<String> = conn
+        .prepare("SELECT path FROM notes")?
+        .query_map([], |row| row.get(0))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    let mut linked = std::collections::HashSet::new();
+    for target in &targets {
+        let target = target.split(['#', '|']).next().unwrap_or("").trim();
+        let target = target.strip_suffix(".md").unwrap_or(target).trim_start_matches("./");
+        if target.is_empty() || target.contains("://") {
+            continue;
+        }
+        let suffix = format!("/{target}");
+        for path in &paths {
+            let stem = path.strip_suffix(".md").unwrap_or(path);
+            if stem == target || stem.ends_with(&suffix) {
+                linked.insert(path.clone());
+            }
+        }
+    }
+    Ok(linked)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn grep_notes(index_dir: &str, pattern: &str, fixed_strings: bool, ignore_case: bool, context: usize, limit: usize, json_out: bool, collection: Option<String>, scope: &FolderScope, files: bool) -> Result<()> {
+    let source = if fixed_strings { regex::escape(pattern) } else { pattern.to_string() };
+    let source = if ignore_case { format!("(?i){source}") } else { source };
+    let re = Regex::new(&source).with_context(|| format!("Invalid pattern: {pattern}"))?;
+
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let fields = schema_fields(&index)?;
+
+    // Narrow candidates to notes whose content terms contain each literal the pattern
+    // requires. Stemmed terms no longer contain the original text, so indexes built
+    // with a stemming analyzer scan every note instead.
+    let mut clauses: Vec<(tantivy::query::Occur, Box<dyn tantivy::query::Query>)> = vec![(tantivy::query::Occur::Must, Box::new(tantivy::query::AllQuery))];
+    if matches!(Analyzer::from_schema(&index.schema()), Analyzer::Default | Analyzer::Cjk) {
+        for literal in required_literals(pattern, !fixed_strings) {
+            let term_pattern = format!(".*{}.*", regex::escape(&literal));
+            clauses.push((tantivy::query::Occur::Must, Box::new(tantivy::query::RegexQuery::from_pattern(&term_pattern, fields.content)?)));
+        }
+    }
+    if let Some(name) = collection.as_deref() {
+        let filter = tantivy::query::TermQuery::new(Term::from_field_text(fields.collection, name), IndexRecordOption::Basic);
+        clauses.push((tantivy::query::Occur::Must, Box::new(filter)));
+    }
+    let q = scope.restrict(Box::new(tantivy::query::BooleanQuery::new(clauses)), fields.facets);
+
+    let mut candidates: Vec<(String, String, String)> = Vec::new();
+    for addr in searcher.search(&q, &tantivy::collector::DocSetCollector)? {
+        let doc: TantivyDocument = searcher.doc(addr)?;
+        let text = |f: Field| doc.get_first(f).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        candidates.push((text(fields.path), text(fields.title), text(fields.content)));
+    }
+    candidates.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let notes_scanned = candidates.len();
+    let mut total_matches = 0;
+    let mut truncated = false;
+    let mut results: Vec<GrepFile> = Vec::new();
+    for (path, title, content) in candidates {
+        let lines: Vec<&str> = content.lines().collect();
+        let hits: Vec<usize> = lines.iter().enumerate().filter(|(_, l)| re.is_match(l)).map(|(i, _)| i).collect();
+        if hits.is_empty() {
+            continue;
+        }
+        let offset = body_line_offset(&path, &content);
+        let mut matches = Vec::new();
+        for i in hits {
+            if total_matches >= limit {
+                truncated = true;
+                break;
+            }
+            total_matches += 1;
+            let around = |range: std::ops::Range<usize>| lines[range].iter().map(|l| l.to_string()).collect::<Vec<_>>();
+            matches.push(GrepMatch {
+                line: offset + i + 1,
+                text: lines[i].to_string(),
+                before: around(i.saturating_sub(context)..i),
+                after: around(i + 1..(i + 1 + context).min(lines.len())),
+            });
+        }
+        if !matches.is_empty() {
+            results.push(GrepFile { path, title, matches });
+        }
+        if truncated {
+            break;
+        }
+    }
+
+    if json_out {
+        let mut data = if files {
+            let files_out: Vec<String> = results.iter().map(|r| r.path.clone()).collect();
+            json!({ "pattern": pattern, "files": files_out })
+        } else {
+            json!({ "pattern": pattern, "results": results })
+        };
+        data["notes_scanned"] = json!(notes_scanned);
+        data["total_matches"] = json!(total_matches);
+        data["truncated"] = json!(truncated);
+        println!("{}", json_response(data));
+    } else if files {
+        for r in results {
+            println!("{}", r.path);
+        }
+    } else {
+        let mut separate = false;
+        for r in results {
+            for m in r.matches {
+                if context > 0 && std::mem::replace(&mut separate, true) {
+                    println!("--");
+                }
+                let first = m.line - m.before.len();
+                for (i, l) in m.before.iter().enumerate() {
+                    println!("{}-{}-{}", r.path, first + i, l);
+                }
+                println!("{}:{}:{}", r.path, m.line, m.text);
+                for (i, l) in m.after.iter().enumerate() {
+                    println!("{}-{}-{}", r.path, m.line + 1 + i, l);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lowercased alphanumeric runs (3+ chars) that every match of `pattern` contains,
+/// for prefiltering on the term dictionary. Conservative: anything optional,
+/// grouped, a character class or under a top-level alternation contributes nothing.
+fn required_literals(pattern: &str, is_regex: bool) -> Vec<String> {
+    let mut runs: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut flush = |current: &mut String| {
+        if current.chars().count() >= 3 && current.len() <= 40 {
+            runs.push(current.to_lowercase());
+        }
+        current.clear();
+    };
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if !is_regex {
+            if c.is_alphanumeric() && !is_cjk(c) {
+                current.push(c);
+            } else {
+                flush(&mut current);
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            '\\' => {
+                flush(&mut current);
+                i += 1;
+            }
+            '(' | '[' => {
+                flush(&mut current);
+                let (open, close) = if c == '(' { ('(', ')') } else { ('[', ']') };
+                let mut depth = 0;
+                while i < chars.len() {
+                    match chars[i] {
+                        '\\' => i += 1,
+                        ch if ch == open && (open == '(' || depth == 0) => depth += 1,
+                        ch if ch == close => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 1;
+                }
+            }
+            '*' | '?' => {
+                current.pop();
+                flush(&mut current);
+            }
+            '{' => {
+                if chars.get(i + 1) == Some(&'0') || chars.get(i + 1) == Some(&',') {
+                    current.pop();
+                }
+                flush(&mut current);
+                while i < chars.len() && chars[i] != '}' {
+                    i += 1;
+                }
+            }
+            '|' => return Vec::new(),
+            c if c.is_alphanumeric() && !is_cjk(c) => current.push(c),
+            _ => flush(&mut current),
+        }
+        i += 1;
+    }
+    flush(&mut current);
+    runs
+}
+
+/// Lines before the indexed body in the note file (its frontmatter), so grep can
+/// report file line numbers. 0 when the file is gone or changed since indexing.
+fn body_line_offset(path: &str, body: &str) -> usize {
+    let Ok(raw) = fs::read_to_string(path) else { return 0 };
+    raw.find(body).map(|pos| raw[..pos].matches('\n').count()).unwrap_or(0)
+}
+
+const CATALOG_SCHEMA: &str = "\
+    CREATE TABLE IF NOT EXISTS notes (\
+        path TEXT PRIMARY KEY,\
+        doc_id TEXT NOT NULL,\
+        collection TEXT NOT NULL,\
+        title TEXT NOT NULL,\
+        mtime INTEGER NOT NULL,\
+        indexed_at INTEGER NOT NULL\
+    );\
+    CREATE TABLE IF NOT EXISTS links (\
+        source TEXT NOT NULL,\
+        target TEXT NOT NULL,\
+        PRIMARY KEY (source, target)\
+    );\
+    CREATE TABLE IF NOT EXISTS tags (\
+        path TEXT NOT NULL,\
+        tag TEXT NOT NULL,\
+        PRIMARY KEY (path, tag)\
+    );\
+    CREATE TABLE IF NOT EXISTS properties (\
+        path TEXT NOT NULL,\
+        key TEXT NOT NULL,\
+        value TEXT NOT NULL,\
+        PRIMARY KEY (path, key)\
+    );\
+    CREATE TABLE IF NOT EXISTS collections (\
+        name TEXT PRIMARY KEY,\
+        root TEXT NOT NULL,\
+        indexed_at INTEGER NOT NULL\
+    );\
+    CREATE INDEX IF NOT EXISTS idx_notes_doc_id ON notes(doc_id);\
+    CREATE INDEX IF NOT EXISTS idx_notes_collection ON notes(collection);\
+    CREATE INDEX IF NOT EXISTS idx_links_target ON links(target);\
+    CREATE INDEX IF NOT EXISTS idx_tags_tag ON tags(tag);\
+    CREATE INDEX IF NOT EXISTS idx_properties_key ON properties(key);\
+";
+
+fn catalog_path(index_dir: &str) -> PathBuf {
+    Path::new(index_dir).join("catalog.db")
+}
+
+fn open_catalog(index_dir: &str) -> Result<Connection> {
+    fs::create_dir_all(index_dir).ok();
+    let conn = open_sqlite(&catalog_path(index_dir))?;
+    conn.execute_batch(CATALOG_SCHEMA)?;
+    // Added after the first catalog release; older catalogs gain it in place.
+    let has_language: bool = conn
+        .prepare("SELECT 1 FROM pragma_table_info('notes') WHERE name = 'language'")?
+        .exists([])?;
+    if !has_language {
+        conn.execute_batch("ALTER TABLE notes ADD COLUMN language TEXT")?;
+    }
+    Ok(conn)
+}
+
+fn open_existing_catalog(index_dir: &str) -> Result<Connection> {
+    let path = catalog_path(index_dir);
+    if !path.exists() {
+        anyhow::bail!("Catalog not found: {} (run `obsidx index` to build it)", path.display());
+    }
+    open_catalog(index_dir)
+}
+
+fn catalog_clear(conn: &Connection) -> Result<()> {
+    conn.execute_batch("DELETE FROM notes; DELETE FROM links; DELETE FROM tags; DELETE FROM properties; DELETE FROM collections;")?;
+    Ok(())
+}
+
+fn catalog_delete(conn: &Connection, path: &str) -> Result<()> {
+    conn.execute("DELETE FROM notes WHERE path = ?1", params![path])?;
+    conn.execute("DELETE FROM links WHERE source = ?1", params![path])?;
+    conn.execute("DELETE FROM tags WHERE path = ?1", params![path])?;
+    conn.execute("DELETE FROM properties WHERE path = ?1", params![path])?;
+    Ok(())
+}
+
+fn catalog_upsert(conn: &Connection, doc: &NoteDoc) -> Result<()> {
+    catalog_delete(conn, &doc.path)?;
+    conn.execute(
+        "INSERT INTO notes (path, doc_id, collection, title, mtime, indexed_at, language) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![doc.path, doc.doc_id, doc.collection, doc.title, doc.mtime, Utc::now().timestamp(), detect_language(&doc.content).map(|(code, _)| code)],
+    )?;
+    for link in &doc.links {
+        conn.execute(
+            "INSERT OR IGNORE INTO links (source, target) VALUES (?1, ?2)",
+            params![doc.path, link],
+        )?;
+    }
+    for tag in &doc.tags {
+        conn.execute(
+            "INSERT OR IGNORE INTO tags (path, tag) VALUES (?1, ?2)",
+            params![doc.path, tag],
+        )?;
+    }
+    if let Ok(serde_json::Value::Object(map)) = serde_json::from_str::<serde_json::Value>(&doc.frontmatter_json) {
+        for (key, value) in map {
+            conn.execute(
+                "INSERT OR REPLACE INTO properties (path, key, value) VALUES (?1, ?2, ?3)",
+                params![doc.path, key, value.to_string()],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the reindex for requests queued by `note-* --reindex` while `watch` held
+/// the registration: one incremental pass per collection the notes were written
+/// to. Chunking is recorded index-wide, so within a collection the latest request's
+/// chunking applies.
+fn run_queued_reindex(index_dir: &str, reqs: &[ReindexRequest]) {
+    for req in reindex_groups(reqs) {
+        if let Err(e) = reindex_incremental(index_dir, &req) {
+            let name = req.collection.as_deref().unwrap_or("default");
+            eprintln!("queued reindex of collection {name} failed: {e}");
+        }
+    }
+}
+
+/// The last request per (vault, collection), in order of first appearance.
+fn reindex_groups(reqs: &[ReindexRequest]) -> Vec<ReindexRequest> {
+    let mut groups: Vec<ReindexRequest> = Vec::new();
+    for req in reqs {
+        match groups.iter_mut().find(|g| g.vault == req.vault && g.collection == req.collection) {
+            Some(group) => *group = req.clone(),
+            None => groups.push(req.clone()),
+        }
+    }
+    groups
+}
+
+fn watch_vault(vault: &str, index_dir: &str, debounce_ms: u64, optimize_idle_secs: u64) -> Result<()> {
+    let registration = register_watcher(index_dir)?;
+    // Initial index
+    build_index(vault, index_dir, true, None, None, &ScanOptions::default())?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.configure(NotifyConfig::default().with_poll_interval(Duration::from_millis(250)))?;
+    watcher.watch(Path::new(vault), RecursiveMode::Recursive)?;
+
+    println!("Watching {} (index: {})", vault, index_dir);
+
+    // Set after every reindex; cleared once an idle optimize has run.
+    let mut last_reindex: Option<std::time::Instant> = None;
+    loop {
+        // block until event, waking periodically for queued reindex requests
+        match rx.recv_timeout(WATCH_QUEUE_POLL) {
+            Ok(_) => {
+                // debounce: drain events for debounce_ms
+                let start = std::time::Instant::now();
+                while start.elapsed() < Duration::from_millis(debounce_ms) {
+                    if rx.try_recv().is_err() {
+                        std::thread::sleep(Duration::from_millis(50));
+                    }
+                }
+                // incremental rebuild
+                if let Err(e) = build_index(vault, index_dir, true, None, None, &ScanOptions::default()) {
+                    eprintln!("reindex failed: {e}");
+                }
+                last_reindex = Some(std::time::Instant::now());
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let reqs = {
+            let _queue = lock_reindex_queue(index_dir)?;
+            take_reindex_queue(index_dir)
+        };
+        if !reqs.is_empty() {
+            run_queued_reindex(index_dir, &reqs);
+            last_reindex = Some(std::time::Instant::now());
+        }
+
+        if optimize_idle_secs > 0
+            && last_reindex.is_some_and(|t| t.elapsed() >= Duration::from_secs(optimize_idle_secs))
+        {
+            match optimize_index(index_dir) {
+                Ok(summary) => println!("{}", json_response(summary)),
+                Err(e) => eprintln!("optimize failed: {e}"),
+            }
+            last_reindex = None;
+        }
+    }
+
+    // Stop accepting requests, then handle whatever was queued before that; later
+    // writers see no watcher and reindex themselves.
+    let reqs = {
+        let _queue = lock_reindex_queue(index_dir)?;
+        drop(registration);
+        take_reindex_queue(index_dir)
+    };
+    run_queued_reindex(index_dir, &reqs);
+    Ok(())
+}
+
+
+#[derive(Debug, Serialize)]
+struct VectorResult {
+    path: String,
+    score: f32,
+    chunk: String,
+}
+
+fn load_vss_extensions(conn: &Connection) -> Result<()> {
+    unsafe {
+        conn.load_extension_enable()?;
+    }
+    let vector_path = env::var("OBSIDX_VSS_VECTOR0").unwrap_or_else(|_| "vector0".to_string());
+    let vss_path = env::var("OBSIDX_VSS_VSS0").unwrap_or_else(|_| "vss0".to_string());
+    unsafe {
+        conn.load_extension(vector_path, None)?;
+        conn.load_extension(vss_path, None)?;
+    }
+    Ok(())
+}
+
+/// Loads sqlite-vec from `OBSIDX_VEC0`, `[extensions] sqlite_vec` in the config, or
+/// `vec0` on the library search path.
+fn load_vec_extension(conn: &Connection) -> Result<()> {
+    let path = env::var("OBSIDX_VEC0")
+        .ok()
+        .or_else(|| load_config().extensions.sqlite_vec)
+        .unwrap_or_else(|| "vec0".to_string());
+    unsafe {
+        conn.load_extension_enable()?;
+        conn.load_extension(&path, None)
+            .with_context(|| format!("Failed to load sqlite-vec from {path} (set OBSIDX_VEC0 or [extensions] sqlite_vec)"))?;
+    }
+    Ok(())
+}
+
+/// `vec_chunks` shares rowids with `chunks`; collection and path are metadata
+/// columns so KNN queries can filter on them before ranking.
+fn ensure_vec(conn: &Connection, dims: usize) -> Result<()> {
+    load_vec_extension(conn)?;
+    conn.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS vec_chunks USING vec0(embedding float[{dims}] distance_metric=cosine, collection text, path text);"
+    ))?;
+    Ok(())
+}
+
+fn ensure_vss(conn: &Connection, dims: usize) -> Result<()> {
+    load_vss_extensions(conn)?;
+    conn.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS vss_chunks USING vss0(embedding({dims}));"
+    ))?;
+    Ok(())
+}
+
+/// Largest `k` a vec0 KNN query accepts.
+const VEC_MAX_K: usize = 4096;
+
+const HNSW_FILE: &str = "hnsw.bin";
+const HNSW_MAGIC: &[u8; 8] = b"OBXHNSW1";
+const HNSW_MAX_LEVEL: usize = 16;
+
+#[derive(Debug, Clone)]
+struct HnswOptions {
+    /// Links per node on the upper layers; layer 0 keeps twice as many.
+    m: usize,
+    ef_construction: usize,
+    /// Default search breadth, overridable per query with `--hnsw-ef`.
+    ef: usize,
+}
+
+impl Default for HnswOptions {
+    fn default() -> Self {
+        Self { m: 16, ef_construction: 200, ef: 64 }
+    }
+}
+
+struct HnswNode {
+    /// `chunks.id` of the vector.
+    id: i64,
+    /// Hash of the stored embedding BLOB, so `sync` notices reused rowids.
+    fingerprint: u64,
+    vector: Vec<f32>,
+    /// Neighbour slots per layer, layer 0 first.
+    links: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+/// Hierarchical navigable small world graph over chunk embeddings, persisted as
+/// `<index>/hnsw.bin` for `--vector-backend hnsw`. Vectors are normalized on insert
+/// so similarity is a dot product (cosine). Deletes leave tombstones that are
+/// compacted away by `sync` once they make up a quarter of the graph.
+struct Hnsw {
+    opts: HnswOptions,
+    dims: usize,
+    nodes: Vec<HnswNode>,
+    slots: HashMap<i64, usize>,
+    entry: Option<usize>,
+    deleted: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct HnswSync {
+    added: usize,
+    removed: usize,
+    compacted: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn normalized(v: &[f32]) -> Vec<f32> {
+    let norm = dot(v, v).sqrt();
+    if norm == 0.0 { v.to_vec() } else { v.iter().map(|x| x / norm).collect() }
+}
+
+fn blob_fingerprint(blob: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut h = std::collections::hash_map::DefaultHasher::new();
+    blob.hash(&mut h);
+    h.finish()
+}
+
+fn hnsw_path(index_dir: &str) -> PathBuf {
+    Path::new(index_dir).join(HNSW_FILE)
+}
+
+impl Hnsw {
+    fn new(opts: HnswOptions) -> Self {
+        Self { opts, dims: 0, nodes: Vec::new(), slots: HashMap::new(), entry: None, deleted: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len() - self.deleted
+    }
+
+    /// Deterministic level draw keyed on the chunk id, so rebuilds are reproducible.
+    fn random_level(&self, id: i64) -> usize {
+        let mut x = (id as u64).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^= x >> 31;
+        let u = ((x >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.opts.m.max(2) as f64).ln();
+        ((-u.ln() * ml) as usize).min(HNSW_MAX_LEVEL)
+    }
+
+    /// Best-first search of one layer. Every node is traversed, but only nodes
+    /// passing `allow` are collected, so filtered searches keep going until `ef`
+    /// allowed nodes are found. Returns (similarity, slot) best first.
+    fn search_layer(&self, q: &[f32], entry: &[usize], ef: usize, layer: usize, allow: &dyn Fn(&HnswNode) -> bool) -> Vec<Scored> {
+        use std::cmp::Reverse;
+        use std::collections::{BinaryHeap, HashSet};
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for &slot in entry {
+            if visited.insert(slot) {
+                let s = Scored(dot(q, &self.nodes[slot].vector), slot);
+                candidates.push(s);
+                if allow(&self.nodes[slot]) {
+                    results.push(Reverse(s));
+                }
+            }
+        }
+        while let Some(c) = candidates.pop() {
+            if results.len() >= ef && results.peek().is_some_and(|w| c.0 < w.0.0) {
+                break;
+            }
+            let Some(links) = self.nodes[c.1].links.get(layer) else { continue };
+            for &nb in links {
+                let nb = nb as usize;
+                if !visited.insert(nb) {
+                    continue;
+                }
+                let s = Scored(dot(q, &self.nodes[nb].vector), nb);
+                if results.len() < ef || results.peek().is_some_and(|w| s.0 > w.0.0) {
+                    candidates.push(s);
+                    if allow(&self.nodes[nb]) {
+                        results.push(Reverse(s));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        let mut out: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    /// Neighbour selection heuristic: keep a candidate only if it is closer to the
+    /// base than to every neighbour kept so far, then top up with the nearest rest.
+    fn select_neighbors(&self, candidates: &[Scored], max: usize) -> Vec<u32> {
+        let mut kept: Vec<usize> = Vec::new();
+        for c in candidates {
+            if kept.len() >= max {
+                break;
+            }
+            if kept.iter().all(|&k| dot(&self.nodes[c.1].vector, &self.nodes[k].vector) < c.0) {
+                kept.push(c.1);
+            }
+        }
+        for c in candidates {
+            if kept.len() >= max {
+                break;
+            }
+            if !kept.contains(&c.1) {
+                kept.push(c.1);
+            }
+        }
+        kept.into_iter().map(|s| s as u32).collect()
+    }
+
+    fn descend(&self, q: &[f32], to_layer: usize) -> Option<usize> {
+        let mut ep = self.entry?;
+        let top = self.nodes[ep].links.len() - 1;
+        for layer in (to_layer + 1..=top).rev() {
+            if let Some(best) = self.search_layer(q, &[ep], 1, layer, &|_| true).first() {
+                ep = best.1;
+            }
+        }
+        Some(ep)
+    }
+
+    fn insert(&mut self, id: i64, vector: &[f32], fingerprint: u64) -> Result<()> {
+        if self.dims == 0 {
+            self.dims = vector.len();
+        }
+        if vector.len() != self.dims {
+            anyhow::bail!("HNSW graph holds {}-dimensional vectors, got {} for chunk {id}", self.dims, vector.len());
+        }
+        self.remove(id);
+        let vector = normalized(vector);
+        let level = self.random_level(id);
+        let slot = self.nodes.len();
+        self.nodes.push(HnswNode { id, fingerprint, vector, links: vec![Vec::new(); level + 1], deleted: false });
+        self.slots.insert(id, slot);
+        let q = self.nodes[slot].vector.clone();
+        let Some(ep) = self.descend(&q, level) else {
+            self.entry = Some(slot);
+            return Ok(());
+        };
+        let top = self.nodes[self.entry.unwrap()].links.len() - 1;
+        let mut entry = vec![ep];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&q, &entry, self.opts.ef_construction, layer, &|n| !n.deleted && n.id != id);
+            let neighbors = self.select_neighbors(&found, self.opts.m);
+            let max = if layer == 0 { self.opts.m * 2 } else { self.opts.m };
+            for &nb in &neighbors {
+                let nb = nb as usize;
+                self.nodes[nb].links[layer].push(slot as u32);
+                if self.nodes[nb].links[layer].len() > max {
+                    let base = self.nodes[nb].vector.clone();
+                    let mut scored: Vec<Scored> = self.nodes[nb].links[layer]
+                        .iter()
+                        .map(|&s| Scored(dot(&base, &self.nodes[s as usize].vector), s as usize))
+                        .collect();
+                    scored.sort_by(|a, b| b.cmp(a));
+                    self.nodes[nb].links[layer] = self.select_neighbors(&scored, max);
+                }
+            }
+            self.nodes[slot].links[layer] = neighbors;
+            if !found.is_empty() {
+                entry = found.iter().map(|s| s.1).collect();
+            }
+        }
+        if level > top {
+            self.entry = Some(slot);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: i64) -> bool {
+        let Some(slot) = self.slots.remove(&id) else { return false };
+        self.nodes[slot].deleted = true;
+        self.deleted += 1;
+        true
+    }
+
+    /// Rebuilds the graph from its live nodes, dropping tombstones.
+    fn compact(&mut self) -> Result<()> {
+        let live: Vec<HnswNode> = std::mem::take(&mut self.nodes).into_iter().filter(|n| !n.deleted).collect();
+        *self = Hnsw { dims: self.dims, ..Hnsw::new(self.opts.clone()) };
+        for n in live {
+            self.insert(n.id, &n.vector, n.fingerprint)?;
+        }
+        Ok(())
+    }
+
+    /// Approximate top-`k` chunk ids by cosine similarity among those passing `filter`.
+    fn search(&self, query: &[f32], k: usize, ef: usize, filter: &dyn Fn(i64) -> bool) -> Vec<(i64, f32)> {
+        if query.len() != self.dims {
+            return Vec::new();
+        }
+        let q = normalized(query);
+        let Some(ep) = self.descend(&q, 0) else { return Vec::new() };
+        self.search_layer(&q, &[ep], ef.max(k), 0, &|n| !n.deleted && filter(n.id))
+            .into_iter()
+            .take(k)
+            .map(|s| (self.nodes[s.1].id, s.0))
+            .collect()
+    }
+
+    /// Brings the graph in line with the `chunks` table: drops chunks that are gone,
+    /// (re)inserts new or re-embedded ones, and compacts when tombstones pile up.
+    fn sync(&mut self, conn: &Connection) -> Result<HnswSync> {
+        let mut summary = HnswSync::default();
+        let (live, changed) = self.diff(conn)?;
+        let gone: Vec<i64> = self.slots.keys().filter(|id| !live.contains_key(id)).copied().collect();
+        for id in gone {
+            self.remove(id);
+            summary.removed += 1;
+        }
+        if !changed.is_empty() {
+            let encoding = stored_vector_encoding(conn)?;
+            let mut stmt = conn.prepare("SELECT embedding FROM chunks WHERE id = ?1")?;
+            for id in changed {
+                let blob: Vec<u8> = stmt.query_row(params![id], |row| row.get(0))?;
+                let vector = encoding.decode(&blob);
+                if vector.len() != self.dims && self.len() == 0 {
+                    // Everything was re-embedded at a new size; start over.
+                    *self = Hnsw::new(self.opts.clone());
+                }
+                self.insert(id, &vector, blob_fingerprint(&blob))?;
+                summary.added += 1;
+            }
+        }
+        if self.deleted > 0 && self.deleted * 4 >= self.nodes.len() {
+            self.compact()?;
+            summary.compacted = true;
+        }
+        Ok(summary)
+    }
+
+    /// Fingerprints of every stored chunk, plus the ids the graph lacks or holds a
+    /// different vector for.
+    fn diff(&self, conn: &Connection) -> Result<(HashMap<i64, u64>, Vec<i64>)> {
+        let mut stmt = conn.prepare("SELECT id, embedding FROM chunks ORDER BY id")?;
+        let live: HashMap<i64, u64> = stmt
+            .query_map([], |row| {
+                let blob: Vec<u8> = row.get(1)?;
+                Ok((row.get::<_, i64>(0)?, blob_fingerprint(&blob)))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut changed: Vec<i64> = live
+            .iter()
+            .filter(|(id, fp)| self.slots.get(id).is_none_or(|&s| self.nodes[s].fingerprint != **fp))
+            .map(|(id, _)| *id)
+            .collect();
+        changed.sort_unstable();
+        Ok((live, changed))
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut buf: Vec<u8> = Vec::with_capacity(64 + self.nodes.len() * (self.dims * 4 + 32 + self.opts.m * 12));
+        buf.extend_from_slice(HNSW_MAGIC);
+        for v in [self.dims, self.opts.m, self.opts.ef_construction, self.opts.ef, self.nodes.len()] {
+            buf.extend_from_slice(&(v as u32).to_le_bytes());
+        }
+        buf.extend_from_slice(&self.entry.map_or(u32::MAX, |e| e as u32).to_le_bytes());
+        for n in &self.nodes {
+            buf.extend_from_slice(&n.id.to_le_bytes());
+            buf.extend_from_slice(&n.fingerprint.to_le_bytes());
+            buf.push(n.deleted as u8);
+            for x in &n.vector {
+                buf.extend_from_slice(&x.to_le_bytes());
+            }
+            buf.push(n.links.len() as u8);
+            for layer in &n.links {
+                buf.extend_from_slice(&(layer.len() as u32).to_le_bytes());
+                for s in layer {
+                    buf.extend_from_slice(&s.to_le_bytes());
+                }
+            }
+        }
+        let tmp = path.with_extension("bin.tmp");
+        fs::write(&tmp, &buf)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn load(path: &Path) -> Result<Option<Self>> {
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let corrupt = || anyhow::anyhow!("Corrupt HNSW graph {}; rebuild it with embed-index --vector-backend hnsw", path.display());
+        let mut pos = 0;
+        let mut take = |n: usize| -> Result<&[u8]> {
+            let s = bytes.get(pos..pos + n).ok_or_else(corrupt)?;
+            pos += n;
+            Ok(s)
+        };
+        if take(8)? != HNSW_MAGIC {
+            return Err(corrupt());
+        }
+        let mut header = [0u32; 6];
+        for h in &mut header {
+            *h = u32::from_le_bytes(take(4)?.try_into()?);
+        }
+        let [dims, m, ef_construction, ef, count, entry] = header.map(|v| v as usize);
+        let mut graph = Hnsw::new(HnswOptions { m, ef_construction, ef });
+        graph.dims = dims;
+        graph.entry = (entry != u32::MAX as usize).then_some(entry);
+        for slot in 0..count {
+            let id = i64::from_le_bytes(take(8)?.try_into()?);
+            let fingerprint = u64::from_le_bytes(take(8)?.try_into()?);
+            let deleted = take(1)?[0] != 0;
+            let vector: Vec<f32> = take(dims * 4)?.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+            let levels = take(1)?[0] as usize;
+            let mut links = Vec::with_capacity(levels);
+            for _ in 0..levels {
+                let len = u32::from_le_bytes(take(4)?.try_into()?) as usize;
+                let layer: Vec<u32> = take(len * 4)?.chunks_exact(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect();
+                if layer.iter().any(|&s| s as usize >= count) {
+                    return Err(corrupt());
+                }
+                links.push(layer);
+            }
+            if deleted {
+                graph.deleted += 1;
+            } else {
+                graph.slots.insert(id, slot);
+            }
+            graph.nodes.push(HnswNode { id, fingerprint, vector, links, deleted });
+        }
+        if graph.entry.is_some_and(|e| e >= count || graph.nodes[e].links.is_empty()) {
+            return Err(corrupt());
+        }
+        Ok(Some(graph))
+    }
+}
+
+/// Loads `<index>/hnsw.bin`, reusing the parsed graph while the file is unchanged
+/// so the MCP server does not re-read it on every query.
+fn hnsw_graph(index_dir: &str) -> Result<Arc<Hnsw>> {
+    type Cached = (std::time::SystemTime, Arc<Hnsw>);
+    static GRAPHS: OnceLock<Mutex<HashMap<PathBuf, Cached>>> = OnceLock::new();
+    let path = hnsw_path(index_dir);
+    let modified = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .map_err(|_| anyhow::anyhow!("No HNSW graph in {index_dir}; run embed-index --vector-backend hnsw"))?;
+    let mut graphs = GRAPHS.get_or_init(Default::default).lock().unwrap();
+    if let Some((at, graph)) = graphs.get(&path)
+        && *at == modified
+    {
+        return Ok(graph.clone());
+    }
+    let graph = Arc::new(Hnsw::load(&path)?.ok_or_else(|| anyhow::anyhow!("No HNSW graph in {index_dir}"))?);
+    graphs.insert(path, (modified, graph.clone()));
+    Ok(graph)
+}
+
+/// Updates an existing HNSW graph (or builds one when `build` is set) from the
+/// `chunks` table. `fresh` discards the old graph, e.g. after a full re-embed.
+fn update_hnsw(index_dir: &str, conn: &Connection, opts: &HnswOptions, build: bool, fresh: bool) -> Result<Option<HnswSync>> {
+    let path = hnsw_path(index_dir);
+    let existing = if fresh { None } else { Hnsw::load(&path).unwrap_or(None) };
+    let mut graph = match existing {
+        Some(g) => g,
+        None if build || path.exists() => Hnsw::new(opts.clone()),
+        None => return Ok(None),
+    };
+    let summary = graph.sync(conn)?;
+    graph.save(&path)?;
+    Ok(Some(summary))
+}
+
+/// recall@k of the graph against exact search, using up to `samples` stored
+/// vectors as queries.
+fn hnsw_recall(graph: &Hnsw, conn: &Connection, k: usize, samples: usize) -> Result<Option<f32>> {
+    let encoding = stored_vector_encoding(conn)?;
+    let mut stmt = conn.prepare("SELECT id, embedding FROM chunks")?;
+    let vectors: Vec<(i64, Vec<f32>)> = stmt
+        .query_map([], |row| {
+            let blob: Vec<u8> = row.get(1)?;
+            Ok((row.get(0)?, normalized(&encoding.decode(&blob))))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    if vectors.is_empty() {
+        return Ok(None);
+    }
+    let step = vectors.len().div_ceil(samples).max(1);
+    let (mut found, mut expected) = (0, 0);
+    for (_, q) in vectors.iter().step_by(step) {
+        let mut exact: Vec<(i64, f32)> = vectors.iter().map(|(id, v)| (*id, dot(q, v))).collect();
+        exact.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let truth: Vec<i64> = exact.iter().take(k).map(|(id, _)| *id).collect();
+        let approx = graph.search(q, k, graph.opts.ef, &|_| true);
+        found += approx.iter().filter(|(id, _)| truth.contains(id)).count();
+        expected += truth.len();
+    }
+    Ok(Some(found as f32 / expected as f32))
+}
+
+/// Opens embeddings.db for writing, creating its tables and converting old JSON
+/// vectors. Callers hold the write lock; readers use `open_sqlite`.
+fn open_embeddings_db(index_dir: &str) -> Result<Connection> {
+    fs::create_dir_all(index_dir).ok();
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    let conn = open_sqlite(&db_path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS chunks (\
+            id INTEGER PRIMARY KEY,\
+            path TEXT,\
+            collection TEXT,\
+            chunk TEXT,\
+            chunk_hash TEXT,\
+            mtime INTEGER,\
+            embedding BLOB\
+        );\
+         CREATE TABLE IF NOT EXISTS notes (\
+            path TEXT PRIMARY KEY,\
+            collection TEXT,\
+            mtime INTEGER\
+        );\
+         CREATE TABLE IF NOT EXISTS meta (\
+            key TEXT PRIMARY KEY,\
+            value TEXT\
+        );\
+         CREATE INDEX IF NOT EXISTS idx_chunks_path ON chunks(path);\
+         CREATE INDEX IF NOT EXISTS idx_chunks_hash ON chunks(chunk_hash);\
+         CREATE INDEX IF NOT EXISTS idx_chunks_collection ON chunks(collection);\
+        ",
+    )?;
+    let malformed = migrate_json_embeddings(&conn)?;
+    if !malformed.is_empty() {
+        let ids: Vec<String> = malformed.iter().map(i64::to_string).collect();
+        eprintln!("dropped {} malformed JSON embeddings (chunk ids {}); embed-index re-embeds their notes", malformed.len(), ids.join(", "));
+    }
+    Ok(conn)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn embed_index(
+    vault: &str,
+    index_dir: &str,
+    max_chars: usize,
+    overlap: usize,
+    incremental: bool,
+    collection: Option<String>,
+    embed_backend: EmbeddingBackend,
+    embed_model: Option<&str>,
+    embed_dims: Option<usize>,
+    vector_backend: VectorBackend,
+    vector_encoding: Option<VectorEncoding>,
+    hnsw: &HnswOptions,
+    scan_opts: &ScanOptions,
+) -> Result<()> {
+    fs::create_dir_all(index_dir).ok();
+    let _lock = lock_index(index_dir)?;
+    let conn = open_embeddings_db(index_dir)?;
+    let stored_dims = index_dims(&conn);
+    let requested_dims = embed_dims
+        .or(if incremental { stored_dims } else { None })
+        .or(load_config().embedding.dims);
+    if requested_dims == Some(0) {
+        anyhow::bail!("Embedding dimensions must be at least 1");
+    }
+    let provider = embedding_provider(&embed_backend, embed_model, requested_dims)?;
+    let dims = provider_dims(provider.as_ref())?;
+    if let Some(requested) = requested_dims
+        && requested != dims
+    {
+        anyhow::bail!("{} produces {dims}-dimensional embeddings, but the index is configured for {requested}", value_name(&embed_backend));
+    }
+    if incremental
+        && let Some(stored) = stored_dims
+        && stored != dims
+    {
+        anyhow::bail!("Index stores {stored}-dimensional embeddings; rerun without --incremental to switch to {dims}");
+    }
+    let stored = stored_vector_encoding(&conn)?;
+    let encoding = vector_encoding.unwrap_or(stored);
+    if incremental && encoding != stored {
+        anyhow::bail!(
+            "Index stores {} vectors; rerun without --incremental to switch to {}",
+            value_name(&stored),
+            value_name(&encoding)
+        );
+    }
+
+    if matches!(vector_backend, VectorBackend::Vss) {
+        if !incremental {
+            // Recreated so its dimensions follow the current model.
+            load_vss_extensions(&conn)?;
+            conn.execute_batch("DROP TABLE IF EXISTS vss_chunks;")?;
+        }
+        ensure_vss(&conn, dims)?;
+    }
+    if matches!(vector_backend, VectorBackend::Vec) {
+        if !incremental {
+            // Recreated so its dimensions follow the current model.
+            load_vec_extension(&conn)?;
+            conn.execute_batch("DROP TABLE IF EXISTS vec_chunks;")?;
+        }
+        ensure_vec(&conn, dims)?;
+    }
+    // ANN tables from runs with another backend still mirror `chunks`: keep their
+    // rows in step on incremental runs and drop them on full ones.
+    if !matches!(vector_backend, VectorBackend::Vss) && has_table(&conn, "vss_chunks") {
+        load_vss_extensions(&conn).context("embeddings.db has a vss_chunks table; its extension is needed to update it")?;
+        if !incremental {
+            conn.execute_batch("DROP TABLE vss_chunks;")?;
+        }
+    }
+    if !matches!(vector_backend, VectorBackend::Vec) && has_table(&conn, "vec_chunks") {
+        load_vec_extension(&conn).context("embeddings.db has a vec_chunks table; its extension is needed to update it")?;
+        if !incremental {
+            conn.execute_batch("DROP TABLE vec_chunks;")?;
+        }
+    }
+    let has_vss = has_table(&conn, "vss_chunks");
+    let has_vec = has_table(&conn, "vec_chunks");
+
+    if !incremental {
+        conn.execute("DELETE FROM chunks", [])?;
+        conn.execute("DELETE FROM notes", [])?;
+        set_meta(&conn, "vector_encoding", &value_name(&encoding))?;
+    }
+
+    let (scan_root, collection_name) = resolve_scan_root(vault, collection)?;
+    let mut inserted = 0;
+    let mut skipped = 0;
+    let mut updated = 0;
+
+    let tx = conn.unchecked_transaction()?;
+    // Notes of this collection already embedded; whatever the scan doesn't see was deleted.
+    let mut embedded_paths: std::collections::HashSet<String> = std::collections::HashSet::new();
+    if incremental {
+        let mut stmt = conn.prepare("SELECT path FROM notes WHERE collection = ?1 UNION SELECT path FROM chunks WHERE collection = ?1")?;
+        let paths = stmt.query_map(params![collection_name], |row| row.get::<_, String>(0))?;
+        embedded_paths.extend(paths.filter_map(|r| r.ok()));
+    }
+    scan_vault(&scan_root, &collection_name, scan_opts, |doc| {
+        // Check note mtime
+        let mut stmt = conn.prepare("SELECT mtime FROM notes WHERE path = ?1")?;
+        let existing_mtime: Option<i64> = stmt
+            .query_row(params![doc.path], |row| row.get(0))
+            .ok();
+
+        if incremental {
+            embedded_paths.remove(&doc.path);
+            if let Some(old) = existing_mtime
+                && old >= doc.mtime
+            {
+                skipped += 1;
+                return Ok(());
+            }
+            delete_path_chunks(&conn, &doc.path, has_vss, has_vec)?;
+            updated += 1;
+        }
+
+        let chunks = chunk_text(&doc.content, max_chars, overlap);
+        let texts: Vec<&str> = chunks.iter().map(String::as_str).collect();
+        let embeddings = provider.embed_batch(&texts)?;
+        for (ch, emb) in chunks.iter().zip(&embeddings) {
+            let hash = hash_str(ch);
+            insert_chunk(&conn, &doc.path, &doc.collection, ch, &hash, doc.mtime, emb, dims, &vector_backend, encoding)
+                .with_context(|| format!("{} embedding rejected", value_name(&embed_backend)))?;
+            inserted += 1;
+        }
+
+        conn.execute(
+            "INSERT INTO notes (path, collection, mtime) VALUES (?1, ?2, ?3)\
+             ON CONFLICT(path) DO UPDATE SET mtime=excluded.mtime, collection=excluded.collection",
+            params![doc.path, doc.collection, doc.mtime],
+        )?;
+        Ok(())
+    })?;
+    // Left over after an incremental scan: notes removed from the vault. The HNSW
+    // graph drops their nodes when it syncs below.
+    for path in &embedded_paths {
+        delete_path_chunks(&conn, path, has_vss, has_vec)?;
+        conn.execute("DELETE FROM notes WHERE path = ?1", params![path])?;
+    }
+    set_meta(&conn, "embed_backend", &value_name(&embed_backend))?;
+    set_meta(&conn, "embed_model", embed_model.unwrap_or(""))?;
+    set_meta(&conn, "vector_backend", &value_name(&vector_backend))?;
+    set_meta(&conn, "dims", &dims.to_string())?;
+    set_meta(&conn, "max_chars", &max_chars.to_string())?;
+    set_meta(&conn, "overlap", &overlap.to_string())?;
+    set_meta(&conn, "indexed_at", &Utc::now().timestamp().to_string())?;
+    tx.commit()?;
+    let build_hnsw = matches!(vector_backend, VectorBackend::Hnsw);
+    let hnsw_sync = update_hnsw(index_dir, &conn, hnsw, build_hnsw, build_hnsw && !incremental)?;
+
+    let out = json_response(json!({
+        "message": "embeddings indexed",
+        "vault": vault,
+        "index": index_dir,
+        "embed_backend": value_name(&embed_backend),
+        "dims": dims,
+        "vector_encoding": value_name(&encoding),
+        "chunks": inserted,
+        "skipped": skipped,
+        "updated": updated,
+        "deleted": embedded_paths.len(),
+        "hnsw": hnsw_sync
+    }));
+    println!("{out}");
+    Ok(())
+}
+
+/// Removes a note's chunks and their rows in the ANN tables that mirror `chunks`.
+fn delete_path_chunks(conn: &Connection, path: &str, has_vss: bool, has_vec: bool) -> Result<()> {
+    if has_vss {
+        conn.execute("DELETE FROM vss_chunks WHERE rowid IN (SELECT id FROM chunks WHERE path = ?1)", params![path])?;
+    }
+    if has_vec {
+        conn.execute("DELETE FROM vec_chunks WHERE rowid IN (SELECT id FROM chunks WHERE path = ?1)", params![path])?;
+    }
+    conn.execute("DELETE FROM chunks WHERE path = ?1", params![path])?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn insert_chunk(conn: &Connection, path: &str, collection: &str, chunk: &str, hash: &str, mtime: i64, emb: &[f32], dims: usize, vector_backend: &VectorBackend, encoding: VectorEncoding) -> Result<()> {
+    if emb.len() != dims {
+        anyhow::bail!("Embedding for {path} has {} dimensions, index expects {dims}", emb.len());
+    }
+    conn.execute(
+        "INSERT INTO chunks (path, collection, chunk, chunk_hash, mtime, embedding) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![path, collection, chunk, hash, mtime, encoding.encode(emb)],
+    )?;
+    let rowid = conn.last_insert_rowid();
+    if matches!(vector_backend, VectorBackend::Vss) {
+        // vss0 takes raw little-endian f32 BLOBs whatever the chunks table stores.
+        conn.execute(
+            "INSERT INTO vss_chunks (rowid, embedding) VALUES (?1, ?2)",
+            params![rowid, VectorEncoding::F32.encode(emb)],
+        )?;
+    }
+    if matches!(vector_backend, VectorBackend::Vec) {
+        conn.execute(
+            "INSERT INTO vec_chunks (rowid, embedding, collection, path) VALUES (?1, ?2, ?3, ?4)",
+            params![rowid, VectorEncoding::F32.encode(emb), collection, path],
+        )?;
+    }
+    Ok(())
+}
+
+fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value=excluded.value",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+fn get_meta(conn: &Connection, key: &str) -> Option<String> {
+    conn.query_row("SELECT value FROM meta WHERE key = ?1", params![key], |row| row.get(0))
+        .ok()
+}
+
+fn has_table(conn: &Connection, name: &str) -> bool {
+    conn.query_row("SELECT 1 FROM sqlite_master WHERE name = ?1", params![name], |_| Ok(()))
+        .is_ok()
+}
+
+/// Embedding size recorded by the last `embed-index` run.
+fn index_dims(conn: &Connection) -> Option<usize> {
+    get_meta(conn, "dims").and_then(|d| d.parse().ok())
+}
+
+fn value_name<T: clap::ValueEnum>(v: &T) -> String {
+    v.to_possible_value().map(|p| p.get_name().to_string()).unwrap_or_default()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn embed_search(index_dir: &str, query: &str, limit: usize, json_out: bool, collection: Option<String>, scope: &FolderScope, min_score: f32, files: bool, all: bool, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend, hnsw_ef: Option<usize>, page: &PageOptions, explain: bool) -> Result<()> {
+    let metric = match vector_backend {
+        VectorBackend::Bruteforce => "cosine",
+        VectorBackend::Vss => "1 - vss distance",
+        VectorBackend::Hnsw => "cosine (hnsw)",
+        VectorBackend::Vec => "1 - vec0 cosine distance",
+    };
+    let backend_names = (value_name(&embed_backend), value_name(&vector_backend));
+    // Score every candidate so total_hits reflects the whole match set, then page.
+    let results = embed_search_results(index_dir, query, MAX_PAGE_RESULTS, collection.clone(), scope, embed_backend, embed_model, vector_backend, hnsw_ef)?;
+    // Similarity rank of each chunk, kept through re-sorting for --explain.
+    let mut results: Vec<(usize, VectorResult)> = results.into_iter().enumerate().map(|(i, r)| (i + 1, r)).collect();
+    results.retain(|(_, r)| r.score >= min_score);
+    sort_path_hits(index_dir, &mut results, page.sort, |(_, r)| (r.path.as_str(), r.score))?;
+    let total_hits = results.len();
+    let results = page_slice(results, page, page.page_size(limit, all));
+    let page_info = page.page_info(total_hits, results.len());
+    let explanation = |rank: usize, r: &VectorResult| {
+        json!({
+            "signal": "vector",
+            "metric": metric,
+            "score": r.score,
+            "chunk_rank": rank,
+            "embed_backend": backend_names.0,
+            "embed_model": embed_model,
+            "vector_backend": backend_names.1,
+        })
+    };
+
+    if json_out {
+        let mut data = if files {
+            let files_out: Vec<String> = results.iter().map(|(_, r)| r.path.clone()).collect();
+            json!({ "query": query, "files": files_out })
+        } else {
+            let mut out = Vec::with_capacity(results.len());
+            for (rank, r) in &results {
+                let mut value = serde_json::to_value(r)?;
+                if explain {
+                    value["explain"] = explanation(*rank, r);
+                }
+                out.push(value);
+            }
+            json!({ "query": query, "results": out })
+        };
+        page_info.merge_into(&mut data);
+        println!("{}", json_response(data));
+    } else {
+        for (rank, r) in results {
+            println!("{}	{:.3}	{}", r.path, r.score, r.chunk);
+            if explain {
+                println!("  {metric} {:.4}, chunk rank {rank}", r.score);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn hybrid_search(index_dir: &str, query: &str, limit: usize, rrf_k: u32, bm25_limit: usize, vec_limit: usize, json_out: bool, collection: Option<String>, scope: &FolderScope, min_score: f32, files: bool, all: bool, expand: u32, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend, hnsw_ef: Option<usize>, snippet_opts: &SnippetOptions, query_opts: &QueryOptions, facets: &[String], page: &PageOptions, explain: bool) -> Result<()> {
+    // Facets describe the lexical match set; vector neighbours have no natural cut-off.
+    let issues = {
+        let index = open_existing_index(index_dir)?;
+        parse_user_query_reporting(&index, query, query_opts)?.1
+    };
+    let facet_json = facet_counts(index_dir, query, collection.as_deref(), scope, query_opts, facets)?;
+    let mut fusion = RrfFusion::new(rrf_k);
+
+    // Original query (bonus)
+    let bm25 = bm25_search(index_dir, query, bm25_limit, collection.clone(), scope, query_opts)?;
+    let vec = embed_search_results(index_dir, query, vec_limit, collection.clone(), scope, embed_backend.clone(), embed_model, vector_backend.clone(), hnsw_ef)?;
+    fusion.add(query, "bm25", 2.0, bm25.iter().map(|r| (r.path.clone(), r.score)));
+    fusion.add(query, "vector", 2.0, vec.iter().map(|r| (r.path.clone(), r.score)));
+
+    // Expanded queries
+    for qx in expand_query(query, expand) {
+        let bm25x = bm25_search(index_dir, &qx, bm25_limit, collection.clone(), scope, query_opts)?;
+        let vecx = embed_search_results(index_dir, &qx, vec_limit, collection.clone(), scope, embed_backend.clone(), embed_model, vector_backend.clone(), hnsw_ef)?;
+        fusion.add(&qx, "bm25", 1.0, bm25x.into_iter().map(|r| (r.path, r.score)));
+        fusion.add(&qx, "vector", 1.0, vecx.into_iter().map(|r| (r.path, r.score)));
+    }
+    let contributions = &fusion.contributions;
+
+    let mut fused = fusion.scores();
+    fused.retain(|(_, score)| *score >= min_score);
+    sort_path_hits(index_dir, &mut fused, page.sort, |(path, score)| (path.as_str(), *score))?;
+    let total_hits = fused.len();
+    let fused = page_slice(fused, page, page.page_size(limit, all));
+    let page_info = page.page_info(total_hits, fused.len());
+
+    if json_out {
+        let mut data = if files {
+            let files_out: Vec<String> = fused.iter().map(|(p, _)| p.clone()).collect();
+            json!({ "query": query, "files": files_out })
+        } else {
+            let paths: Vec<String> = fused.iter().map(|(p, _)| p.clone()).collect();
+            let mut snippets = path_snippets(index_dir, query, &paths, snippet_opts, query_opts)?;
+            let results: Vec<HybridResult> = fused
+                .iter()
+                .map(|(path, score)| {
+                    let (mut snippet, heading) = snippets.remove(path).unwrap_or((None, None));
+                    // Vector-only hits share no terms with the query; show their best chunk instead.
+                    if snippet.is_none() && snippet_opts.len > 0 {
+                        snippet = vec.iter().find(|v| &v.path == path).map(|v| truncate_chars(&v.chunk, snippet_opts.len));
+                    }
+                    let explain = explain.then(|| hybrid_explain(&contributions[path], query, rrf_k, *score));
+                    HybridResult { path: path.clone(), score: *score, snippet, heading, explain }
+                })
+                .collect();
+            json!({ "query": query, "results": results })
+        };
+        page_info.merge_into(&mut data);
+        if let Some(f) = facet_json {
+            data["facets"] = f;
+        }
+        if !issues.is_empty() {
+            data["query_issues"] = json!(issues);
+        }
+        println!("{}", json_response(data));
+    } else {
+        for (path, score) in fused {
+            println!("{}	{:.4}", path, score);
+            if explain {
+                for c in &contributions[&path] {
+                    println!("  {} #{} ({:.4}) for {:?}: {:.0}/({rrf_k}+{}) = {:.4}", c.signal, c.rank, c.score, c.query, c.weight, c.rank, c.rrf);
+                }
+            }
+        }
+        print_query_issues(&issues);
+        if let Some(f) = facet_json {
+            print_facets(&f);
+        }
+    }
+
+    Ok(())
+}
+
+/// Reciprocal rank fusion: each ranked list adds `weight / (rrf_k + rank)` per hit.
+struct RrfFusion {
+    rrf_k: u32,
+    contributions: HashMap<String, Vec<RrfContribution>>,
+}
+
+impl RrfFusion {
+    fn new(rrf_k: u32) -> RrfFusion {
+        RrfFusion { rrf_k, contributions: HashMap::new() }
+    }
+
+    fn add(&mut self, query: &str, signal: &'static str, weight: f32, hits: impl IntoIterator<Item = (String, f32)>) {
+        for (rank, (path, score)) in hits.into_iter().enumerate() {
+            let r = (self.rrf_k + (rank as u32) + 1) as f32;
+            let contribution = RrfContribution { query: query.to_string(), signal, rank: rank + 1, score, weight, rrf: weight * (1.0 / r) };
+            self.contributions.entry(path).or_default().push(contribution);
+        }
+    }
+
+    fn scores(&self) -> Vec<(String, f32)> {
+        self.contributions.iter().map(|(path, c)| (path.clone(), c.iter().map(|c| c.rrf).sum())).collect()
+    }
+}
+
+fn hybrid_explain(contributions: &[RrfContribution], query: &str, rrf_k: u32, score: f32) -> HybridExplain {
+    let best_rank = |signal: &str| contributions.iter().filter(|c| c.signal == signal && c.query == query).map(|c| c.rank).min();
+    HybridExplain {
+        bm25_rank: best_rank("bm25"),
+        vector_rank: best_rank("vector"),
+        rrf_k,
+        contributions: contributions.to_vec(),
+        original_query_bonus: contributions.iter().filter(|c| c.query == query).map(|c| c.rrf / 2.0).sum(),
+        score,
+    }
+}
+
+/// Prints a tantivy explanation as an indented tree.
+fn print_explanation(explanation: &serde_json::Value, depth: usize) {
+    let value = explanation["value"].as_f64().unwrap_or(0.0);
+    let description = explanation["description"].as_str().unwrap_or("");
+    println!("{}{value:.4} {description}", "  ".repeat(depth));
+    for child in explanation["details"].as_array().into_iter().flatten() {
+        print_explanation(child, depth + 1);
+    }
+}
+
+fn snippet_generator(searcher: &tantivy::Searcher, query: &dyn tantivy::query::Query, content_field: Field, opts: &SnippetOptions) -> Result<Option<tantivy::snippet::SnippetGenerator>> {
+    if opts.len == 0 {
+        return Ok(None);
+    }
+    let mut generator = tantivy::snippet::SnippetGenerator::create(searcher, query, content_field)?;
+    generator.set_max_num_chars(opts.len);
+    Ok(Some(generator))
+}
+
+/// Highlighted fragment of a note's content plus the heading it falls under.
+type NoteSnippet = (Option<String>, Option<String>);
+
+fn note_snippet(generator: &tantivy::snippet::SnippetGenerator, doc: &TantivyDocument, content_field: Field, opts: &SnippetOptions) -> NoteSnippet {
+    let snippet = generator.snippet_from_doc(doc);
+    if snippet.is_empty() {
+        return (None, None);
+    }
+    let fragment = snippet.fragment();
+    let mut highlighted = String::with_capacity(fragment.len() + 16);
+    let mut pos = 0;
+    // Identifier parts overlap the whole identifier's range; highlight the union.
+    let mut ranges: Vec<std::ops::Range<usize>> = Vec::new();
+    for range in snippet.highlighted() {
+        match ranges.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => ranges.push(range.clone()),
+        }
+    }
+    for range in &ranges {
+        highlighted.push_str(&fragment[pos..range.start]);
+        highlighted.push_str(&opts.pre);
+        highlighted.push_str(&fragment[range.clone()]);
+        highlighted.push_str(&opts.post);
+        pos = range.end;
+    }
+    highlighted.push_str(&fragment[pos..]);
+
+    let content = doc.get_first(content_field).and_then(|v| v.as_str()).unwrap_or("");
+    let first_match = snippet.highlighted().first().map(|r| r.start).unwrap_or(0);
+    let heading = content.find(fragment).and_then(|offset| heading_at(content, offset + first_match));
+    (Some(highlighted.trim().to_string()), heading)
+}
+
+/// The nearest heading that starts at or before `offset` in a markdown body.
+fn heading_at(body: &str, offset: usize) -> Option<String> {
+    let mut current: Option<String> = None;
+    let mut in_heading = false;
+    let mut text = String::new();
+    for (event, range) in MdParser::new(body).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { .. }) if range.start > offset => break,
+            Event::Start(Tag::Heading { .. }) => {
+                in_heading = true;
+                text.clear();
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                in_heading = false;
+                if !text.trim().is_empty() {
+                    current = Some(text.trim().to_string());
+                }
+            }
+            Event::Text(t) | Event::Code(t) if in_heading => text.push_str(&t),
+            _ => {}
+        }
+    }
+    current
+}
+
+/// Snippets for specific notes, looked up by path.
+fn path_snippets(index_dir: &str, query: &str, paths: &[String], opts: &SnippetOptions, query_opts: &QueryOptions) -> Result<HashMap<String, NoteSnippet>> {
+    let mut out = HashMap::new();
+    if opts.len == 0 || paths.is_empty() {
+        return Ok(out);
+    }
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let fields = schema_fields(&index)?;
+    let Ok(q) = parse_user_query(&index, query, query_opts) else {
+        return Ok(out);
+    };
+    let Some(generator) = snippet_generator(&searcher, &*q, fields.content, opts)? else {
+        return Ok(out);
+    };
+    for path in paths {
+        let term = Term::from_field_text(fields.path, path);
+        let hit = searcher
+            .search(&tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic), &TopDocs::with_limit(1))?
+            .into_iter()
+            .next();
+        if let Some((_, addr)) = hit {
+            let doc: TantivyDocument = searcher.doc(addr)?;
+            out.insert(path.clone(), note_snippet(&generator, &doc, fields.content, opts));
+        }
+    }
+    Ok(out)
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", text[..idx].trim_end()),
+        None => text.to_string(),
+    }
+}
+
+fn expand_query(query: &str, n: u32) -> Vec<String> {
+    if n == 0 { return vec![]; }
+    let mut variants = Vec::new();
+    let stop = ["the","a","an","and","or","to","of","in","on","for","with","is","are","be","by"];
+    let tokens: Vec<&str> = query.split_whitespace().collect();
+    let filtered: Vec<&str> = tokens.iter().copied().filter(|t| !stop.contains(&t.to_lowercase().as_str())).collect();
+    if !filtered.is_empty() {
+        variants.push(filtered.join(" "));
+    }
+    variants.push(format!("\"{}\"", query));
+    variants.retain(|v| v.trim() != query);
+    variants.truncate(n as usize);
+    variants
+}
+
+fn bm25_search(index_dir: &str, query: &str, limit: usize, collection: Option<String>, scope: &FolderScope, query_opts: &QueryOptions) -> Result<Vec<SearchResult>> {
+    let index = open_existing_index(index_dir)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+
+    let schema = index.schema();
+    let path_field = schema.get_field("path").unwrap();
+    let title_field = schema.get_field("title").unwrap();
+    let docid_field = schema.get_field("doc_id").unwrap();
+    let collection_field = schema.get_field("collection").unwrap();
+
+    let q = scope.restrict(parse_user_query(&index, query, query_opts)?, schema.get_field("facets").unwrap());
+    let top_docs = if let Some(name) = collection {
+        let term = Term::from_field_text(collection_field, &name);
+        let filter = tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
+        let combined = tantivy::query::BooleanQuery::intersection(vec![Box::new(q), Box::new(filter)]);
+        searcher.search(&combined, &TopDocs::with_limit(limit))?
+    } else {
+        searcher.search(&q, &TopDocs::with_limit(limit))?
+    };
+
+    let mut results = Vec::new();
+    for (score, doc_address) in top_docs {
+        let retrieved: TantivyDocument = searcher.doc(doc_address)?;
+        let path = retrieved
+            .get_first(path_field)
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let title = retrieved
+            .get_first(title_field)
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let doc_id = retrieved
+            .get_first(docid_field)
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        results.push(SearchResult { path, title, score, doc_id, snippet: None, heading: None, explain: None });
+    }
+    Ok(results)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn embed_search_results(index_dir: &str, query: &str, limit: usize, collection: Option<String>, scope: &FolderScope, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend, hnsw_ef: Option<usize>) -> Result<Vec<VectorResult>> {
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    let conn = open_sqlite(&db_path)?;
+    let dims = index_dims(&conn);
+    if let Some(indexed) = get_meta(&conn, "embed_backend")
+        && indexed != value_name(&embed_backend)
+    {
+        anyhow::bail!("Index was embedded with the {indexed} backend; search with --embed-backend {indexed} (got {})", value_name(&embed_backend));
+    }
+    let qemb = embedding_provider(&embed_backend, embed_model, dims)?.embed(query)?;
+    if let Some(dims) = dims
+        && qemb.len() != dims
+    {
+        anyhow::bail!(
+            "{} produced a {}-dimensional query embedding, but the index stores {dims}; search with the embed-index backend and model",
+            value_name(&embed_backend),
+            qemb.len()
+        );
+    }
+
+    if matches!(vector_backend, VectorBackend::Vss) {
+        ensure_vss(&conn, qemb.len())?;
+        let qblob = VectorEncoding::F32.encode(&qemb);
+        // Folder filtering happens after the k-NN lookup, so widen it first.
+        let final_limit = limit;
+        let limit = if scope.is_empty() { limit } else { MAX_PAGE_RESULTS };
+        let mut results: Vec<VectorResult> = Vec::new();
+        if let Some(name) = collection.as_ref() {
+            let mut stmt = conn.prepare(
+                "SELECT c.path, c.chunk, v.distance FROM vss_chunks v JOIN chunks c ON c.id = v.rowid WHERE vss_search(v.embedding, ?) AND c.collection = ? ORDER BY v.distance LIMIT ?",
+            )?;
+            let rows = stmt.query_map(params![qblob, name, limit as i64], |row| {
+                let path: String = row.get(0)?;
+                let chunk: String = row.get(1)?;
+                let distance: f32 = row.get(2)?;
+                let score = 1.0 - distance;
+                Ok(VectorResult { path, score, chunk })
+            })?;
+            for v in rows.flatten() { results.push(v); }
+        } else {
+            let mut stmt = conn.prepare(
+                "SELECT c.path, c.chunk, v.distance FROM vss_chunks v JOIN chunks c ON c.id = v.rowid WHERE vss_search(v.embedding, ?) ORDER BY v.distance LIMIT ?",
+            )?;
+            let rows = stmt.query_map(params![qblob, limit as i64], |row| {
+                let path: String = row.get(0)?;
+                let chunk: String = row.get(1)?;
+                let distance: f32 = row.get(2)?;
+                let score = 1.0 - distance;
+                Ok(VectorResult { path, score, chunk })
+            })?;
+            for v in rows.flatten() { results.push(v); }
+        }
+        results.retain(|r| scope.matches(&r.path));
+        results.truncate(final_limit);
+        return Ok(results);
+    }
+
+    if matches!(vector_backend, VectorBackend::Vec) {
+        ensure_vec(&conn, qemb.len())?;
+        // Collection, and a single --folder as a path range, are vec0 metadata
+        // constraints evaluated inside the KNN scan; other folder scopes are
+        // filtered afterwards from a widened k (vec0 allows at most 4096).
+        let range = scope.single_folder_range();
+        let k = if scope.is_empty() || range.is_some() { limit } else { MAX_PAGE_RESULTS }.min(VEC_MAX_K);
+        let mut filters = String::new();
+        if collection.is_some() {
+            filters.push_str(" AND collection = :collection");
+        }
+        if range.is_some() {
+            filters.push_str(" AND path >= :path_start AND path < :path_end");
+        }
+        let mut stmt = conn.prepare(&format!(
+            "WITH knn AS (SELECT rowid, distance FROM vec_chunks WHERE embedding MATCH :query AND k = :k{filters}) \
+             SELECT c.path, c.chunk, knn.distance FROM knn JOIN chunks c ON c.id = knn.rowid ORDER BY knn.distance"
+        ))?;
+        let qblob = VectorEncoding::F32.encode(&qemb);
+        let mut args: Vec<(&str, &dyn rusqlite::ToSql)> = vec![(":query", &qblob), (":k", &k)];
+        if let Some(name) = collection.as_ref() {
+            args.push((":collection", name));
+        }
+        if let Some((start, end)) = range.as_ref() {
+            args.push((":path_start", start));
+            args.push((":path_end", end));
+        }
+        let rows = stmt.query_map(args.as_slice(), |row| {
+            let distance: f32 = row.get(2)?;
+            Ok(VectorResult { path: row.get(0)?, score: 1.0 - distance, chunk: row.get(1)? })
+        })?;
+        let mut results: Vec<VectorResult> = rows.collect::<rusqlite::Result<_>>()?;
+        results.retain(|r| scope.matches(&r.path));
+        results.truncate(limit);
+        return Ok(results);
+    }
+
+    if matches!(vector_backend, VectorBackend::Hnsw) {
+        let graph = hnsw_graph(index_dir)?;
+        // Collection and folder filters are applied inside the graph walk.
+        let allowed: Option<std::collections::HashSet<i64>> = if collection.is_some() || !scope.is_empty() {
+            let mut stmt = conn.prepare("SELECT id, path FROM chunks WHERE ?1 IS NULL OR collection = ?1")?;
+            let rows = stmt.query_map(params![collection], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+            Some(rows.filter_map(|r| r.ok()).filter(|(_, path)| scope.matches(path)).map(|(id, _)| id).collect())
+        } else {
+            None
+        };
+        // `search` widens the walk to `limit` candidates when that exceeds `ef`, so
+        // --all, --offset and total_hits are not cut off at the search breadth.
+        let ef = hnsw_ef.unwrap_or(graph.opts.ef).max(1);
+        let hits = graph.search(&qemb, limit, ef, &|id| allowed.as_ref().is_none_or(|a| a.contains(&id)));
+        let mut stmt = conn.prepare("SELECT path, chunk FROM chunks WHERE id = ?1")?;
+        let mut results: Vec<VectorResult> = Vec::new();
+        for (id, score) in hits {
+            if let Ok((path, chunk)) = stmt.query_row(params![id], |row| Ok((row.get(0)?, row.get(1)?))) {
+                results.push(VectorResult { path, score, chunk });
+            }
+        }
+        return Ok(results);
+    }
+
+    let encoding = stored_vector_encoding(&conn)?;
+    let mut stmt = if collection.is_some() {
+        conn.prepare("SELECT path, chunk, embedding FROM chunks WHERE collection = ?1")?
+    } else {
+        conn.prepare("SELECT path, chunk, embedding FROM chunks")?
+    };
+    let rows_vec: Vec<(String, String, Vec<f32>)> = if let Some(name) = collection.as_ref() {
+        stmt.query_map(params![name], |row| {
+            let path: String = row.get(0)?;
+            let chunk: String = row.get(1)?;
+            let blob: Vec<u8> = row.get(2)?;
+            Ok((path, chunk, encoding.decode(&blob)))
+        })?.filter_map(|r| r.ok()).collect()
+    } else {
+        stmt.query_map([], |row| {
+            let path: String = row.get(0)?;
+            let chunk: String = row.get(1)?;
+            let blob: Vec<u8> = row.get(2)?;
+            Ok((path, chunk, encoding.decode(&blob)))
+        })?.filter_map(|r| r.ok()).collect()
+    };
+
+    let mut results: Vec<VectorResult> = Vec::new();
+    for (path, chunk, emb) in rows_vec {
+        if !scope.matches(&path) {
+            continue;
+        }
+        let score = cosine_sim(&qemb, &emb);
+        results.push(VectorResult { path, score, chunk });
+    }
+
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    results.truncate(limit);
+    Ok(results)
+}
+
+const DEFAULT_MAX_CHARS: usize = 1500;
+const DEFAULT_OVERLAP: usize = 200;
+
+fn chunk_text(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
+    if text.len() <= max_chars {
+        return vec![text.to_string()];
+    }
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let end = usize::min(start + max_chars, text.len());
+        let chunk = text[start..end].to_string();
+        chunks.push(chunk);
+        if end == text.len() { break; }
+        start = end.saturating_sub(overlap);
+    }
+    chunks
+}
+
+/// Turns text into vectors for one embedding backend.
+trait EmbeddingProvider {
+    /// Vector size, when known without embedding anything.
+    fn dims(&self) -> Option<usize>;
+
+    /// One vector per text, in order.
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        self.embed_batch(&[text])?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("embedding backend returned no vector"))
+    }
+}
+
+/// `dims` sizes the hash backend; model-backed providers report their own size.
+fn embedding_provider(backend: &EmbeddingBackend, model: Option<&str>, dims: Option<usize>) -> Result<Box<dyn EmbeddingProvider>> {
+    Ok(match backend {
+        EmbeddingBackend::Hash => Box::new(HashEmbedder { dims: dims.unwrap_or(DEFAULT_EMBED_DIMS) }),
+        EmbeddingBackend::Candle => {
+            let dir = model.ok_or_else(|| anyhow::anyhow!("candle backend requires --embed-model <dir> (config.json, tokenizer.json, model.safetensors)"))?;
+            Box::new(CandleEmbedder(sentence_model(dir)?))
+        }
+        EmbeddingBackend::Command => {
+            let mut config = load_config()
+                .embedding
+                .command
+                .ok_or_else(|| anyhow::anyhow!("command backend requires [embedding.command] in {}", config_path().display()))?;
+            if let Some(model) = model {
+                config.model = Some(model.to_string());
+            }
+            Box::new(CommandEmbedder { config })
+        }
+        EmbeddingBackend::Http => {
+            let mut config = load_config()
+                .embedding
+                .http
+                .ok_or_else(|| anyhow::anyhow!("http backend requires [embedding.http] in {}", config_path().display()))?;
+            if let Some(model) = model {
+                config.model = Some(model.to_string());
+            }
+            Box::new(HttpEmbedder::new(config))
+        }
+    })
+}
+
+/// The provider's vector size, asking it to embed a probe when it can't say up front.
+fn provider_dims(provider: &dyn EmbeddingProvider) -> Result<usize> {
+    match provider.dims() {
+        Some(dims) => Ok(dims),
+        None => Ok(provider.embed("obsidx")?.len()),
+    }
+}
+
+struct HashEmbedder {
+    dims: usize,
+}
+
+impl EmbeddingProvider for HashEmbedder {
+    fn dims(&self) -> Option<usize> {
+        Some(self.dims)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|t| hash_embedding(t, self.dims)).collect())
+    }
+}
+
+struct CandleEmbedder(Arc<SentenceModel>);
+
+impl EmbeddingProvider for CandleEmbedder {
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.dims)
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        texts.iter().map(|t| self.0.embed(t)).collect()
+    }
+}
+
+/// Sends `{"model": ..., "texts": [...]}` on stdin and reads `{"embeddings": [[...]]}`
+/// (or any shape `parse_embeddings` accepts) from stdout.
+struct CommandEmbedder {
+    config: CommandEmbeddingConfig,
+}
+
+impl CommandEmbedder {
+    fn run(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        use std::process::{Command, Stdio};
+
+        let program = &self.config.program;
+        let mut child = Command::new(program)
+            .args(&self.config.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("Failed to start embedding command {program}"))?;
+        let input = serde_json::to_vec(&json!({"model": self.config.model, "texts": texts}))?;
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(self.config.timeout_secs);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if std::time::Instant::now() >= deadline {
+                child.kill().ok();
+                child.wait().ok();
+                anyhow::bail!("embedding command {program} timed out after {}s", self.config.timeout_secs);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        // A command that exits without reading all of stdin is fine if it answered.
+        let _ = writer.join();
+        let output = reader.join().map_err(|_| anyhow::anyhow!("embedding command reader panicked"))??;
+        if !status.success() {
+            anyhow::bail!("embedding command {program} failed with {status}");
+        }
+        let value: serde_json::Value = serde_json::from_slice(&output)
+            .with_context(|| format!("embedding command {program} did not print JSON"))?;
+        parse_embeddings(&value, texts.len())
+    }
+}
+
+impl EmbeddingProvider for CommandEmbedder {
+    fn dims(&self) -> Option<usize> {
+        None
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut out = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.config.batch_size.max(1)) {
+            out.extend(with_retries(self.config.retries, || self.run(batch))?);
+        }
+        Ok(out)
+    }
+}
+
+/// POSTs `{"model": ..., "input": [...]}`, which both OpenAI-style `/v1/embeddings`
+/// and Ollama's `/api/embed` accept.
+struct HttpEmbedder {
+    config: HttpEmbeddingConfig,
+    agent: ureq::Agent,
+}
+
+impl HttpEmbedder {
+    fn new(config: HttpEmbeddingConfig) -> HttpEmbedder {
+        let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(config.timeout_secs)).build();
+        HttpEmbedder { config, agent }
+    }
+
+    fn post(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let url = &self.config.url;
+        let mut request = self.agent.post(url);
+        if let Some(var) = &self.config.api_key_env {
+            let key = env::var(var).with_context(|| format!("{var} is not set (embedding.http.api_key_env)"))?;
+            request = request.set("Authorization", &format!("Bearer {key}"));
+        }
+        let body = json!({"model": self.config.model, "input": texts});
+        let value: serde_json::Value = match request.send_json(body) {
+            Ok(response) => response.into_json().with_context(|| format!("{url} did not return JSON"))?,
+            Err(ureq::Error::Status(code, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                let message = format!("{url} returned HTTP {code}: {}", truncate_chars(detail.trim(), 200));
+                // Only overload and server errors are worth another attempt.
+                return Err(if code == 429 || code >= 500 { anyhow::anyhow!(message) } else { NoRetry(message).into() });
+            }
+            Err(e) => return Err(anyhow::anyhow!("embedding request to {url} failed: {e}")),
+        };
+        parse_embeddings(&value, texts.len())
+    }
+}
+
+impl EmbeddingProvider for HttpEmbedder {
+    fn dims(&self) -> Option<usize> {
+        None
+    }
+
+    fn embed_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut out = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.config.batch_size.max(1)) {
+            out.extend(with_retries(self.config.retries, || self.post(batch))?);
+        }
+        Ok(out)
+    }
+}
+
+/// An error that another attempt would only repeat.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct NoRetry(String);
+
+/// Runs `attempt` up to `retries + 1` times, backing off 250ms, 500ms, 1s, ...
+fn with_retries<T>(retries: u32, mut attempt: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut tries = 0;
+    loop {
+        match attempt() {
+            Ok(v) => return Ok(v),
+            Err(e) if tries < retries && e.downcast_ref::<NoRetry>().is_none() => {
+                std::thread::sleep(Duration::from_millis(250 << tries.min(6)));
+                tries += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Accepts `{"embeddings": [[...]]}` (Ollama), `{"data": [{"embedding": [...], "index": n}]}`
+/// (OpenAI) or a bare `[[...]]`, and checks there is one equally sized vector per input.
+fn parse_embeddings(value: &serde_json::Value, expected: usize) -> Result<Vec<Vec<f32>>> {
+    let to_vec = |v: &serde_json::Value| -> Option<Vec<f32>> {
+        v.as_array()?.iter().map(|x| x.as_f64().map(|f| f as f32)).collect()
+    };
+    let vectors: Option<Vec<Vec<f32>>> = if let Some(data) = value.get("data").and_then(|d| d.as_array()) {
+        let mut items: Vec<(u64, &serde_json::Value)> = data
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (item.get("index").and_then(|n| n.as_u64()).unwrap_or(i as u64), item))
+            .collect();
+        items.sort_by_key(|(index, _)| *index);
+        items.iter().map(|(_, item)| item.get("embedding").and_then(to_vec)).collect()
+    } else {
+        value
+            .get("embeddings")
+            .unwrap_or(value)
+            .as_array()
+            .and_then(|rows| rows.iter().map(to_vec).collect())
+    };
+    let vectors = vectors.ok_or_else(|| anyhow::anyhow!("unrecognized embedding response (expected embeddings, data[].embedding or an array of vectors)"))?;
+    if vectors.len() != expected {
+        anyhow::bail!("embedding backend returned {} vectors for {expected} texts", vectors.len());
+    }
+    if vectors.first().is_some_and(|v| v.is_empty()) || vectors.windows(2).any(|w| w[0].len() != w[1].len()) {
+        anyhow::bail!("embedding backend returned empty or mixed-size vectors");
+    }
+    Ok(vectors)
+}
+
+/// A BERT-family sentence-transformer (all-MiniLM, bge, e5, ...) loaded from a local
+/// directory holding `config.json`, `tokenizer.json` and `model.safetensors`
+/// (or `pytorch_model.bin`). Embeddings are mean-pooled and L2-normalized.
+struct SentenceModel {
+    model: candle_transformers::models::bert::BertModel,
+    tokenizer: tokenizers::Tokenizer,
+    dims: usize,
+}
+
+impl SentenceModel {
+    fn load(dir: &Path) -> Result<SentenceModel> {
+        use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+
+        let config_path = dir.join("config.json");
+        let config: Config = serde_json::from_str(
+            &fs::read_to_string(&config_path).with_context(|| format!("Failed to read {}", config_path.display()))?,
+        )
+        .with_context(|| format!("Unsupported model config {}", config_path.display()))?;
+
+        let mut tokenizer = tokenizers::Tokenizer::from_file(dir.join("tokenizer.json"))
+            .map_err(|e| anyhow::anyhow!("Failed to load tokenizer.json: {e}"))?;
+        tokenizer.with_padding(None);
+        tokenizer
+            .with_truncation(Some(tokenizers::TruncationParams {
+                max_length: config.max_position_embeddings,
+                ..Default::default()
+            }))
+            .map_err(|e| anyhow::anyhow!("Invalid truncation for tokenizer: {e}"))?;
+
+        let device = candle_core::Device::Cpu;
+        let safetensors = dir.join("model.safetensors");
+        let vb = if safetensors.exists() {
+            // SAFETY: the weights file is only read, and nothing in obsidx writes to it.
+            unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[safetensors], DTYPE, &device)? }
+        } else {
+            candle_nn::VarBuilder::from_pth(dir.join("pytorch_model.bin"), DTYPE, &device)?
+        };
+        let model = BertModel::load(vb, &config)?;
+        Ok(SentenceModel { model, tokenizer, dims: config.hidden_size })
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        use candle_core::{DType, Tensor};
+
+        let encoding = self.tokenizer.encode(text, true).map_err(|e| anyhow::anyhow!("Tokenization failed: {e}"))?;
+        let device = &self.model.device;
+        let ids = Tensor::new(encoding.get_ids(), device)?.unsqueeze(0)?;
+        let type_ids = Tensor::new(encoding.get_type_ids(), device)?.unsqueeze(0)?;
+        let mask = Tensor::new(encoding.get_attention_mask(), device)?.unsqueeze(0)?;
+        let hidden = self.model.forward(&ids, &type_ids, Some(&mask))?;
+
+        // Mean over the attended tokens: (1, tokens, dims) -> (dims).
+        let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let pooled = summed.broadcast_div(&mask.sum(1)?)?.squeeze(0)?;
+        let mut vec = pooled.to_vec1::<f32>()?;
+        let norm = vec.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for v in &mut vec { *v /= norm; }
+        }
+        Ok(vec)
+    }
+}
+
+/// Models loaded so far, by directory; loading costs far more than embedding a chunk.
+fn sentence_model(dir: &str) -> Result<Arc<SentenceModel>> {
+    static MODELS: OnceLock<Mutex<HashMap<String, Arc<SentenceModel>>>> = OnceLock::new();
+    let mut models = MODELS.get_or_init(Default::default).lock().unwrap();
+    if let Some(model) = models.get(dir) {
+        return Ok(model.clone());
+    }
+    let model = Arc::new(SentenceModel::load(Path::new(dir)).with_context(|| format!("Failed to load embedding model from {dir}"))?);
+    models.insert(dir.to_string(), model.clone());
+    Ok(model)
+}
+
+fn hash_embedding(text: &str, dims: usize) -> Vec<f32> {
+    use std::hash::{Hash, Hasher};
+    use std::collections::hash_map::DefaultHasher;
+    let mut vec = vec![0f32; dims];
+    for (i, ch) in text.chars().enumerate() {
+        let mut h = DefaultHasher::new();
+        ch.hash(&mut h);
+        let idx = (h.finish() as usize + i) % dims;
+        vec[idx] += 1.0;
+    }
+    let norm = (vec.iter().map(|v| v*v).sum::<f32>()).sqrt();
+    if norm > 0.0 {
+        for v in &mut vec { *v /= norm; }
+    }
+    vec
+}
+
+fn hash_str(text: &str) -> String {
+    use std::hash::{Hash, Hasher};
+    use std::collections::hash_map::DefaultHasher;
+    let mut h = DefaultHasher::new();
+    text.hash(&mut h);
+    format!("{:x}", h.finish())
+}
+
+fn cosine_sim(a: &[f32], b: &[f32]) -> f32 {
+    if a.is_empty() || b.is_empty() || a.len() != b.len() { return 0.0; }
+    let mut dot = 0.0; let mut na = 0.0; let mut nb = 0.0;
+    for i in 0..a.len() {
+        dot += a[i]*b[i];
+        na += a[i]*a[i];
+        nb += b[i]*b[i];
+    }
+    if na == 0.0 || nb == 0.0 { 0.0 } else { dot / (na.sqrt()*nb.sqrt()) }
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn note_create(vault: &str, rel_path: &str, content: Option<String>, stdin: bool, reindex: bool, index_dir: &str, max_chars: Option<usize>, overlap: Option<usize>) -> Result<()> {
+    let full_path = Path::new(vault).join(rel_path);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let body = if stdin {
+        read_stdin()? 
+    } else {
+        content.unwrap_or_default()
+    };
+    fs::write(&full_path, body)?;
+
+    let queued = if reindex {
+        reindex_after_write(vault, index_dir, &full_path, max_chars, overlap)?
+    } else {
+        false
+    };
+
+    let out = json_response(json!({
+        "message": "note created",
+        "path": full_path.to_string_lossy().to_string(),
+        "reindexed": reindex && !queued,
+        "reindex_queued": queued
+    }));
+    println!("{out}");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn note_append(vault: &str, rel_path: &str, content: Option<String>, stdin: bool, reindex: bool, index_dir: &str, max_chars: Option<usize>, overlap: Option<usize>) -> Result<()> {
+    let full_path = Path::new(vault).join(rel_path);
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let body = if stdin { read_stdin()? } else { content.unwrap_or_default() };
+    let mut existing = String::new();
+    if full_path.exists() {
+        existing = fs::read_to_string(&full_path)?;
+    }
+    let mut merged = existing;
+    if !merged.is_empty() && !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+    merged.push_str(&body);
+    fs::write(&full_path, merged)?;
+
+    let queued = if reindex {
+        reindex_after_write(vault, index_dir, &full_path, max_chars, overlap)?
+    } else {
+        false
+    };
+
+    let out = json_response(json!({
+        "message": "note appended",
+        "path": full_path.to_string_lossy().to_string(),
+        "reindexed": reindex && !queued,
+        "reindex_queued": queued
+    }));
+    println!("{out}");
+    Ok(())
+}
+
+fn read_stdin() -> Result<String> {
+    let mut buf = String::new();
+    std::io::stdin().read_to_string(&mut buf)?;
+    Ok(buf)
+}
+
+
+fn multi_get(index_dir: &str, paths: Option<String>, glob_pat: Option<String>, json_out: bool, collection: Option<String>, scope: &FolderScope) -> Result<()> {
+    let mut targets: Vec<String> = Vec::new();
+    if let Some(p) = paths {
+        for part in p.split(',') {
+            let trimmed = part.trim();
+            if !trimmed.is_empty() { targets.push(trimmed.to_string()); }
+        }
+    }
+    if let Some(g) = glob_pat {
+        for path in glob(&g)?.flatten() {
+            targets.push(path.to_string_lossy().to_string());
+        }
+    }
+    if targets.is_empty() {
+        anyhow::bail!("No paths provided");
+    }
+
+    let mut results = Vec::new();
+    for t in targets {
+        // reuse get_note by calling searcher directly
+        let index = open_existing_index(index_dir)?;
+        let reader = index.reader()?;
+        let searcher = reader.searcher();
+        let schema = index.schema();
+        let lookup = resolve_doc_id(&t);
+        let term = if lookup.is_doc_id {
+            Term::from_field_text(schema.get_field("doc_id").unwrap(), &lookup.value)
+        } else {
+            Term::from_field_text(schema.get_field("path").unwrap(), &lookup.value)
+        };
+        let doc_opt: Option<TantivyDocument> = searcher
+            .search(&tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic), &TopDocs::with_limit(1))?
+            .into_iter()
+            .next()
+            .map(|(_, addr)| searcher.doc(addr))
+            .transpose()?;
+        if let Some(doc) = doc_opt {
+            if let Some(name) = collection.as_ref() {
+                let coll = doc
+                    .get_first(schema.get_field("collection").unwrap())
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("");
+                if coll != name { continue; }
+            }
+            let path = doc.get_first(schema.get_field("path").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            if !scope.matches(&path) { continue; }
+            let title = doc.get_first(schema.get_field("title").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            let doc_id = doc.get_first(schema.get_field("doc_id").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            results.push(json!({"path": path, "title": title, "doc_id": doc_id}));
+        }
     }
 
     if json_out {
-        if files {
-            let files_out: Vec<String> = results.iter().map(|r| r.path.clone()).collect();
-            let out = json_response(json!({"query": query, "files": files_out}));
-            println!("{out}");
-        } else {
-            let out = json_response(json!({
-                "query": query,
-                "results": results
-            }));
-            println!("{out}");
-        }
+        let out = json_response(json!({"results": results}));
+        println!("{out}");
     } else {
         for r in results {
-            println!("{}\t{}\t{:.2}", r.path, r.title, r.score);
+            println!("{}", r);
         }
     }
-
     Ok(())
 }
 
-fn get_note(index_dir: &str, path: &str, json_out: bool, include_content: bool, collection: Option<String>) -> Result<()> {
-    let lookup = resolve_doc_id(path);
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-    let schema = index.schema();
-    let path_field = schema.get_field("path").unwrap();
 
-    let term = if lookup.is_doc_id {
-        Term::from_field_text(schema.get_field("doc_id").unwrap(), &lookup.value)
-    } else {
-        Term::from_field_text(path_field, &lookup.value)
-    };
-    let doc_opt: Option<TantivyDocument> = searcher
-        .search(
-            &tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic),
-            &TopDocs::with_limit(1),
-        )?
-        .into_iter()
-        .next()
-        .map(|(_, addr)| searcher.doc(addr))
-        .transpose()?;
+fn mcp_server() -> Result<()> {
+    let stdin = std::io::stdin();
+    mcp_serve(stdin.lock(), &mut std::io::stdout())
+}
 
-    if let Some(doc) = doc_opt {
-        if let Some(name) = collection.as_ref() {
-            let coll = doc
-                .get_first(schema.get_field("collection").unwrap())
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-            if coll != name {
-                if json_out {
-                    let out = json_response(json!({"error": {"code": "not_found", "message": "Not in collection"}}));
-                    println!("{out}");
-                }
-                return Ok(());
+/// Answers one JSON request per line. Tools print their own responses; failures,
+/// including invalid arguments, are written to `out` as that request's error and
+/// the loop moves on to the next line.
+fn mcp_serve(reader: impl std::io::BufRead, out: &mut impl Write) -> Result<()> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        let v: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(v) => v,
+            Err(e) => {
+                let err = json_response(json!({"error": {"code": "bad_json", "message": e.to_string()}}));
+                writeln!(out, "{}", err)?;
+                continue;
             }
-        }
-        let title = doc
-            .get_first(schema.get_field("title").unwrap())
-            .and_then(|v| v.as_str())
-            .unwrap_or("");
-        let tags = doc
-            .get_first(schema.get_field("tags").unwrap())
-            .and_then(|v| v.as_str())
-            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
-            .unwrap_or_default();
-        let headings = doc
-            .get_first(schema.get_field("headings").unwrap())
-            .and_then(|v| v.as_str())
-            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
-            .unwrap_or_default();
-        let links = doc
-            .get_first(schema.get_field("links").unwrap())
-            .and_then(|v| v.as_str())
-            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
-            .unwrap_or_default();
-        let frontmatter = doc
-            .get_first(schema.get_field("frontmatter").unwrap())
-            .and_then(|v| v.as_str())
-            .and_then(|s| serde_json::from_str::<serde_json::Value>(s).ok())
-            .unwrap_or_else(|| json!({}));
-        let mtime = doc
-            .get_first(schema.get_field("mtime").unwrap())
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let content = if include_content {
-            doc.get_first(schema.get_field("content").unwrap())
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .to_string()
-        } else {
-            "".to_string()
-        };
-
-        let detail = NoteDetail {
-            path: path.to_string(),
-            title: title.to_string(),
-            content,
-            tags,
-            headings,
-            links,
-            frontmatter,
-            mtime,
         };
+        let tool = v.get("tool").and_then(|t| t.as_str()).unwrap_or("");
+        let args = v.get("args").cloned().unwrap_or(json!({}));
 
-        if json_out {
-            let out = json_response(json!({ "note": detail }));
-            println!("{out}");
-        } else {
-            println!("{}\t{}", detail.path, detail.title);
+        if let Err(e) = mcp_call(tool, &args) {
+            let err = json_response(error_json(&e));
+            writeln!(out, "{}", err)?;
+            out.flush()?;
         }
-    } else if json_out {
-        let out = json_response(json!({
-            "error": {
-                "code": "not_found",
-                "message": format!("No note found for path: {path}")
-            }
-        }));
-        println!("{out}");
     }
-
     Ok(())
 }
 
-fn list_tags(index_dir: &str, json_out: bool) -> Result<()> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-    let schema = index.schema();
-    let tags_field = schema.get_field("tags").unwrap();
-
-    let mut counts: HashMap<String, usize> = HashMap::new();
-    for segment_reader in searcher.segment_readers() {
-        let store_reader = segment_reader.get_store_reader(0)?;
-        for doc_id in 0..segment_reader.max_doc() {
-            let doc: TantivyDocument = store_reader.get(doc_id)?;
-            if let Some(val) = doc.get_first(tags_field).and_then(|v| v.as_str()) {
-                if let Ok(tags) = serde_json::from_str::<Vec<String>>(val) {
-                    for tag in tags {
-                        *counts.entry(tag).or_insert(0) += 1;
-                    }
-                }
-            }
+/// Runs one MCP tool call; argument errors surface here like any other failure.
+fn mcp_call(tool: &str, args: &serde_json::Value) -> Result<()> {
+    match tool {
+        "search" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
+            let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
+            let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
+            let explain = args.get("explain").and_then(|v| v.as_bool()).unwrap_or(false);
+            search_index(index, query, limit, true, collection, &mcp_folder_scope(index, args)?, min_score, files, all, &mcp_snippet_options(args), &mcp_query_options(args)?, &mcp_facet_names(args)?, &mcp_page_options(args)?, explain)
         }
-    }
-
-    let mut results: Vec<TagCount> = counts
-        .into_iter()
-        .map(|(tag, count)| TagCount { tag, count })
-        .collect();
-    results.sort_by(|a, b| b.count.cmp(&a.count));
-
-    if json_out {
-        let out = json_response(json!({ "results": results }));
-        println!("{out}");
-    } else {
-        for r in results {
-            println!("{}\t{}", r.tag, r.count);
+        "vector" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
+            let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
+            let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
+            let explain = args.get("explain").and_then(|v| v.as_bool()).unwrap_or(false);
+            let vector_backend = mcp_vector_backend(index, args)?;
+            let hnsw_ef = args.get("hnsw_ef").and_then(|v| v.as_u64()).map(|v| v as usize);
+            let (embed_backend, embed_model) = mcp_embedding_options(index, args)?;
+            embed_search(index, query, limit, true, collection, &mcp_folder_scope(index, args)?, min_score, files, all, embed_backend, embed_model.as_deref(), vector_backend, hnsw_ef, &mcp_page_options(args)?, explain)
+        }
+        "hybrid" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
+            let rrf_k = args.get("rrf_k").and_then(|v| v.as_u64()).unwrap_or(60) as u32;
+            let bm25_limit = args.get("bm25_limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+            let vec_limit = args.get("vec_limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
+            let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
+            let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
+            let explain = args.get("explain").and_then(|v| v.as_bool()).unwrap_or(false);
+            let expand = args.get("expand").and_then(|v| v.as_u64()).unwrap_or(2) as u32;
+            let vector_backend = mcp_vector_backend(index, args)?;
+            let hnsw_ef = args.get("hnsw_ef").and_then(|v| v.as_u64()).map(|v| v as usize);
+            let (embed_backend, embed_model) = mcp_embedding_options(index, args)?;
+            hybrid_search(index, query, limit, rrf_k, bm25_limit, vec_limit, true, collection, &mcp_folder_scope(index, args)?, min_score, files, all, expand, embed_backend, embed_model.as_deref(), vector_backend, hnsw_ef, &mcp_snippet_options(args), &mcp_query_options(args)?, &mcp_facet_names(args)?, &mcp_page_options(args)?, explain)
+        }
+        "get" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let path = args.get("path").and_then(|v| v.as_str()).unwrap_or("");
+            let content = args.get("content").and_then(|v| v.as_bool()).unwrap_or(false);
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            get_note(index, path, true, content, collection)
+        }
+        "multi_get" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let paths = args.get("paths").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let glob_pat = args.get("glob").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            multi_get(index, paths, glob_pat, true, collection, &mcp_folder_scope(index, args)?)
+        }
+        "grep" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let pattern = args.get("pattern").and_then(|v| v.as_str()).unwrap_or("");
+            let fixed_strings = args.get("fixed_strings").and_then(|v| v.as_bool()).unwrap_or(false);
+            let ignore_case = args.get("ignore_case").and_then(|v| v.as_bool()).unwrap_or(false);
+            let context = args.get("context").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+            let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(200) as usize;
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
+            grep_notes(index, pattern, fixed_strings, ignore_case, context, limit, true, collection, &mcp_folder_scope(index, args)?, files)
+        }
+        "related" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            let path = args.get("path").and_then(|v| v.as_str()).unwrap_or("");
+            let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
+            let rrf_k = args.get("rrf_k").and_then(|v| v.as_u64()).unwrap_or(60) as u32;
+            let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let exclude_linked = args.get("exclude_linked").and_then(|v| v.as_bool()).unwrap_or(false);
+            let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
+            let vector_mode = match args.get("vector_mode").and_then(|v| v.as_str()) {
+                Some("max-sim") | Some("max_sim") => RelatedVectorMode::MaxSim,
+                _ => RelatedVectorMode::Centroid,
+            };
+            related_notes(index, path, limit, rrf_k, true, collection, &mcp_folder_scope(index, args)?, exclude_linked, vector_mode, files)
+        }
+        "status" => {
+            let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
+            stats(index, true)
         }
+        _ => Err(anyhow::anyhow!("Unknown tool: {}", tool)),
     }
-
-    Ok(())
 }
 
-fn list_links(index_dir: &str, from: &str, json_out: bool) -> Result<()> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-    let schema = index.schema();
-    let path_field = schema.get_field("path").unwrap();
-    let links_field = schema.get_field("links").unwrap();
-
-    let term = Term::from_field_text(path_field, from);
-    let doc_opt: Option<TantivyDocument> = searcher
-        .search(
-            &tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic),
-            &TopDocs::with_limit(1),
-        )?
-        .into_iter()
-        .next()
-        .map(|(_, addr)| searcher.doc(addr))
-        .transpose()?;
+#[derive(Debug, Default, Serialize)]
+struct StoreDiff {
+    entries: usize,
+    missing: Vec<String>,
+    stale: Vec<String>,
+    orphaned: Vec<String>,
+}
 
-    let mut links: Vec<String> = vec![];
-    if let Some(doc) = doc_opt {
-        if let Some(val) = doc.get_first(links_field).and_then(|v| v.as_str()) {
-            links = serde_json::from_str::<Vec<String>>(val).unwrap_or_default();
+impl StoreDiff {
+    fn compare(disk: &HashMap<String, i64>, store: &HashMap<String, i64>) -> StoreDiff {
+        let mut diff = StoreDiff { entries: store.len(), ..Default::default() };
+        for (path, mtime) in disk {
+            match store.get(path) {
+                None => diff.missing.push(path.clone()),
+                Some(old) if old < mtime => diff.stale.push(path.clone()),
+                Some(_) => {}
+            }
         }
+        diff.orphaned = store.keys().filter(|p| !disk.contains_key(*p)).cloned().collect();
+        diff.missing.sort();
+        diff.stale.sort();
+        diff.orphaned.sort();
+        diff
     }
 
-    if json_out {
-        let out = json_response(json!({ "from": from, "links": links }));
-        println!("{out}");
-    } else {
-        for l in links {
-            println!("{l}");
-        }
+    fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.orphaned.is_empty()
     }
+}
 
-    Ok(())
+#[derive(Debug, Serialize)]
+struct BadChunk {
+    id: i64,
+    path: String,
+    dims: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct VssCheck {
+    table: bool,
+    extension_loaded: bool,
+    error: Option<String>,
+    orphaned_rows: usize,
+    missing_rows: usize,
 }
 
+#[derive(Debug, Default, Serialize)]
+struct HnswCheck {
+    graph: bool,
+    error: Option<String>,
+    nodes: usize,
+    tombstones: usize,
+    /// Graph nodes whose chunk is gone.
+    orphaned_nodes: usize,
+    /// Chunks absent from the graph or embedded differently since it was updated.
+    missing_nodes: usize,
+    /// Share of the exact top 10 the graph returns, over sampled stored vectors.
+    recall_at_10: Option<f32>,
+}
 
-fn list_backlinks(index_dir: &str, to: &str, json_out: bool) -> Result<()> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-    let schema = index.schema();
-    let path_field = schema.get_field("path").unwrap();
-    let links_term_field = schema.get_field("links_term").unwrap();
+#[derive(Debug, Serialize)]
+struct DocIdCollision {
+    doc_id: String,
+    paths: Vec<String>,
+}
 
-    let term = Term::from_field_text(links_term_field, to);
-    let q = tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
-    let top_docs = searcher.search(&q, &TopDocs::with_limit(10_000))?;
+#[derive(Debug, Default, Serialize)]
+struct VerifyReport {
+    vault_notes: usize,
+    tantivy: StoreDiff,
+    duplicates: Vec<String>,
+    catalog: StoreDiff,
+    embeddings: Option<StoreDiff>,
+    orphaned_chunks: usize,
+    bad_dimensions: Vec<BadChunk>,
+    expected_dims: usize,
+    vss: VssCheck,
+    vec: VssCheck,
+    hnsw: HnswCheck,
+    doc_id_collisions: Vec<DocIdCollision>,
+}
 
-    let mut results: Vec<String> = Vec::new();
-    for (_score, doc_address) in top_docs {
-        let doc: TantivyDocument = searcher.doc(doc_address)?;
-        let path = doc
-            .get_first(path_field)
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        if !path.is_empty() {
-            results.push(path);
-        }
+impl VerifyReport {
+    fn is_clean(&self) -> bool {
+        self.tantivy.is_clean()
+            && self.duplicates.is_empty()
+            && self.catalog.is_clean()
+            && self.embeddings.as_ref().is_none_or(|e| e.is_clean())
+            && self.orphaned_chunks == 0
+            && self.bad_dimensions.is_empty()
+            && self.vss.error.is_none()
+            && self.vss.orphaned_rows == 0
+            && self.vss.missing_rows == 0
+            && self.vec.error.is_none()
+            && self.vec.orphaned_rows == 0
+            && self.vec.missing_rows == 0
+            && self.hnsw.error.is_none()
+            && self.hnsw.orphaned_nodes == 0
+            && self.hnsw.missing_nodes == 0
+            && self.doc_id_collisions.is_empty()
     }
+}
 
-    results.sort();
-    results.dedup();
+fn verify_index(vault: &str, index_dir: &str, collection: Option<String>, repair: bool, json_out: bool) -> Result<()> {
+    let (scan_root, collection_name) = resolve_scan_root(vault, collection)?;
+
+    let report = verify_report(&scan_root, index_dir, &collection_name)?;
+    let clean = report.is_clean();
+
+    let repaired = if repair && !clean {
+        Some(repair_index(vault, index_dir, &collection_name, &report)?)
+    } else {
+        None
+    };
 
     if json_out {
-        let out = json_response(json!({ "to": to, "backlinks": results }));
+        let out = json_response(json!({ "ok": clean, "report": report, "repaired": repaired }));
         println!("{out}");
     } else {
-        for r in results {
-            println!("{r}");
+        let e = report.embeddings.as_ref();
+        println!("vault notes\t{}", report.vault_notes);
+        println!("tantivy\tmissing {}\tstale {}\torphaned {}\tduplicates {}", report.tantivy.missing.len(), report.tantivy.stale.len(), report.tantivy.orphaned.len(), report.duplicates.len());
+        println!("catalog\tmissing {}\tstale {}\torphaned {}", report.catalog.missing.len(), report.catalog.stale.len(), report.catalog.orphaned.len());
+        match e {
+            Some(e) => println!("embeddings\tmissing {}\tstale {}\torphaned {}\torphaned chunks {}\tbad dims {}", e.missing.len(), e.stale.len(), e.orphaned.len(), report.orphaned_chunks, report.bad_dimensions.len()),
+            None => println!("embeddings\tnot built"),
+        }
+        if report.vss.table {
+            println!("vss\tloaded {}\torphaned {}\tmissing {}", report.vss.extension_loaded, report.vss.orphaned_rows, report.vss.missing_rows);
         }
+        if report.vec.table {
+            println!("vec\tloaded {}\torphaned {}\tmissing {}", report.vec.extension_loaded, report.vec.orphaned_rows, report.vec.missing_rows);
+        }
+        if report.hnsw.graph {
+            let recall = report.hnsw.recall_at_10.map_or("-".to_string(), |r| format!("{r:.3}"));
+            println!("hnsw	nodes {}	tombstones {}	orphaned {}	missing {}	recall@10 {recall}", report.hnsw.nodes, report.hnsw.tombstones, report.hnsw.orphaned_nodes, report.hnsw.missing_nodes);
+        }
+        println!("doc id collisions\t{}", report.doc_id_collisions.len());
+        println!("{}", if clean { "ok" } else if repaired.is_some() { "repaired" } else { "problems found (rerun with --repair)" });
     }
-
     Ok(())
 }
 
-fn watch_vault(vault: &str, index_dir: &str, debounce_ms: u64) -> Result<()> {
-    // Initial index
-    build_index(vault, index_dir, true, None)?;
-
-    let (tx, rx) = channel();
-    let mut watcher = notify::recommended_watcher(tx)?;
-    watcher.configure(NotifyConfig::default().with_poll_interval(Duration::from_millis(250)))?;
-    watcher.watch(Path::new(vault), RecursiveMode::Recursive)?;
-
-    println!("Watching {} (index: {})", vault, index_dir);
+fn verify_report(scan_root: &Path, index_dir: &str, collection_name: &str) -> Result<VerifyReport> {
+    let mut report = VerifyReport { expected_dims: DEFAULT_EMBED_DIMS, ..Default::default() };
 
-    loop {
-        // block until event
-        let _ = rx.recv();
-        // debounce: drain events for debounce_ms
-        let start = std::time::Instant::now();
-        while start.elapsed() < Duration::from_millis(debounce_ms) {
-            if rx.try_recv().is_err() {
-                std::thread::sleep(Duration::from_millis(50));
-            }
+    let mut disk: HashMap<String, i64> = HashMap::new();
+    for entry in WalkDir::new(scan_root).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("md") {
+            let mtime = fs::metadata(path).map(|m| file_mtime(&m)).unwrap_or(0);
+            disk.insert(path.to_string_lossy().to_string(), mtime);
         }
-        // incremental rebuild
-        let _ = build_index(vault, index_dir, true, None);
     }
-}
-
-
-#[derive(Debug, Serialize)]
-struct VectorResult {
-    path: String,
-    score: f32,
-    chunk: String,
-}
+    report.vault_notes = disk.len();
 
-fn ensure_vss(conn: &Connection, dims: usize) -> Result<()> {
-    unsafe {
-        conn.load_extension_enable()?;
-    }
-    let vector_path = env::var("OBSIDX_VSS_VECTOR0").unwrap_or_else(|_| "vector0".to_string());
-    let vss_path = env::var("OBSIDX_VSS_VSS0").unwrap_or_else(|_| "vss0".to_string());
-    unsafe {
-        conn.load_extension(vector_path, None)?;
-        conn.load_extension(vss_path, None)?;
-    }
-    conn.execute_batch(&format!(
-        "CREATE VIRTUAL TABLE IF NOT EXISTS vss_chunks USING vss0(embedding({dims}));"
-    ))?;
-    Ok(())
-}
+    // Tantivy: live documents only, so deleted-but-unmerged docs aren't counted.
+    let index = open_existing_index(index_dir)?;
+    let fields = schema_fields(&index)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+    let mut tantivy_mtimes: HashMap<String, i64> = HashMap::new();
+    let mut path_counts: HashMap<String, usize> = HashMap::new();
+    let mut doc_ids: HashMap<String, Vec<String>> = HashMap::new();
+    for segment_reader in searcher.segment_readers() {
+        let store_reader = segment_reader.get_store_reader(0)?;
+        for doc_id in segment_reader.doc_ids_alive() {
+            let doc: TantivyDocument = store_reader.get(doc_id)?;
+            let coll = doc.get_first(fields.collection).and_then(|v| v.as_str()).unwrap_or("");
+            if coll != collection_name {
+                continue;
+            }
+            let path = doc.get_first(fields.path).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            let mtime = doc.get_first(fields.mtime).and_then(|v| v.as_i64()).unwrap_or(0);
+            let id = doc.get_first(fields.doc_id).and_then(|v| v.as_str()).unwrap_or("").to_string();
+            *path_counts.entry(path.clone()).or_insert(0) += 1;
+            let paths = doc_ids.entry(id).or_default();
+            if !paths.contains(&path) {
+                paths.push(path.clone());
+            }
+            let slot = tantivy_mtimes.entry(path).or_insert(mtime);
+            *slot = (*slot).max(mtime);
+        }
+    }
+    report.tantivy = StoreDiff::compare(&disk, &tantivy_mtimes);
+    report.duplicates = path_counts.into_iter().filter(|(_, n)| *n > 1).map(|(p, _)| p).collect();
+    report.duplicates.sort();
+    report.doc_id_collisions = doc_ids
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(doc_id, mut paths)| {
+            paths.sort();
+            DocIdCollision { doc_id, paths }
+        })
+        .collect();
+    report.doc_id_collisions.sort_by(|a, b| a.doc_id.cmp(&b.doc_id));
+
+    let catalog = open_catalog(index_dir)?;
+    let catalog_mtimes: HashMap<String, i64> = catalog
+        .prepare("SELECT path, mtime FROM notes WHERE collection = ?1")?
+        .query_map(params![collection_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+    report.catalog = StoreDiff::compare(&disk, &catalog_mtimes);
 
-fn embed_index(
-    vault: &str,
-    index_dir: &str,
-    max_chars: usize,
-    overlap: usize,
-    incremental: bool,
-    collection: Option<String>,
-    embed_backend: EmbeddingBackend,
-    embed_model: Option<&str>,
-    vector_backend: VectorBackend,
-) -> Result<()> {
-    fs::create_dir_all(index_dir).ok();
     let db_path = Path::new(index_dir).join("embeddings.db");
-    let conn = Connection::open(db_path)?;
-    conn.execute_batch(
-        "CREATE TABLE IF NOT EXISTS chunks (\
-            id INTEGER PRIMARY KEY,\
-            path TEXT,\
-            collection TEXT,\
-            chunk TEXT,\
-            chunk_hash TEXT,\
-            mtime INTEGER,\
-            embedding TEXT\
-        );\
-         CREATE TABLE IF NOT EXISTS notes (\
-            path TEXT PRIMARY KEY,\
-            collection TEXT,\
-            mtime INTEGER\
-        );\
-         CREATE INDEX IF NOT EXISTS idx_chunks_path ON chunks(path);\
-         CREATE INDEX IF NOT EXISTS idx_chunks_hash ON chunks(chunk_hash);\
-         CREATE INDEX IF NOT EXISTS idx_chunks_collection ON chunks(collection);\
-        ",
-    )?;
+    if db_path.exists() {
+        let conn = open_sqlite(&db_path)?;
+        if let Some(dims) = index_dims(&conn) {
+            report.expected_dims = dims;
+        }
+        let note_mtimes: HashMap<String, i64> = conn
+            .prepare("SELECT path, mtime FROM notes WHERE collection = ?1")?
+            .query_map(params![collection_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .filter_map(|r| r.ok())
+            .collect();
+        report.embeddings = Some(StoreDiff::compare(&disk, &note_mtimes));
+        report.orphaned_chunks = conn.query_row(
+            "SELECT COUNT(*) FROM chunks c WHERE NOT EXISTS (SELECT 1 FROM notes n WHERE n.path = c.path)",
+            [],
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+
+        let encoding = stored_vector_encoding(&conn)?;
+        let mut stmt = conn.prepare("SELECT id, path, embedding FROM chunks WHERE collection = ?1")?;
+        let rows = stmt.query_map(params![collection_name], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Vec<u8>>(2)?))
+        })?;
+        for (id, path, blob) in rows.flatten() {
+            let dims = encoding.dims(blob.len());
+            if dims != report.expected_dims {
+                report.bad_dimensions.push(BadChunk { id, path, dims });
+            }
+        }
 
-    if matches!(vector_backend, VectorBackend::Vss) {
-        ensure_vss(&conn, DEFAULT_EMBED_DIMS)?;
+        report.vss = check_vector_table(&conn, "vss_chunks", load_vss_extensions)?;
+        report.vec = check_vector_table(&conn, "vec_chunks", load_vec_extension)?;
+
+        report.hnsw.graph = hnsw_path(index_dir).exists();
+        if report.hnsw.graph {
+            match Hnsw::load(&hnsw_path(index_dir)) {
+                Ok(Some(graph)) => {
+                    let (live, missing) = graph.diff(&conn)?;
+                    report.hnsw.nodes = graph.len();
+                    report.hnsw.tombstones = graph.deleted;
+                    report.hnsw.orphaned_nodes = graph.slots.keys().filter(|id| !live.contains_key(id)).count();
+                    report.hnsw.missing_nodes = missing.len();
+                    report.hnsw.recall_at_10 = hnsw_recall(&graph, &conn, 10, 100)?;
+                }
+                Ok(None) => {}
+                Err(e) => report.hnsw.error = Some(e.to_string()),
+            }
+        }
     }
 
-    if !incremental {
-        if matches!(vector_backend, VectorBackend::Vss) {
-            conn.execute("DELETE FROM vss_chunks", [])?;
+    Ok(report)
+}
+
+/// Compares an extension-backed vector table's rowids with `chunks`.
+fn check_vector_table(conn: &Connection, table: &str, load: fn(&Connection) -> Result<()>) -> Result<VssCheck> {
+    let mut check = VssCheck {
+        table: conn
+            .query_row("SELECT 1 FROM sqlite_master WHERE name = ?1", params![table], |_| Ok(()))
+            .is_ok(),
+        ..Default::default()
+    };
+    match load(conn) {
+        Ok(()) => {
+            check.extension_loaded = true;
+            if check.table {
+                check.orphaned_rows = conn.query_row(
+                    &format!("SELECT COUNT(*) FROM {table} WHERE rowid NOT IN (SELECT id FROM chunks)"),
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )? as usize;
+                check.missing_rows = conn.query_row(
+                    &format!("SELECT COUNT(*) FROM chunks WHERE id NOT IN (SELECT rowid FROM {table})"),
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )? as usize;
+            }
         }
-        conn.execute("DELETE FROM chunks", [])?;
-        conn.execute("DELETE FROM notes", [])?;
+        // Only a problem if the index was built with this backend.
+        Err(e) if check.table => check.error = Some(e.to_string()),
+        Err(_) => {}
     }
+    Ok(check)
+}
 
-    let collection_path = resolve_collection_path(&collection)?;
-    let (scan_root, collection_name) = if let Some(p) = collection_path { (p, collection.unwrap()) } else { (PathBuf::from(vault), "default".to_string()) };
-    let docs = scan_vault(&scan_root, &collection_name)?;
-    let mut inserted = 0;
-    let mut skipped = 0;
-    let mut updated = 0;
+#[derive(Debug, Default, Serialize)]
+struct RepairSummary {
+    tantivy_deleted: usize,
+    catalog_deleted: usize,
+    embeddings_deleted: usize,
+    chunks_deleted: usize,
+    vss_rows_deleted: usize,
+    vec_rows_deleted: usize,
+    reindexed: bool,
+    reembedded: bool,
+    hnsw: Option<HnswSync>,
+}
 
-    for doc in docs {
-        // Check note mtime
-        let mut stmt = conn.prepare("SELECT mtime FROM notes WHERE path = ?1")?;
-        let existing_mtime: Option<i64> = stmt
-            .query_row(params![doc.path], |row| row.get(0))
-            .ok();
+/// Removes orphaned, duplicate and malformed entries, then lets the incremental
+/// indexers re-add whatever is missing or stale.
+fn repair_index(vault: &str, index_dir: &str, collection_name: &str, report: &VerifyReport) -> Result<RepairSummary> {
+    let mut summary = RepairSummary::default();
+    let collection = (collection_name != "default").then(|| collection_name.to_string());
+
+    {
+        let _lock = lock_index(index_dir)?;
+        let index = open_existing_index(index_dir)?;
+        let fields = schema_fields(&index)?;
+        let mut writer = index_writer(&index, index_dir)?;
+        // Duplicates, and notes the catalog lost track of, are deleted outright and
+        // re-added to both stores by the incremental pass below.
+        let catalog_gaps = report.catalog.missing.iter().chain(&report.catalog.stale);
+        for path in report.tantivy.orphaned.iter().chain(&report.duplicates).chain(catalog_gaps) {
+            writer.delete_term(Term::from_field_text(fields.path, path));
+            summary.tantivy_deleted += 1;
+        }
+        writer.commit()?;
 
-        if incremental {
-            if let Some(old) = existing_mtime {
-                if old >= doc.mtime {
-                    skipped += 1;
-                    continue;
+        let catalog = open_catalog(index_dir)?;
+        for path in report.catalog.orphaned.iter().chain(&report.duplicates) {
+            catalog_delete(&catalog, path)?;
+            summary.catalog_deleted += 1;
+        }
+
+        let db_path = Path::new(index_dir).join("embeddings.db");
+        if report.embeddings.is_some() {
+            let conn = open_sqlite(&db_path)?;
+            let vss = report.vss.table && report.vss.extension_loaded;
+            let vec = report.vec.table && report.vec.extension_loaded;
+            if vss {
+                load_vss_extensions(&conn)?;
+            }
+            if vec {
+                load_vec_extension(&conn)?;
+            }
+            let orphaned = report.embeddings.as_ref().map(|e| e.orphaned.clone()).unwrap_or_default();
+            let bad_paths: Vec<String> = report.bad_dimensions.iter().map(|b| b.path.clone()).collect();
+            for path in orphaned.iter().chain(&bad_paths) {
+                if vss {
+                    summary.vss_rows_deleted += conn.execute(
+                        "DELETE FROM vss_chunks WHERE rowid IN (SELECT id FROM chunks WHERE path = ?1)",
+                        params![path],
+                    )?;
                 }
+                if vec {
+                    summary.vec_rows_deleted += conn.execute(
+                        "DELETE FROM vec_chunks WHERE rowid IN (SELECT id FROM chunks WHERE path = ?1)",
+                        params![path],
+                    )?;
+                }
+                summary.chunks_deleted += conn.execute("DELETE FROM chunks WHERE path = ?1", params![path])?;
+                summary.embeddings_deleted += conn.execute("DELETE FROM notes WHERE path = ?1", params![path])?;
             }
-            // remove old chunks for this path
-            if matches!(vector_backend, VectorBackend::Vss) {
+            summary.chunks_deleted += conn.execute(
+                "DELETE FROM chunks WHERE NOT EXISTS (SELECT 1 FROM notes n WHERE n.path = chunks.path)",
+                [],
+            )?;
+            if vss {
+                summary.vss_rows_deleted += conn.execute(
+                    "DELETE FROM vss_chunks WHERE rowid NOT IN (SELECT id FROM chunks)",
+                    [],
+                )?;
+                // Chunks without a VSS row can only be fixed by re-embedding their note.
                 conn.execute(
-                    "DELETE FROM vss_chunks WHERE rowid IN (SELECT id FROM chunks WHERE path = ?1)",
-                    params![doc.path],
+                    "DELETE FROM notes WHERE path IN (SELECT path FROM chunks WHERE id NOT IN (SELECT rowid FROM vss_chunks))",
+                    [],
                 )?;
             }
-            conn.execute("DELETE FROM chunks WHERE path = ?1", params![doc.path])?;
-            updated += 1;
-        }
-
-        let chunks = chunk_text(&doc.content, max_chars, overlap);
-        for ch in chunks {
-            let hash = hash_str(&ch);
-            let emb = embed_text(&ch, embed_backend.clone(), DEFAULT_EMBED_DIMS, embed_model)?;
-            let emb_json = serde_json::to_string(&emb).unwrap_or_else(|_| "[]".to_string());
-            conn.execute(
-                "INSERT INTO chunks (path, collection, chunk, chunk_hash, mtime, embedding) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-                params![doc.path, doc.collection, ch, hash, doc.mtime, emb_json],
-            )?;
-            let rowid = conn.last_insert_rowid();
-            if matches!(vector_backend, VectorBackend::Vss) {
+            if vec {
+                summary.vec_rows_deleted += conn.execute(
+                    "DELETE FROM vec_chunks WHERE rowid NOT IN (SELECT id FROM chunks)",
+                    [],
+                )?;
                 conn.execute(
-                    "INSERT INTO vss_chunks (rowid, embedding) VALUES (?1, ?2)",
-                    params![rowid, serde_json::to_string(&emb).unwrap_or_else(|_| "[]".to_string())],
+                    "DELETE FROM notes WHERE path IN (SELECT path FROM chunks WHERE id NOT IN (SELECT rowid FROM vec_chunks))",
+                    [],
                 )?;
             }
-            inserted += 1;
         }
-
-        conn.execute(
-            "INSERT INTO notes (path, collection, mtime) VALUES (?1, ?2, ?3)\
-             ON CONFLICT(path) DO UPDATE SET mtime=excluded.mtime, collection=excluded.collection",
-            params![doc.path, doc.collection, doc.mtime],
-        )?;
     }
 
-    let out = json_response(json!({
-        "message": "embeddings indexed (hash placeholder)",
-        "vault": vault,
-        "index": index_dir,
-        "chunks": inserted,
-        "skipped": skipped,
-        "updated": updated
-    }));
-    println!("{out}");
-    Ok(())
+    let needs_reindex = !report.tantivy.missing.is_empty()
+        || !report.tantivy.stale.is_empty()
+        || !report.duplicates.is_empty()
+        || !report.catalog.missing.is_empty()
+        || !report.catalog.stale.is_empty();
+    if needs_reindex {
+        build_index(vault, index_dir, true, collection.clone(), None, &ScanOptions::default())?;
+        summary.reindexed = true;
+    }
+    let needs_reembed = report.embeddings.as_ref().is_some_and(|e| !e.missing.is_empty() || !e.stale.is_empty())
+        || !report.bad_dimensions.is_empty()
+        || report.vss.missing_rows > 0
+        || report.vec.missing_rows > 0;
+    if needs_reembed {
+        let vector_backend = recorded_vector_backend(index_dir).unwrap_or(if report.vec.table {
+            VectorBackend::Vec
+        } else if report.vss.table {
+            VectorBackend::Vss
+        } else {
+            VectorBackend::Bruteforce
+        });
+        let (embed_backend, embed_model) = recorded_embedding(index_dir)?;
+        let (max_chars, overlap) = recorded_chunking(index_dir);
+        embed_index(vault, index_dir, max_chars, overlap, true, collection, embed_backend, embed_model.as_deref(), None, vector_backend, None, &HnswOptions::default(), &ScanOptions::default())?;
+        summary.reembedded = true;
+    }
+    if report.hnsw.graph {
+        // Re-embedding already synced the graph; this catches deletions and rebuilds
+        // a graph that failed to load.
+        let _lock = lock_index(index_dir)?;
+        let conn = open_sqlite(&Path::new(index_dir).join("embeddings.db"))?;
+        summary.hnsw = update_hnsw(index_dir, &conn, &HnswOptions::default(), true, report.hnsw.error.is_some())?;
+    }
+    Ok(summary)
 }
 
-fn embed_search(index_dir: &str, query: &str, limit: usize, json_out: bool, collection: Option<String>, min_score: f32, files: bool, all: bool, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend) -> Result<()> {
-    let mut results = embed_search_results(index_dir, query, limit, collection.clone(), embed_backend, embed_model, vector_backend)?;
-    results.retain(|r| r.score >= min_score);
-    let limit = if all { 10_000 } else { limit };
-    results.truncate(limit);
-
+fn optimize(index_dir: &str, json_out: bool) -> Result<()> {
+    let summary = optimize_index(index_dir)?;
     if json_out {
-        if files {
-            let files_out: Vec<String> = results.iter().map(|r| r.path.clone()).collect();
-            let out = json_response(json!({ "query": query, "files": files_out }));
-            println!("{out}");
-        } else {
-            let out = json_response(json!({ "query": query, "results": results }));
-            println!("{out}");
-        }
+        println!("{}", json_response(summary));
     } else {
-        for r in results {
-            println!("{}	{:.3}	{}", r.path, r.score, r.chunk);
+        println!("segments\t{} -> {}", summary["segments"]["before"], summary["segments"]["after"]);
+        for store in ["tantivy", "catalog", "embeddings"] {
+            println!("{store}\t{} -> {} bytes", summary["bytes"][store]["before"], summary["bytes"][store]["after"]);
+        }
+        if summary["vss"]["rebuilt"].as_bool().unwrap_or(false) {
+            println!("vss\trebuilt");
+        }
+        if summary["vec"]["rebuilt"].as_bool().unwrap_or(false) {
+            println!("vec\trebuilt");
+        }
+        if summary["hnsw"]["graph"].as_bool().unwrap_or(false) {
+            println!("hnsw\tadded {}\tremoved {}\tcompacted {}", summary["hnsw"]["sync"]["added"], summary["hnsw"]["sync"]["removed"], summary["hnsw"]["sync"]["compacted"]);
         }
     }
     Ok(())
 }
 
-fn hybrid_search(index_dir: &str, query: &str, limit: usize, rrf_k: u32, bm25_limit: usize, vec_limit: usize, json_out: bool, collection: Option<String>, min_score: f32, files: bool, all: bool, expand: u32, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend) -> Result<()> {
-    let mut scores: HashMap<String, f32> = HashMap::new();
-
-    // Original query (bonus)
-    let bm25 = bm25_search(index_dir, query, bm25_limit, collection.clone())?;
-    let vec = embed_search_results(index_dir, query, vec_limit, collection.clone(), embed_backend.clone(), embed_model, vector_backend.clone())?;
-    for (rank, item) in bm25.iter().enumerate() {
-        let r = (rrf_k + (rank as u32) + 1) as f32;
-        *scores.entry(item.path.clone()).or_insert(0.0) += 2.0 * (1.0 / r);
-    }
-    for (rank, item) in vec.iter().enumerate() {
-        let r = (rrf_k + (rank as u32) + 1) as f32;
-        *scores.entry(item.path.clone()).or_insert(0.0) += 2.0 * (1.0 / r);
+/// Store sizes with both SQLite WALs checkpointed into their main files first, so
+/// pending WAL pages aren't counted on top of the pages they will replace.
+fn store_sizes(index_dir: &str) -> Result<[u64; 3]> {
+    for db in [catalog_path(index_dir), Path::new(index_dir).join("embeddings.db")] {
+        if db.exists() {
+            open_sqlite(&db)?.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        }
     }
+    Ok([
+        tantivy_dir_size(Path::new(index_dir)),
+        sqlite_file_size(&catalog_path(index_dir)),
+        sqlite_file_size(&Path::new(index_dir).join("embeddings.db")),
+    ])
+}
 
-    // Expanded queries
-    for qx in expand_query(query, expand) {
-        let bm25x = bm25_search(index_dir, &qx, bm25_limit, collection.clone())?;
-        let vecx = embed_search_results(index_dir, &qx, vec_limit, collection.clone(), embed_backend.clone(), embed_model, vector_backend.clone())?;
-        for (rank, item) in bm25x.iter().enumerate() {
-            let r = (rrf_k + (rank as u32) + 1) as f32;
-            *scores.entry(item.path.clone()).or_insert(0.0) += 1.0 / r;
-        }
-        for (rank, item) in vecx.iter().enumerate() {
-            let r = (rrf_k + (rank as u32) + 1) as f32;
-            *scores.entry(item.path.clone()).or_insert(0.0) += 1.0 / r;
-        }
+/// Force-merges tantivy segments, drops unreferenced segment files, and
+/// checkpoints, vacuums and analyzes both SQLite stores. The VSS table is rebuilt
+/// from `chunks` when the two have drifted apart.
+fn optimize_index(index_dir: &str) -> Result<serde_json::Value> {
+    let _lock = lock_index(index_dir)?;
+    let before = store_sizes(index_dir)?;
+
+    let index = open_existing_index(index_dir)?;
+    let segment_ids = index.searchable_segment_ids()?;
+    let segments_before = segment_ids.len();
+    let has_deletes = index
+        .searchable_segment_metas()?
+        .iter()
+        .any(|m| m.has_deletes());
+    let mut writer = index_writer(&index, index_dir)?;
+    if segment_ids.len() > 1 || has_deletes {
+        writer.merge(&segment_ids).wait()?;
     }
+    let gc = writer.garbage_collect_files().wait()?;
+    writer.wait_merging_threads()?;
+    let segments_after = index.searchable_segment_ids()?.len();
 
-    let mut fused: Vec<(String, f32)> = scores.into_iter().collect();
-    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    let limit = if all { 10_000 } else { limit };
-    fused.retain(|(_, score)| *score >= min_score);
-    fused.truncate(limit);
+    let vacuum = |conn: &Connection| -> Result<()> {
+        conn.execute_batch("VACUUM; ANALYZE;")?;
+        Ok(())
+    };
+    if catalog_path(index_dir).exists() {
+        vacuum(&open_catalog(index_dir)?)?;
+    }
 
-    if json_out {
-        if files {
-            let files_out: Vec<String> = fused.iter().map(|(p, _)| p.clone()).collect();
-            let out = json_response(json!({ "query": query, "files": files_out }));
-            println!("{out}");
-        } else {
-            let out = json_response(json!({ "query": query, "results": fused }));
-            println!("{out}");
+    let mut vss = json!({"table": false, "rebuilt": false, "error": null});
+    let mut vec = json!({"table": false, "rebuilt": false, "error": null});
+    let mut hnsw = json!({"graph": false, "sync": null, "error": null});
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    if db_path.exists() {
+        let conn = open_sqlite(&db_path)?;
+        let has_vss = conn
+            .query_row("SELECT 1 FROM sqlite_master WHERE name = 'vss_chunks'", [], |_| Ok(()))
+            .is_ok();
+        if has_vss {
+            vss["table"] = json!(true);
+            match rebuild_vss_if_needed(&conn) {
+                Ok(rebuilt) => vss["rebuilt"] = json!(rebuilt),
+                Err(e) => vss["error"] = json!(e.to_string()),
+            }
         }
-    } else {
-        for (path, score) in fused {
-            println!("{}	{:.4}", path, score);
+        let has_vec = conn
+            .query_row("SELECT 1 FROM sqlite_master WHERE name = 'vec_chunks'", [], |_| Ok(()))
+            .is_ok();
+        if has_vec {
+            vec["table"] = json!(true);
+            match rebuild_vec_if_needed(&conn) {
+                Ok(rebuilt) => vec["rebuilt"] = json!(rebuilt),
+                Err(e) => vec["error"] = json!(e.to_string()),
+            }
+        }
+        if hnsw_path(index_dir).exists() {
+            hnsw["graph"] = json!(true);
+            match compact_hnsw(index_dir, &conn) {
+                Ok(sync) => hnsw["sync"] = json!(sync),
+                Err(e) => hnsw["error"] = json!(e.to_string()),
+            }
         }
+        vacuum(&conn)?;
     }
 
-    Ok(())
+    let after = store_sizes(index_dir)?;
+    let size = |i: usize| json!({"before": before[i], "after": after[i]});
+    Ok(json!({
+        "message": "index optimized",
+        "index": index_dir,
+        "segments": {"before": segments_before, "after": segments_after},
+        "deleted_files": gc.deleted_files.len(),
+        "bytes": {"tantivy": size(0), "catalog": size(1), "embeddings": size(2)},
+        "vss": vss,
+        "vec": vec,
+        "hnsw": hnsw,
+    }))
 }
 
-
-fn expand_query(query: &str, n: u32) -> Vec<String> {
-    if n == 0 { return vec![]; }
-    let mut variants = Vec::new();
-    let stop = ["the","a","an","and","or","to","of","in","on","for","with","is","are","be","by"];
-    let tokens: Vec<&str> = query.split_whitespace().collect();
-    let filtered: Vec<&str> = tokens.iter().copied().filter(|t| !stop.contains(&t.to_lowercase().as_str())).collect();
-    if !filtered.is_empty() {
-        variants.push(filtered.join(" "));
+/// Syncs the HNSW graph with `chunks` and drops every tombstone.
+fn compact_hnsw(index_dir: &str, conn: &Connection) -> Result<HnswSync> {
+    let path = hnsw_path(index_dir);
+    let mut graph = Hnsw::load(&path)?.unwrap_or_else(|| Hnsw::new(HnswOptions::default()));
+    let mut sync = graph.sync(conn)?;
+    if graph.deleted > 0 {
+        graph.compact()?;
+        sync.compacted = true;
     }
-    variants.push(format!("\"{}\"", query));
-    variants.retain(|v| v.trim() != query);
-    variants.truncate(n as usize);
-    variants
+    graph.save(&path)?;
+    Ok(sync)
 }
 
-fn bm25_search(index_dir: &str, query: &str, limit: usize, collection: Option<String>) -> Result<Vec<SearchResult>> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader = index.reader()?;
-    let searcher = reader.searcher();
-
-    let schema = index.schema();
-    let path_field = schema.get_field("path").unwrap();
-    let title_field = schema.get_field("title").unwrap();
-    let content_field = schema.get_field("content").unwrap();
-    let docid_field = schema.get_field("doc_id").unwrap();
-    let tags_field = schema.get_field("tags").unwrap();
-    let collection_field = schema.get_field("collection").unwrap();
-
-    let query_parser = QueryParser::for_index(&index, vec![title_field, content_field, tags_field]);
-    let q = query_parser.parse_query(query)?;
-    let top_docs = if let Some(name) = collection {
-        let term = Term::from_field_text(collection_field, &name);
-        let filter = tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic);
-        let combined = tantivy::query::BooleanQuery::intersection(vec![Box::new(q), Box::new(filter)]);
-        searcher.search(&combined, &TopDocs::with_limit(limit))?
-    } else {
-        searcher.search(&q, &TopDocs::with_limit(limit))?
-    };
+fn rebuild_vec_if_needed(conn: &Connection) -> Result<bool> {
+    load_vec_extension(conn)?;
+    let chunks: i64 = conn.query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))?;
+    let vec_rows: i64 = conn.query_row("SELECT COUNT(*) FROM vec_chunks", [], |row| row.get(0))?;
+    let orphaned: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM vec_chunks WHERE rowid NOT IN (SELECT id FROM chunks)",
+        [],
+        |row| row.get(0),
+    )?;
+    if chunks == vec_rows && orphaned == 0 {
+        return Ok(false);
+    }
+    let dims = index_dims(conn).unwrap_or(DEFAULT_EMBED_DIMS);
+    conn.execute_batch("DROP TABLE vec_chunks;")?;
+    ensure_vec(conn, dims)?;
+    let encoding = stored_vector_encoding(conn)?;
+    let mut stmt = conn.prepare("SELECT id, embedding, collection, path FROM chunks")?;
+    let mut insert = conn.prepare("INSERT INTO vec_chunks (rowid, embedding, collection, path) VALUES (?1, ?2, ?3, ?4)")?;
+    for row in stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?)))? {
+        let (id, blob, collection, path) = row?;
+        insert.execute(params![id, VectorEncoding::F32.encode(&encoding.decode(&blob)), collection, path])?;
+    }
+    Ok(true)
+}
 
-    let mut results = Vec::new();
-    for (score, doc_address) in top_docs {
-        let retrieved: TantivyDocument = searcher.doc(doc_address)?;
-        let path = retrieved
-            .get_first(path_field)
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        let title = retrieved
-            .get_first(title_field)
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        let doc_id = retrieved
-            .get_first(docid_field)
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string();
-        results.push(SearchResult { path, title, score, doc_id });
+fn rebuild_vss_if_needed(conn: &Connection) -> Result<bool> {
+    load_vss_extensions(conn)?;
+    let chunks: i64 = conn.query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))?;
+    let vss_rows: i64 = conn.query_row("SELECT COUNT(*) FROM vss_chunks", [], |row| row.get(0))?;
+    let orphaned: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM vss_chunks WHERE rowid NOT IN (SELECT id FROM chunks)",
+        [],
+        |row| row.get(0),
+    )?;
+    if chunks == vss_rows && orphaned == 0 {
+        return Ok(false);
     }
-    Ok(results)
+    conn.execute_batch("DROP TABLE vss_chunks;")?;
+    ensure_vss(conn, index_dims(conn).unwrap_or(DEFAULT_EMBED_DIMS))?;
+    let encoding = stored_vector_encoding(conn)?;
+    let mut stmt = conn.prepare("SELECT id, embedding FROM chunks")?;
+    let mut insert = conn.prepare("INSERT INTO vss_chunks (rowid, embedding) VALUES (?1, ?2)")?;
+    for row in stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))? {
+        let (id, blob) = row?;
+        insert.execute(params![id, VectorEncoding::F32.encode(&encoding.decode(&blob))])?;
+    }
+    Ok(true)
 }
 
-fn embed_search_results(index_dir: &str, query: &str, limit: usize, collection: Option<String>, embed_backend: EmbeddingBackend, embed_model: Option<&str>, vector_backend: VectorBackend) -> Result<Vec<VectorResult>> {
-    let db_path = Path::new(index_dir).join("embeddings.db");
-    let conn = Connection::open(db_path)?;
-    let qemb = embed_text(query, embed_backend, DEFAULT_EMBED_DIMS, embed_model)?;
+const EXPORT_FORMAT: &str = "obsidx-export";
+/// 2: note paths are relative to their collection root and embeddings are
+/// separate records. 3: the manifest records the analyzer.
+const EXPORT_FORMAT_VERSION: u32 = 3;
+
+#[derive(Debug, Serialize, serde::Deserialize)]
+struct ExportManifest {
+    format: String,
+    format_version: u32,
+    obsidx_version: String,
+    exported_at: String,
+    notes: usize,
+    collections: Vec<serde_json::Value>,
+    embeddings: HashMap<String, String>,
+    /// Analyzer the index was built with; absent before format 3, where import
+    /// falls back to config.toml or `default`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    analyzer: Option<String>,
+    /// `auto` only: the fallback for undetectable text and the languages detected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_analyzer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    languages: Option<std::collections::BTreeMap<String, usize>>,
+}
 
-    if matches!(vector_backend, VectorBackend::Vss) {
-        ensure_vss(&conn, DEFAULT_EMBED_DIMS)?;
-        let qemb_json = serde_json::to_string(&qemb).unwrap_or_else(|_| "[]".to_string());
-        let mut results: Vec<VectorResult> = Vec::new();
-        if let Some(name) = collection.as_ref() {
-            let mut stmt = conn.prepare(
-                "SELECT c.path, c.chunk, v.distance FROM vss_chunks v JOIN chunks c ON c.id = v.rowid WHERE vss_search(v.embedding, ?) AND c.collection = ? ORDER BY v.distance LIMIT ?",
-            )?;
-            let rows = stmt.query_map(params![qemb_json, name, limit as i64], |row| {
-                let path: String = row.get(0)?;
-                let chunk: String = row.get(1)?;
-                let distance: f32 = row.get(2)?;
-                let score = 1.0 - distance;
-                Ok(VectorResult { path, score, chunk })
-            })?;
-            for r in rows { if let Ok(v) = r { results.push(v); } }
-        } else {
-            let mut stmt = conn.prepare(
-                "SELECT c.path, c.chunk, v.distance FROM vss_chunks v JOIN chunks c ON c.id = v.rowid WHERE vss_search(v.embedding, ?) ORDER BY v.distance LIMIT ?",
-            )?;
-            let rows = stmt.query_map(params![qemb_json, limit as i64], |row| {
-                let path: String = row.get(0)?;
-                let chunk: String = row.get(1)?;
-                let distance: f32 = row.get(2)?;
-                let score = 1.0 - distance;
-                Ok(VectorResult { path, score, chunk })
-            })?;
-            for r in rows { if let Ok(v) = r { results.push(v); } }
-        }
-        return Ok(results);
-    }
+#[derive(Debug, Serialize, serde::Deserialize)]
+struct ExportChunk {
+    chunk: String,
+    chunk_hash: String,
+    mtime: i64,
+    embedding: Vec<f32>,
+}
 
-    let mut stmt = if collection.is_some() {
-        conn.prepare("SELECT path, chunk, embedding FROM chunks WHERE collection = ?1")?
-    } else {
-        conn.prepare("SELECT path, chunk, embedding FROM chunks")?
-    };
-    let rows_vec: Vec<(String, String, Vec<f32>)> = if let Some(name) = collection.as_ref() {
-        stmt.query_map(params![name], |row| {
-            let path: String = row.get(0)?;
-            let chunk: String = row.get(1)?;
-            let emb_json: String = row.get(2)?;
-            let emb: Vec<f32> = serde_json::from_str(&emb_json).unwrap_or_default();
-            Ok((path, chunk, emb))
-        })?.filter_map(|r| r.ok()).collect()
-    } else {
-        stmt.query_map([], |row| {
-            let path: String = row.get(0)?;
-            let chunk: String = row.get(1)?;
-            let emb_json: String = row.get(2)?;
-            let emb: Vec<f32> = serde_json::from_str(&emb_json).unwrap_or_default();
-            Ok((path, chunk, emb))
-        })?.filter_map(|r| r.ok()).collect()
-    };
+#[derive(Debug, Serialize, serde::Deserialize)]
+struct ExportNote {
+    /// Relative to the collection root.
+    path: String,
+    collection: String,
+    doc_id: String,
+    title: String,
+    content: String,
+    tags: Vec<String>,
+    links: Vec<String>,
+    headings: Vec<String>,
+    frontmatter: serde_json::Value,
+    mtime: i64,
+    /// Format 1 only: embeddings were nested in the note.
+    #[serde(default, skip_serializing)]
+    embedded_mtime: Option<i64>,
+    #[serde(default, skip_serializing)]
+    chunks: Vec<ExportChunk>,
+}
+
+/// Everything embeddings.db holds for one note.
+#[derive(Debug, Serialize, serde::Deserialize)]
+struct ExportEmbeddings {
+    /// Relative to the collection root.
+    path: String,
+    collection: String,
+    /// mtime recorded by `embed-index`.
+    mtime: i64,
+    chunks: Vec<ExportChunk>,
+}
 
-    let mut results: Vec<VectorResult> = Vec::new();
-    for (path, chunk, emb) in rows_vec {
-        let score = cosine_sim(&qemb, &emb);
-        results.push(VectorResult { path, score, chunk });
-    }
+/// One line of an export file: a manifest first, then one record per indexed
+/// note and one per embedded note.
+#[derive(Debug, Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExportRecord {
+    Manifest(ExportManifest),
+    Note(ExportNote),
+    Embeddings(ExportEmbeddings),
+}
 
-    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-    results.truncate(limit);
-    Ok(results)
+/// `path` relative to its collection's root, or unchanged if it lies outside it.
+fn export_path(roots: &HashMap<String, PathBuf>, collection: &str, path: &str) -> String {
+    roots
+        .get(collection)
+        .and_then(|root| Path::new(path).strip_prefix(root).ok())
+        .map(|rel| rel.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
 }
 
-fn chunk_text(text: &str, max_chars: usize, overlap: usize) -> Vec<String> {
-    if text.len() <= max_chars {
-        return vec![text.to_string()];
-    }
-    let mut chunks = Vec::new();
-    let mut start = 0;
-    while start < text.len() {
-        let end = usize::min(start + max_chars, text.len());
-        let chunk = text[start..end].to_string();
-        chunks.push(chunk);
-        if end == text.len() { break; }
-        start = end.saturating_sub(overlap);
+/// Resolves an exported path against the collection's root on this machine.
+/// Absolute paths (format 1, or notes outside their root) are kept.
+fn import_path(roots: &HashMap<String, PathBuf>, collection: &str, path: &str) -> String {
+    match roots.get(collection) {
+        Some(root) => root.join(path).to_string_lossy().to_string(),
+        None => path.to_string(),
     }
-    chunks
 }
 
-fn embed_text(text: &str, backend: EmbeddingBackend, dims: usize, model_path: Option<&str>) -> Result<Vec<f32>> {
-    match backend {
-        EmbeddingBackend::Hash => Ok(hash_embedding(text, dims)),
-        EmbeddingBackend::Ort => {
-            let model = model_path.ok_or_else(|| anyhow::anyhow!("ORT backend requires --embed-model"))?;
-            // TODO: Load ONNX model + tokenizer and produce real embeddings.
-            let _ = model;
-            Err(anyhow::anyhow!("ORT backend not yet wired (model path provided)"))
+fn export_index(index_dir: &str, output: Option<&str>) -> Result<()> {
+    let index = open_existing_index(index_dir)?;
+    let fields = schema_fields(&index)?;
+    let reader = index.reader()?;
+    let searcher = reader.searcher();
+
+    let mut collections: Vec<serde_json::Value> = Vec::new();
+    if catalog_path(index_dir).exists() {
+        let conn = open_catalog(index_dir)?;
+        let mut stmt = conn.prepare("SELECT name, root, indexed_at FROM collections ORDER BY name")?;
+        collections = stmt
+            .query_map([], |row| {
+                Ok(json!({"name": row.get::<_, String>(0)?, "root": row.get::<_, String>(1)?, "indexed_at": row.get::<_, i64>(2)?}))
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+    }
+    let roots: HashMap<String, PathBuf> = collections
+        .iter()
+        .filter_map(|c| Some((c["name"].as_str()?.to_string(), PathBuf::from(c["root"].as_str()?))))
+        .collect();
+
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    let embeddings = if db_path.exists() { Some(open_sqlite(&db_path)?) } else { None };
+    let mut embed_meta = HashMap::new();
+    let mut encoding = VectorEncoding::F32;
+    if let Some(conn) = embeddings.as_ref() {
+        encoding = stored_vector_encoding(conn)?;
+        // Databases that never ran `embed-index` to completion may lack meta.
+        if let Ok(mut stmt) = conn.prepare("SELECT key, value FROM meta") {
+            embed_meta = stmt
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .filter_map(|r| r.ok())
+                .collect();
         }
     }
-}
 
-fn hash_embedding(text: &str, dims: usize) -> Vec<f32> {
-    use std::hash::{Hash, Hasher};
-    use std::collections::hash_map::DefaultHasher;
-    let mut vec = vec![0f32; dims];
-    for (i, ch) in text.chars().enumerate() {
-        let mut h = DefaultHasher::new();
-        ch.hash(&mut h);
-        let idx = (h.finish() as usize + i) % dims;
-        vec[idx] += 1.0;
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            fs::File::create(path).with_context(|| format!("Failed to create {path}"))?,
+        )),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+    };
+
+    let analyzer = Analyzer::from_schema(&index.schema());
+    let detection = (analyzer == Analyzer::Auto).then(|| read_manifest(Path::new(index_dir)).unwrap_or_default());
+    let manifest = ExportRecord::Manifest(ExportManifest {
+        format: EXPORT_FORMAT.to_string(),
+        format_version: EXPORT_FORMAT_VERSION,
+        obsidx_version: env!("CARGO_PKG_VERSION").to_string(),
+        exported_at: Utc::now().to_rfc3339(),
+        notes: searcher.num_docs() as usize,
+        collections,
+        embeddings: embed_meta,
+        analyzer: Some(analyzer.name().to_string()),
+        fallback_analyzer: detection.as_ref().map(|_| auto_fallback(Path::new(index_dir)).name().to_string()),
+        languages: detection.map(|m| m.languages),
+    });
+    writeln!(out, "{}", serde_json::to_string(&manifest)?)?;
+
+    let text = |doc: &TantivyDocument, field: Field| doc.get_first(field).and_then(|v| v.as_str()).unwrap_or("").to_string();
+    let list = |doc: &TantivyDocument, field: Field| {
+        doc.get_first(field)
+            .and_then(|v| v.as_str())
+            .and_then(|s| serde_json::from_str::<Vec<String>>(s).ok())
+            .unwrap_or_default()
+    };
+
+    let mut exported = 0;
+    for segment_reader in searcher.segment_readers() {
+        let store_reader = segment_reader.get_store_reader(0)?;
+        for doc_id in segment_reader.doc_ids_alive() {
+            let doc: TantivyDocument = store_reader.get(doc_id)?;
+            let collection = text(&doc, fields.collection);
+            let record = ExportRecord::Note(ExportNote {
+                path: export_path(&roots, &collection, &text(&doc, fields.path)),
+                collection,
+                doc_id: text(&doc, fields.doc_id),
+                title: text(&doc, fields.title),
+                content: text(&doc, fields.content),
+                tags: list(&doc, fields.tags),
+                links: list(&doc, fields.links),
+                headings: list(&doc, fields.headings),
+                frontmatter: serde_json::from_str(&text(&doc, fields.frontmatter)).unwrap_or_else(|_| json!({})),
+                mtime: doc.get_first(fields.mtime).and_then(|v| v.as_i64()).unwrap_or(0),
+                embedded_mtime: None,
+                chunks: Vec::new(),
+            });
+            writeln!(out, "{}", serde_json::to_string(&record)?)?;
+            exported += 1;
+        }
     }
-    let norm = (vec.iter().map(|v| v*v).sum::<f32>()).sqrt();
-    if norm > 0.0 {
-        for v in &mut vec { *v /= norm; }
+
+    // Embeddings come straight from embeddings.db, so notes embedded but not in
+    // the tantivy index survive the round trip too.
+    let mut exported_chunks = 0;
+    if let Some(conn) = embeddings.as_ref() {
+        let mut notes = conn.prepare("SELECT path, collection, mtime FROM notes ORDER BY path")?;
+        let mut chunk_stmt = conn.prepare("SELECT chunk, chunk_hash, mtime, embedding FROM chunks WHERE path = ?1 ORDER BY id")?;
+        let rows = notes.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)))?;
+        for row in rows {
+            let (path, collection, mtime) = row?;
+            let chunks = chunk_stmt
+                .query_map(params![path], |row| {
+                    let blob: Vec<u8> = row.get(3)?;
+                    Ok(ExportChunk {
+                        chunk: row.get(0)?,
+                        chunk_hash: row.get(1)?,
+                        mtime: row.get(2)?,
+                        embedding: encoding.decode(&blob),
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            exported_chunks += chunks.len();
+            let record = ExportRecord::Embeddings(ExportEmbeddings {
+                path: export_path(&roots, &collection, &path),
+                collection,
+                mtime,
+                chunks,
+            });
+            writeln!(out, "{}", serde_json::to_string(&record)?)?;
+        }
     }
-    vec
-}
+    out.flush()?;
 
-fn hash_str(text: &str) -> String {
-    use std::hash::{Hash, Hasher};
-    use std::collections::hash_map::DefaultHasher;
-    let mut h = DefaultHasher::new();
-    text.hash(&mut h);
-    format!("{:x}", h.finish())
+    if output.is_some() {
+        let out = json_response(json!({
+            "message": "index exported",
+            "index": index_dir,
+            "output": output,
+            "notes": exported,
+            "chunks": exported_chunks
+        }));
+        println!("{out}");
+    }
+    Ok(())
 }
 
-fn cosine_sim(a: &[f32], b: &[f32]) -> f32 {
-    if a.is_empty() || b.is_empty() || a.len() != b.len() { return 0.0; }
-    let mut dot = 0.0; let mut na = 0.0; let mut nb = 0.0;
-    for i in 0..a.len() {
-        dot += a[i]*b[i];
-        na += a[i]*a[i];
-        nb += b[i]*b[i];
+/// Replaces the tantivy index, catalog and embeddings with the contents of an
+/// export. Nothing is read from the vault and no embeddings are recomputed.
+/// Paths are resolved against `vault` for the default collection and against the
+/// configured path for named ones, falling back to the exported roots.
+fn import_index(index_dir: &str, input: Option<&str>, vault: Option<&str>) -> Result<()> {
+    use std::io::BufRead;
+
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(std::io::BufReader::new(
+            fs::File::open(path).with_context(|| format!("Failed to open {path}"))?,
+        )),
+        None => Box::new(std::io::BufReader::new(std::io::stdin().lock())),
+    };
+    let mut lines = reader.lines();
+    let first = lines.next().transpose()?.unwrap_or_default();
+    let manifest = match serde_json::from_str::<ExportRecord>(&first) {
+        Ok(ExportRecord::Manifest(m)) if m.format == EXPORT_FORMAT => m,
+        _ => anyhow::bail!("Not an obsidx export: missing manifest line"),
+    };
+    if manifest.format_version > EXPORT_FORMAT_VERSION {
+        anyhow::bail!(
+            "Export format version {} is newer than supported version {EXPORT_FORMAT_VERSION}",
+            manifest.format_version
+        );
     }
-    if na == 0.0 || nb == 0.0 { 0.0 } else { dot / (na.sqrt()*nb.sqrt()) }
-}
 
+    let analyzer = manifest.analyzer.as_deref().map(Analyzer::parse).transpose()?;
 
-fn note_create(vault: &str, rel_path: &str, content: Option<String>, stdin: bool, reindex: bool, index_dir: &str, max_chars: usize, overlap: usize) -> Result<()> {
-    let full_path = Path::new(vault).join(rel_path);
-    if let Some(parent) = full_path.parent() {
-        fs::create_dir_all(parent)?;
+    let index_path = PathBuf::from(index_dir);
+    let _lock = lock_index(index_dir)?;
+    if let Some(fallback) = &manifest.fallback_analyzer {
+        // Tokenizers read the `auto` fallback from the manifest, so it must be in
+        // place before the index is opened.
+        let fallback = Analyzer::parse(fallback)?;
+        let interim = IndexManifest { analyzer: Analyzer::Auto.name().to_string(), fallback_analyzer: fallback.name().to_string(), ..Default::default() };
+        fs::write(index_path.join(MANIFEST_FILE), serde_json::to_string_pretty(&interim)?)?;
     }
-    let body = if stdin {
-        read_stdin()? 
-    } else {
-        content.unwrap_or_default()
+    let (index, _) = open_or_create_index(&index_path, analyzer)?;
+    let fields = schema_fields(&index)?;
+    let mut writer = index_writer(&index, index_dir)?;
+    writer.delete_all_documents()?;
+
+    let mut catalog = open_catalog(index_dir)?;
+    let catalog_tx = catalog.transaction()?;
+    catalog_clear(&catalog_tx)?;
+    let config = load_config();
+    let mut roots = HashMap::new();
+    for c in &manifest.collections {
+        let Some(name) = c["name"].as_str() else {
+            continue;
+        };
+        let root = match (name, vault) {
+            ("default", Some(vault)) => fs::canonicalize(vault).unwrap_or_else(|_| PathBuf::from(vault)),
+            _ => config
+                .collections
+                .get(name)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(c["root"].as_str().unwrap_or_default())),
+        };
+        catalog_tx.execute(
+            "INSERT INTO collections (name, root, indexed_at) VALUES (?1, ?2, ?3)",
+            params![name, root.to_string_lossy(), c["indexed_at"].as_i64()],
+        )?;
+        roots.insert(name.to_string(), root);
+    }
+
+    let vector_backend = match manifest.embeddings.get("vector_backend").map(String::as_str) {
+        Some("vss") => VectorBackend::Vss,
+        Some("hnsw") => VectorBackend::Hnsw,
+        Some("vec") => VectorBackend::Vec,
+        _ => VectorBackend::Bruteforce,
     };
-    fs::write(&full_path, body)?;
+    // Exports from before dims was recorded all hold 256-dimensional hash vectors.
+    let dims = manifest.embeddings.get("dims").and_then(|d| d.parse().ok()).unwrap_or(DEFAULT_EMBED_DIMS);
+    let embeddings = open_embeddings_db(index_dir)?;
+    if matches!(vector_backend, VectorBackend::Vss) {
+        load_vss_extensions(&embeddings)?;
+        embeddings.execute_batch("DROP TABLE IF EXISTS vss_chunks;")?;
+        ensure_vss(&embeddings, dims)?;
+    }
+    if matches!(vector_backend, VectorBackend::Vec) {
+        load_vec_extension(&embeddings)?;
+        embeddings.execute_batch("DROP TABLE IF EXISTS vec_chunks;")?;
+        ensure_vec(&embeddings, dims)?;
+    }
+    let embed_tx = embeddings.unchecked_transaction()?;
+    embeddings.execute_batch("DELETE FROM chunks; DELETE FROM notes; DELETE FROM meta;")?;
+    for (key, value) in &manifest.embeddings {
+        set_meta(&embeddings, key, value)?;
+    }
+    // Exports carry plain f32 lists; older ones predate vector_encoding.
+    let encoding = match manifest.embeddings.get("vector_encoding") {
+        Some(name) => <VectorEncoding as clap::ValueEnum>::from_str(name, true)
+            .map_err(|_| anyhow::anyhow!("Unknown vector_encoding {name} in export manifest"))?,
+        None => VectorEncoding::F32,
+    };
+    set_meta(&embeddings, "vector_encoding", &value_name(&encoding))?;
+    set_meta(&embeddings, "dims", &dims.to_string())?;
+
+    let mut notes = 0;
+    let mut chunks = 0;
+    for (lineno, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let note = match serde_json::from_str::<ExportRecord>(&line)
+            .with_context(|| format!("Bad export record on line {}", lineno + 2))?
+        {
+            ExportRecord::Note(n) => n,
+            ExportRecord::Embeddings(e) => {
+                let path = import_path(&roots, &e.collection, &e.path);
+                chunks += import_embeddings(&embeddings, &path, &e.collection, Some(e.mtime), &e.chunks, dims, &vector_backend, encoding)
+                    .with_context(|| format!("Bad export record on line {}", lineno + 2))?;
+                continue;
+            }
+            ExportRecord::Manifest(_) => anyhow::bail!("Unexpected manifest on line {}", lineno + 2),
+        };
 
-    if reindex {
-        build_index(vault, index_dir, true, None)?;
-        embed_index(vault, index_dir, max_chars, overlap, true, None, EmbeddingBackend::Hash, None, VectorBackend::Bruteforce)?;
+        let path = import_path(&roots, &note.collection, &note.path);
+        chunks += import_embeddings(&embeddings, &path, &note.collection, note.embedded_mtime, &note.chunks, dims, &vector_backend, encoding)
+            .with_context(|| format!("Bad export record on line {}", lineno + 2))?;
+
+        let doc = NoteDoc {
+            // Ids derive from the full path, which may have moved.
+            doc_id: hash_str(&path),
+            path,
+            collection: note.collection,
+            title: note.title,
+            content: note.content,
+            tags: note.tags,
+            links: note.links,
+            headings: note.headings,
+            frontmatter_json: note.frontmatter.to_string(),
+            mtime: note.mtime,
+        };
+        catalog_upsert(&catalog_tx, &doc)?;
+        writer.add_document(tantivy_doc(&fields, doc))?;
+        notes += 1;
     }
 
+    writer.commit()?;
+    catalog_tx.commit()?;
+    embed_tx.commit()?;
+    // Graphs are not exported; rebuild from the imported vectors.
+    update_hnsw(index_dir, &embeddings, &HnswOptions::default(), matches!(vector_backend, VectorBackend::Hnsw), true)?;
+    write_manifest(index_dir, Analyzer::from_schema(&index.schema()), auto_fallback(&index_path), &catalog)?;
+
     let out = json_response(json!({
-        "message": "note created",
-        "path": full_path.to_string_lossy().to_string(),
-        "reindexed": reindex
+        "message": "index imported",
+        "index": index_dir,
+        "format_version": manifest.format_version,
+        "notes": notes,
+        "chunks": chunks
     }));
     println!("{out}");
     Ok(())
 }
 
-fn note_append(vault: &str, rel_path: &str, content: Option<String>, stdin: bool, reindex: bool, index_dir: &str, max_chars: usize, overlap: usize) -> Result<()> {
-    let full_path = Path::new(vault).join(rel_path);
-    if let Some(parent) = full_path.parent() {
-        fs::create_dir_all(parent)?;
+/// Inserts one note's exported chunks and, when it was embedded, its notes row.
+#[allow(clippy::too_many_arguments)]
+fn import_embeddings(conn: &Connection, path: &str, collection: &str, mtime: Option<i64>, chunks: &[ExportChunk], dims: usize, vector_backend: &VectorBackend, encoding: VectorEncoding) -> Result<usize> {
+    for ch in chunks {
+        insert_chunk(conn, path, collection, &ch.chunk, &ch.chunk_hash, ch.mtime, &ch.embedding, dims, vector_backend, encoding)?;
     }
-    let body = if stdin { read_stdin()? } else { content.unwrap_or_default() };
-    let mut existing = String::new();
-    if full_path.exists() {
-        existing = fs::read_to_string(&full_path)?;
+    if let Some(mtime) = mtime {
+        conn.execute(
+            "INSERT INTO notes (path, collection, mtime) VALUES (?1, ?2, ?3)",
+            params![path, collection, mtime],
+        )?;
     }
-    let mut merged = existing;
-    if !merged.is_empty() && !merged.ends_with('\n') {
-        merged.push('\n');
+    Ok(chunks.len())
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionStats {
+    name: String,
+    root: Option<String>,
+    notes: usize,
+    chunks: usize,
+    changed_since_index: Option<usize>,
+    indexed_at: Option<i64>,
+}
+
+fn mcp_snippet_options(args: &serde_json::Value) -> SnippetOptions {
+    let defaults = SnippetOptions::default();
+    SnippetOptions {
+        len: args.get("snippet_len").and_then(|v| v.as_u64()).map(|n| n as usize).unwrap_or(defaults.len),
+        pre: args.get("highlight_pre").and_then(|v| v.as_str()).map(|s| s.to_string()).unwrap_or(defaults.pre),
+        post: args.get("highlight_post").and_then(|v| v.as_str()).map(|s| s.to_string()).unwrap_or(defaults.post),
     }
-    merged.push_str(&body);
-    fs::write(&full_path, merged)?;
+}
+
+/// Backend and model the index was last embedded with, for internal re-embeds.
+fn recorded_embedding(index_dir: &str) -> Result<(EmbeddingBackend, Option<String>)> {
+    mcp_embedding_options(index_dir, &json!({}))
+}
 
-    if reindex {
-        build_index(vault, index_dir, true, None)?;
-        embed_index(vault, index_dir, max_chars, overlap, true, None, EmbeddingBackend::Hash, None, VectorBackend::Bruteforce)?;
+/// Vector backend the index was last embedded with, so internal re-embeds keep its
+/// ANN table or graph in step.
+fn recorded_vector_backend(index_dir: &str) -> Option<VectorBackend> {
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    if !db_path.exists() {
+        return None;
     }
+    let name = get_meta(&open_sqlite(&db_path).ok()?, "vector_backend")?;
+    <VectorBackend as clap::ValueEnum>::from_str(&name, true).ok()
+}
 
-    let out = json_response(json!({
-        "message": "note appended",
-        "path": full_path.to_string_lossy().to_string(),
-        "reindexed": reindex
-    }));
-    println!("{out}");
-    Ok(())
+/// Chunk size and overlap the index was last embedded with, so internal re-embeds
+/// split new notes the same way as the rest of the index.
+fn recorded_chunking(index_dir: &str) -> (usize, usize) {
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    let conn = if db_path.exists() { open_sqlite(&db_path).ok() } else { None };
+    let recorded = |key: &str, default: usize| {
+        conn.as_ref().and_then(|c| get_meta(c, key)).and_then(|v| v.parse().ok()).unwrap_or(default)
+    };
+    (recorded("max_chars", DEFAULT_MAX_CHARS), recorded("overlap", DEFAULT_OVERLAP))
 }
 
-fn read_stdin() -> Result<String> {
-    let mut buf = String::new();
-    std::io::stdin().read_to_string(&mut buf)?;
-    Ok(buf)
+/// MCP `embed_backend`/`embed_model`, defaulting to whatever built the index's embeddings
+/// so queries land in the same vector space.
+fn mcp_embedding_options(index_dir: &str, args: &serde_json::Value) -> Result<(EmbeddingBackend, Option<String>)> {
+    let db_path = Path::new(index_dir).join("embeddings.db");
+    let recorded = |key: &str| {
+        if db_path.exists() { open_sqlite(&db_path).ok().and_then(|conn| get_meta(&conn, key)).filter(|v| !v.is_empty()) } else { None }
+    };
+    let backend = match args.get("embed_backend").and_then(|v| v.as_str()).map(str::to_string).or_else(|| recorded("embed_backend")) {
+        Some(name) => <EmbeddingBackend as clap::ValueEnum>::from_str(&name, true).map_err(|e| anyhow::anyhow!("Unknown embed_backend {name}: {e}"))?,
+        None => EmbeddingBackend::Hash,
+    };
+    let model = args.get("embed_model").and_then(|v| v.as_str()).map(str::to_string).or_else(|| recorded("embed_model"));
+    Ok((backend, model))
 }
 
+/// MCP `vector_backend`, defaulting to the one the index was embedded with.
+fn mcp_vector_backend(index_dir: &str, args: &serde_json::Value) -> Result<VectorBackend> {
+    match args.get("vector_backend").and_then(|v| v.as_str()) {
+        Some(name) => <VectorBackend as clap::ValueEnum>::from_str(name, true).map_err(|e| anyhow::anyhow!("Unknown vector_backend {name}: {e}")),
+        None => Ok(recorded_vector_backend(index_dir).unwrap_or(VectorBackend::Bruteforce)),
+    }
+}
 
-fn multi_get(index_dir: &str, paths: Option<String>, glob_pat: Option<String>, json_out: bool, collection: Option<String>) -> Result<()> {
-    let mut targets: Vec<String> = Vec::new();
-    if let Some(p) = paths {
-        for part in p.split(',') {
-            let trimmed = part.trim();
-            if !trimmed.is_empty() { targets.push(trimmed.to_string()); }
+/// MCP `boost` accepts either an object (`{"title": 2}`) or a `title=2,...` string.
+fn mcp_query_options(args: &serde_json::Value) -> Result<QueryOptions> {
+    let fuzzy = args.get("fuzzy").and_then(|v| v.as_u64()).unwrap_or(0).min(u8::MAX as u64) as u8;
+    let prefix = args.get("prefix").and_then(|v| v.as_bool()).unwrap_or(false);
+    // Model-written queries often carry stray syntax; recover unless asked not to.
+    let lenient = args.get("lenient").and_then(|v| v.as_bool()).unwrap_or(true);
+    let opts = match args.get("boost") {
+        Some(serde_json::Value::Object(map)) => {
+            let list: Vec<String> = map
+                .iter()
+                .filter_map(|(k, v)| v.as_f64().map(|w| format!("{k}={w}")))
+                .collect();
+            QueryOptions::with_boosts(Some(&list.join(",")))
+        }
+        Some(v) => QueryOptions::with_boosts(v.as_str()),
+        None => QueryOptions::with_boosts(None),
+    }?;
+    Ok(QueryOptions { lenient, ..opts.with_matching(fuzzy, prefix)? })
+}
+
+/// MCP `facets` accepts an array (`["tags", "year"]`) or a comma-separated string.
+fn mcp_facet_names(args: &serde_json::Value) -> Result<Vec<String>> {
+    match args.get("facets") {
+        Some(serde_json::Value::Array(items)) => {
+            let list: Vec<&str> = items.iter().filter_map(|v| v.as_str()).collect();
+            parse_facet_names(Some(&list.join(",")))
         }
+        Some(v) => parse_facet_names(v.as_str()),
+        None => Ok(Vec::new()),
     }
-    if let Some(g) = glob_pat {
-        for entry in glob(&g)? {
-            if let Ok(path) = entry {
-                targets.push(path.to_string_lossy().to_string());
+}
+
+/// MCP `folder` accepts a single prefix or an array; `!` negates, as on the CLI.
+fn mcp_folder_scope(index_dir: &str, args: &serde_json::Value) -> Result<FolderScope> {
+    let not_a_prefix = |v: &serde_json::Value| anyhow::anyhow!("folder must be a path prefix or an array of them, got {v}");
+    let folders: Vec<String> = match args.get("folder") {
+        Some(serde_json::Value::Array(items)) => items
+            .iter()
+            .map(|v| v.as_str().map(str::to_string).ok_or_else(|| not_a_prefix(v)))
+            .collect::<Result<_>>()?,
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        None | Some(serde_json::Value::Null) => Vec::new(),
+        Some(v) => return Err(not_a_prefix(v)),
+    };
+    FolderScope::resolve(index_dir, &folders)
+}
+
+fn mcp_page_options(args: &serde_json::Value) -> Result<PageOptions> {
+    let sort = match args.get("sort").and_then(|v| v.as_str()) {
+        Some(name) => <SortOrder as clap::ValueEnum>::from_str(name, true).map_err(|_| anyhow::anyhow!("Unknown sort: {name}"))?,
+        None => SortOrder::Relevance,
+    };
+    let offset = args.get("offset").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+    Ok(PageOptions { sort, offset })
+}
+
+fn stats(index_dir: &str, json_out: bool) -> Result<()> {
+    let index = open_existing_index(index_dir)?;
+    let reader: IndexReader = index.reader()?;
+    let searcher = reader.searcher();
+
+    let num_docs = searcher.num_docs();
+    let segments = searcher.segment_readers().len();
+    let deleted_docs: u32 = searcher.segment_readers().iter().map(|s| s.num_deleted_docs()).sum();
+
+    let catalog_db = catalog_path(index_dir);
+    let embeddings_db = Path::new(index_dir).join("embeddings.db");
+
+    let mut collections: Vec<CollectionStats> = Vec::new();
+    let mut tag_count = 0;
+    let mut tag_assignments = 0;
+    let mut link_count = 0;
+    let mut last_indexed: Option<i64> = None;
+    if catalog_db.exists() {
+        let conn = open_catalog(index_dir)?;
+        let mut stmt = conn.prepare(
+            "SELECT n.collection, COUNT(*), c.root, c.indexed_at FROM notes n \
+             LEFT JOIN collections c ON c.name = n.collection GROUP BY n.collection ORDER BY n.collection",
+        )?;
+        collections = stmt
+            .query_map([], |row| {
+                Ok(CollectionStats {
+                    name: row.get(0)?,
+                    notes: row.get::<_, i64>(1)? as usize,
+                    root: row.get(2)?,
+                    chunks: 0,
+                    changed_since_index: None,
+                    indexed_at: row.get(3)?,
+                })
+            })?
+            .filter_map(|r| r.ok())
+            .collect();
+        for c in collections.iter_mut() {
+            if let Some(root) = c.root.as_deref() {
+                c.changed_since_index = Some(count_changed_notes(&conn, &c.name, Path::new(root))?);
             }
         }
-    }
-    if targets.is_empty() {
-        anyhow::bail!("No paths provided");
+        tag_count = conn.query_row("SELECT COUNT(DISTINCT tag) FROM tags", [], |row| row.get::<_, i64>(0))? as usize;
+        tag_assignments = conn.query_row("SELECT COUNT(*) FROM tags", [], |row| row.get::<_, i64>(0))? as usize;
+        link_count = conn.query_row("SELECT COUNT(*) FROM links", [], |row| row.get::<_, i64>(0))? as usize;
+        last_indexed = conn.query_row("SELECT MAX(indexed_at) FROM collections", [], |row| row.get(0))?;
     }
 
-    let mut results = Vec::new();
-    for t in targets {
-        // reuse get_note by calling searcher directly
-        let index = Index::open_in_dir(index_dir)?;
-        let reader = index.reader()?;
-        let searcher = reader.searcher();
-        let schema = index.schema();
-        let lookup = resolve_doc_id(&t);
-        let term = if lookup.is_doc_id {
-            Term::from_field_text(schema.get_field("doc_id").unwrap(), &lookup.value)
-        } else {
-            Term::from_field_text(schema.get_field("path").unwrap(), &lookup.value)
-        };
-        let doc_opt: Option<TantivyDocument> = searcher
-            .search(&tantivy::query::TermQuery::new(term, tantivy::schema::IndexRecordOption::Basic), &TopDocs::with_limit(1))?
-            .into_iter()
-            .next()
-            .map(|(_, addr)| searcher.doc(addr))
-            .transpose()?;
-        if let Some(doc) = doc_opt {
-            if let Some(name) = collection.as_ref() {
-                let coll = doc
-                    .get_first(schema.get_field("collection").unwrap())
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("");
-                if coll != name { continue; }
+    let mut embeddings = serde_json::Value::Null;
+    if embeddings_db.exists() {
+        let conn = open_sqlite(&embeddings_db)?;
+        let has_meta = conn
+            .query_row("SELECT 1 FROM sqlite_master WHERE name = 'meta'", [], |_| Ok(()))
+            .is_ok();
+        let meta = |key: &str| if has_meta { get_meta(&conn, key) } else { None };
+        let mut total_chunks = 0;
+        let mut stmt = conn.prepare("SELECT collection, COUNT(*) FROM chunks GROUP BY collection")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as usize)))?;
+        for (name, n) in rows.flatten() {
+            total_chunks += n;
+            match collections.iter_mut().find(|c| c.name == name) {
+                Some(c) => c.chunks = n,
+                None => collections.push(CollectionStats { name, root: None, notes: 0, chunks: n, changed_since_index: None, indexed_at: None }),
             }
-            let path = doc.get_first(schema.get_field("path").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let title = doc.get_first(schema.get_field("title").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
-            let doc_id = doc.get_first(schema.get_field("doc_id").unwrap()).and_then(|v| v.as_str()).unwrap_or("").to_string();
-            results.push(json!({"path": path, "title": title, "doc_id": doc_id}));
         }
+        let notes: i64 = conn.query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))?;
+        embeddings = json!({
+            "notes": notes,
+            "chunks": total_chunks,
+            "embed_backend": meta("embed_backend"),
+            "embed_model": meta("embed_model").filter(|m| !m.is_empty()),
+            "vector_backend": meta("vector_backend"),
+            "dims": meta("dims").and_then(|d| d.parse::<usize>().ok()),
+            "vector_encoding": meta("vector_encoding").unwrap_or_else(|| "json".to_string()),
+            "hnsw": Hnsw::load(&hnsw_path(index_dir)).ok().flatten().map(|g| json!({
+                "nodes": g.len(),
+                "tombstones": g.deleted,
+                "m": g.opts.m,
+                "ef_construction": g.opts.ef_construction,
+                "ef": g.opts.ef,
+            })),
+            "indexed_at": meta("indexed_at").and_then(|t| t.parse::<i64>().ok()),
+        });
     }
 
+    let changed: usize = collections.iter().filter_map(|c| c.changed_since_index).sum();
+    let analyzer = Analyzer::from_schema(&index.schema());
+    let languages = read_manifest(Path::new(index_dir)).map(|m| m.languages).unwrap_or_default();
+    let sizes = json!({
+        "tantivy": tantivy_dir_size(Path::new(index_dir)),
+        "catalog": sqlite_file_size(&catalog_db),
+        "embeddings": sqlite_file_size(&embeddings_db),
+    });
+
+    let out = json_response(json!({
+        "documents": num_docs,
+        "deleted_documents": deleted_docs,
+        "segments": segments,
+        "analyzer": analyzer.name(),
+        "languages": languages,
+        "collections": collections,
+        "changed_since_index": changed,
+        "tags": tag_count,
+        "tag_assignments": tag_assignments,
+        "links": link_count,
+        "embeddings": embeddings,
+        "bytes": sizes,
+        "last_indexed": last_indexed.and_then(|t| chrono::DateTime::from_timestamp(t, 0)).map(|t| t.to_rfc3339()),
+    }));
+
     if json_out {
-        let out = json_response(json!({"results": results}));
         println!("{out}");
     } else {
-        for r in results {
-            println!("{}", r);
+        println!("documents\t{num_docs}");
+        println!("segments\t{segments}");
+        println!("analyzer\t{}", analyzer.name());
+        if !languages.is_empty() {
+            let langs: Vec<String> = languages.iter().map(|(code, n)| format!("{code} {n}")).collect();
+            println!("languages\t{}", langs.join("\t"));
         }
-    }
-    Ok(())
-}
-
-
-fn mcp_server() -> Result<()> {
-    use std::io::{self, BufRead};
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-    let reader = stdin.lock();
-
-    for line in reader.lines() {
-        let line = line?;
-        if line.trim().is_empty() { continue; }
-        let v: serde_json::Value = match serde_json::from_str(&line) {
-            Ok(v) => v,
-            Err(e) => {
-                let err = json_response(json!({"error": {"code": "bad_json", "message": e.to_string()}}));
-                writeln!(stdout, "{}", err)?;
-                continue;
-            }
-        };
-        let tool = v.get("tool").and_then(|t| t.as_str()).unwrap_or("");
-        let args = v.get("args").cloned().unwrap_or(json!({}));
-
-        let result = match tool {
-            "search" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
-                let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
-                let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
-                let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
-                let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
-                search_index(index, query, limit, true, collection, min_score, files, all)
-            }
-            "vector" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(10) as usize;
-                let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
-                let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
-                let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
-                let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
-                let vector_backend = match args.get("vector_backend").and_then(|v| v.as_str()) {
-                    Some("vss") => VectorBackend::Vss,
-                    _ => VectorBackend::Bruteforce,
-                };
-                embed_search(index, query, limit, true, collection, min_score, files, all, EmbeddingBackend::Hash, None, vector_backend)
-            }
-            "hybrid" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                let query = args.get("query").and_then(|v| v.as_str()).unwrap_or("");
-                let limit = args.get("limit").and_then(|v| v.as_u64()).unwrap_or(20) as usize;
-                let rrf_k = args.get("rrf_k").and_then(|v| v.as_u64()).unwrap_or(60) as u32;
-                let bm25_limit = args.get("bm25_limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
-                let vec_limit = args.get("vec_limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
-                let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
-                let min_score = args.get("min_score").and_then(|v| v.as_f64()).unwrap_or(0.0) as f32;
-                let files = args.get("files").and_then(|v| v.as_bool()).unwrap_or(false);
-                let all = args.get("all").and_then(|v| v.as_bool()).unwrap_or(false);
-                let expand = args.get("expand").and_then(|v| v.as_u64()).unwrap_or(2) as u32;
-                let vector_backend = match args.get("vector_backend").and_then(|v| v.as_str()) {
-                    Some("vss") => VectorBackend::Vss,
-                    _ => VectorBackend::Bruteforce,
-                };
-                hybrid_search(index, query, limit, rrf_k, bm25_limit, vec_limit, true, collection, min_score, files, all, expand, EmbeddingBackend::Hash, None, vector_backend)
-            }
-            "get" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                let path = args.get("path").and_then(|v| v.as_str()).unwrap_or("");
-                let content = args.get("content").and_then(|v| v.as_bool()).unwrap_or(false);
-                let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
-                get_note(index, path, true, content, collection)
-            }
-            "multi_get" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                let paths = args.get("paths").and_then(|v| v.as_str()).map(|s| s.to_string());
-                let glob_pat = args.get("glob").and_then(|v| v.as_str()).map(|s| s.to_string());
-                let collection = args.get("collection").and_then(|v| v.as_str()).map(|s| s.to_string());
-                multi_get(index, paths, glob_pat, true, collection)
-            }
-            "status" => {
-                let index = args.get("index").and_then(|v| v.as_str()).unwrap_or("./.obsidx");
-                stats(index, true)
-            }
-            _ => Err(anyhow::anyhow!("Unknown tool: {}", tool)),
-        };
-
-        if let Err(e) = result {
-            let err = json_response(json!({"error": {"code": "exception", "message": e.to_string()}}));
-            writeln!(stdout, "{}", err)?;
+        for c in &collections {
+            println!("collection\t{}\tnotes {}\tchunks {}\tchanged {}", c.name, c.notes, c.chunks, c.changed_since_index.map(|n| n.to_string()).unwrap_or_else(|| "?".to_string()));
         }
+        println!("tags\t{tag_count}");
+        println!("links\t{link_count}");
+        println!("bytes\ttantivy {}\tcatalog {}\tembeddings {}", sizes["tantivy"], sizes["catalog"], sizes["embeddings"]);
     }
-
     Ok(())
 }
 
-fn stats(index_dir: &str, json_out: bool) -> Result<()> {
-    let index = Index::open_in_dir(index_dir)
-        .with_context(|| format!("Index not found: {index_dir}"))?;
-    let reader: IndexReader = index.reader()?;
-    let searcher = reader.searcher();
+/// Notes added, modified or removed under `root` since the catalog was written.
+fn count_changed_notes(conn: &Connection, collection: &str, root: &Path) -> Result<usize> {
+    let mut indexed: HashMap<String, i64> = conn
+        .prepare("SELECT path, mtime FROM notes WHERE collection = ?1")?
+        .query_map(params![collection], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .filter_map(|r| r.ok())
+        .collect();
+    let mut changed = 0;
+    for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("md") {
+            let mtime = fs::metadata(path).map(|m| file_mtime(&m)).unwrap_or(0);
+            match indexed.remove(path.to_string_lossy().as_ref()) {
+                Some(old) if old >= mtime => {}
+                _ => changed += 1,
+            }
+        }
+    }
+    Ok(changed + indexed.len())
+}
 
-    let num_docs = searcher.num_docs();
-    let out = json_response(json!({ "documents": num_docs }));
+fn tantivy_dir_size(index_dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(index_dir) else { return 0 };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            !name.contains(".db") && !name.ends_with(".lock") && !name.starts_with(REINDEX_QUEUE_FILE)
+        })
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum()
+}
 
-    if json_out {
-        println!("{out}");
-    } else {
-        println!("{num_docs}");
-    }
-    Ok(())
+/// Size of a SQLite database including its WAL and shared-memory files.
+fn sqlite_file_size(path: &Path) -> u64 {
+    ["", "-wal", "-shm"]
+        .iter()
+        .filter_map(|suffix| fs::metadata(format!("{}{suffix}", path.display())).ok())
+        .map(|m| m.len())
+        .sum()
 }
 
 struct SchemaFields {
@@ -1594,57 +6791,176 @@ struct SchemaFields {
     headings: Field,
     frontmatter: Field,
     mtime: Field,
+    heading: Field,
+    path_text: Field,
+    tag: Field,
+    alias: Field,
+    code: Field,
+    facets: Field,
 }
 
-fn schema_fields(index: &Index) -> SchemaFields {
+fn schema_fields(index: &Index) -> Result<SchemaFields> {
     let schema = index.schema();
-    SchemaFields {
-        path: schema.get_field("path").unwrap(),
-        collection: schema.get_field("collection").unwrap(),
-        doc_id: schema.get_field("doc_id").unwrap(),
-        title: schema.get_field("title").unwrap(),
-        content: schema.get_field("content").unwrap(),
-        tags: schema.get_field("tags").unwrap(),
-        links: schema.get_field("links").unwrap(),
-        links_term: schema.get_field("links_term").unwrap(),
-        headings: schema.get_field("headings").unwrap(),
-        frontmatter: schema.get_field("frontmatter").unwrap(),
-        mtime: schema.get_field("mtime").unwrap(),
-    }
-}
-
-fn scan_vault(vault: &Path, collection_name: &str) -> Result<Vec<NoteDoc>> {
-    let mut docs = Vec::new();
-    for entry in WalkDir::new(vault).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("md") {
-            let content = fs::read_to_string(path)
-                .with_context(|| format!("Failed reading: {}", path.display()))?;
-            let meta = fs::metadata(path)?;
-            let mtime = meta
-                .modified()
-                .ok()
-                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                .map(|d| d.as_secs() as i64)
-                .unwrap_or(0);
-            let parsed = parse_note(path, &content);
-            let full_path = path.to_string_lossy().to_string();
-            let doc_id = hash_str(&full_path);
-            docs.push(NoteDoc {
-                path: full_path,
-                collection: collection_name.to_string(),
-                doc_id,
-                title: parsed.title,
-                content: parsed.content,
-                tags: parsed.tags,
-                links: parsed.links,
-                headings: parsed.headings,
-                frontmatter_json: parsed.frontmatter_json,
-                mtime,
-            });
+    // Indexes built by older versions lack newer fields; reads must not panic on them.
+    let field = |name: &str| {
+        schema.get_field(name).map_err(|_| ObsidxError::SchemaOutdated { field: name.to_string() })
+    };
+    Ok(SchemaFields {
+        path: field("path")?,
+        collection: field("collection")?,
+        doc_id: field("doc_id")?,
+        title: field("title")?,
+        content: field("content")?,
+        tags: field("tags")?,
+        links: field("links")?,
+        links_term: field("links_term")?,
+        headings: field("headings")?,
+        frontmatter: field("frontmatter")?,
+        mtime: field("mtime")?,
+        heading: field("heading")?,
+        path_text: field("path_text")?,
+        tag: field("tag")?,
+        alias: field("alias")?,
+        code: field("code")?,
+        facets: field("facets")?,
+    })
+}
+
+/// Called with the number of notes handed to the sink so far, and `true` once
+/// the scan has finished.
+type ScanProgress = fn(usize, bool);
+
+#[derive(Debug, Clone, Default)]
+struct ScanOptions {
+    /// Parser threads; 0 uses all available cores.
+    jobs: usize,
+    progress: Option<ScanProgress>,
+}
+
+impl ScanOptions {
+    /// Options for the CLI, where `--progress` reports on stderr.
+    fn cli(jobs: usize, progress: bool) -> Self {
+        Self { jobs, progress: progress.then_some(report_scan_progress as ScanProgress) }
+    }
+
+    fn worker_count(&self) -> usize {
+        if self.jobs > 0 {
+            self.jobs
+        } else {
+            std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
         }
     }
-    Ok(docs)
+}
+
+const SCAN_PROGRESS_EVERY: usize = 1000;
+
+fn report_scan_progress(scanned: usize, done: bool) {
+    if done || scanned.is_multiple_of(SCAN_PROGRESS_EVERY) {
+        eprintln!("scanned {scanned} notes");
+    }
+}
+
+/// Walks `vault` and hands every parsed note to `sink` in walk order.
+///
+/// Reading and parsing run on `opts.jobs` worker threads; the walker may only run a
+/// fixed window ahead of `sink`, so memory stays flat regardless of vault size.
+/// Notes are re-sequenced before `sink` sees them, so the result matches a
+/// single-threaded scan exactly.
+fn scan_vault<F>(vault: &Path, collection_name: &str, opts: &ScanOptions, mut sink: F) -> Result<usize>
+where
+    F: FnMut(NoteDoc) -> Result<()>,
+{
+    use std::collections::BTreeMap;
+    use std::sync::mpsc::sync_channel;
+    use std::sync::{Arc, Mutex};
+
+    let workers = opts.worker_count();
+    let window = workers * 16;
+    let (path_tx, path_rx) = sync_channel::<(usize, PathBuf)>(window);
+    let (doc_tx, doc_rx) = sync_channel::<(usize, Result<NoteDoc>)>(window);
+    let (slot_tx, slot_rx) = sync_channel::<()>(window);
+    let path_rx = Arc::new(Mutex::new(path_rx));
+
+    std::thread::scope(|scope| -> Result<usize> {
+        let walker_root = vault.to_path_buf();
+        scope.spawn(move || {
+            let mut seq = 0;
+            for entry in WalkDir::new(&walker_root).into_iter().filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("md") {
+                    if slot_tx.send(()).is_err() || path_tx.send((seq, path.to_path_buf())).is_err() {
+                        return;
+                    }
+                    seq += 1;
+                }
+            }
+        });
+
+        for _ in 0..workers {
+            let path_rx = Arc::clone(&path_rx);
+            let doc_tx = doc_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let next = path_rx.lock().unwrap().recv();
+                    let Ok((seq, path)) = next else { break };
+                    if doc_tx.send((seq, read_note(&path, collection_name))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(doc_tx);
+
+        // Everything below runs on the calling thread, which owns the index writers.
+        let doc_rx = doc_rx;
+        let slot_rx = slot_rx;
+        let mut pending: BTreeMap<usize, Result<NoteDoc>> = BTreeMap::new();
+        let mut next_seq = 0;
+        for (seq, doc) in doc_rx.iter() {
+            pending.insert(seq, doc);
+            while let Some(doc) = pending.remove(&next_seq) {
+                sink(doc?)?;
+                next_seq += 1;
+                let _ = slot_rx.recv();
+                if let Some(report) = opts.progress {
+                    report(next_seq, false);
+                }
+            }
+        }
+        if let Some(report) = opts.progress {
+            report(next_seq, true);
+        }
+        Ok(next_seq)
+    })
+}
+
+fn file_mtime(meta: &fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn read_note(path: &Path, collection_name: &str) -> Result<NoteDoc> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed reading: {}", path.display()))?;
+    let mtime = file_mtime(&fs::metadata(path)?);
+    let parsed = parse_note(path, &content);
+    let full_path = path.to_string_lossy().to_string();
+    let doc_id = hash_str(&full_path);
+    Ok(NoteDoc {
+        path: full_path,
+        collection: collection_name.to_string(),
+        doc_id,
+        title: parsed.title,
+        content: parsed.content,
+        tags: parsed.tags,
+        links: parsed.links,
+        headings: parsed.headings,
+        frontmatter_json: parsed.frontmatter_json,
+        mtime,
+    })
 }
 
 struct ParsedNote {
@@ -1700,12 +7016,12 @@ fn parse_note(path: &Path, raw: &str) -> ParsedNote {
 }
 
 fn extract_frontmatter(raw: &str) -> (Option<String>, String) {
-    if raw.starts_with("---\n") {
-        if let Some(end) = raw[4..].find("\n---") {
-            let fm = &raw[4..4 + end];
-            let rest = &raw[4 + end + 4..];
-            return (Some(fm.to_string()), rest.trim_start().to_string());
-        }
+    if let Some(stripped) = raw.strip_prefix("---\n")
+        && let Some(end) = stripped.find("\n---")
+    {
+        let fm = &stripped[..end];
+        let rest = &stripped[end + 4..];
+        return (Some(fm.to_string()), rest.trim_start().to_string());
     }
     (None, raw.to_string())
 }
@@ -1752,10 +7068,8 @@ fn extract_headings_and_links(body: &str) -> (Vec<String>, Vec<String>) {
                 }
                 in_heading = false;
             }
-            Event::Text(t) => {
-                if in_heading {
-                    heading_text.push_str(&t);
-                }
+            Event::Text(t) if in_heading => {
+                heading_text.push_str(&t);
             }
             Event::Start(Tag::Link { dest_url, .. }) => {
                 links.push(dest_url.to_string());
@@ -1796,12 +7110,15 @@ fn print_schema(pretty: bool) -> Result<()> {
             "data": "object"
         },
         "commands": {
-            "search": {"data": {"query": "string", "results": [{"path": "string", "title": "string", "score": "float"}] }},
+            "search": {"data": {"query": "string", "did_you_mean": "string? (only when total_hits is 0)", "query_issues?": [{"message": "string", "position": "int?"}], "results": [{"path": "string", "title": "string", "score": "float", "doc_id": "string", "snippet": "string?", "heading": "string?"}], "facets?": {"tags|folder|collection|year": [{"value": "string", "count": "int"}]}, "total_hits": "int", "offset": "int", "truncated": "bool", "next_offset": "int|null"}},
+            "hybrid": {"data": {"query": "string", "query_issues?": [{"message": "string", "position": "int?"}], "results": [{"path": "string", "score": "float", "snippet": "string?", "heading": "string?"}], "facets?": {"tags|folder|collection|year": [{"value": "string", "count": "int"}]}, "total_hits": "int", "offset": "int", "truncated": "bool", "next_offset": "int|null"}},
             "get": {"data": {"path": "string", "title": "string", "tags": ["string"], "headings": ["string"], "links": ["string"], "frontmatter": "object", "mtime": "int", "content": "string"}},
             "tags": {"data": {"results": [{"tag": "string", "count": "int"}]}},
             "links": {"data": {"from": "string", "links": ["string"]}},
             "backlinks": {"data": {"to": "string", "backlinks": ["string"]}},
-            "stats": {"data": {"documents": "int"}},
+            "grep": {"data": {"pattern": "string", "results": [{"path": "string", "title": "string", "matches": [{"line": "int", "text": "string", "before": ["string"], "after": ["string"]}]}], "notes_scanned": "int", "total_matches": "int", "truncated": "bool"}},
+            "related": {"data": {"path": "string", "results": [{"path": "string", "title": "string", "score": "float", "bm25_rank": "int?", "vector_rank": "int?"}]}},
+            "stats": {"data": {"documents": "int", "deleted_documents": "int", "segments": "int", "collections": [{"name": "string", "root": "string", "notes": "int", "chunks": "int", "changed_since_index": "int", "indexed_at": "int"}], "changed_since_index": "int", "tags": "int", "tag_assignments": "int", "links": "int", "embeddings": {"notes": "int", "chunks": "int", "embed_backend": "string", "embed_model": "string", "vector_backend": "string", "dims": "int", "vector_encoding": "f32|f16|int8|json", "hnsw": "{nodes, tombstones, m, ef_construction, ef}|null", "indexed_at": "int"}, "bytes": {"tantivy": "int", "catalog": "int", "embeddings": "int"}, "last_indexed": "RFC3339 string"}},
             "note_create": {"data": {"message": "string", "path": "string", "reindexed": "bool"}},
             "note_append": {"data": {"message": "string", "path": "string", "reindexed": "bool"}},
             "init/index": {"data": {"message": "string", "vault": "string", "index": "string", "documents": "int"}}
@@ -1817,22 +7134,1277 @@ fn print_tool_spec(pretty: bool) -> Result<()> {
         "name": "obsidx",
         "description": "Local Obsidian vault indexer with JSON output. Composable CLI for LLM tools.",
         "commands": [
-            {"name": "init", "args": "--vault <path> --index <path>", "json": true},
-            {"name": "index", "args": "--vault <path> --index <path> [--incremental]", "json": true},
-            {"name": "search", "args": "--index <path> --query <q> --limit 20 --json", "json": true},
+            {"name": "init", "args": "--vault <path> --index <path> [--analyzer <name>]", "json": true},
+            {"name": "index", "args": "--vault <path> --index <path> [--incremental] [--analyzer <name>]", "json": true},
+            {"name": "search", "args": "--index <path> --query <q> --limit 20 --json [--snippet-len 160] [--highlight-pre '**'] [--highlight-post '**'] [--boost title=2,heading=1.5] [--fuzzy 0-2] [--prefix] [--lenient] [--facets tags,folder,collection,year] [--sort relevance|mtime|title|path] [--offset <n>] [--explain] [--folder <prefix>|--folder '!<prefix>']...", "json": true},
             {"name": "get", "args": "--index <path> --path <note.md> --json [--content]", "json": true},
-            {"name": "tags", "args": "--index <path> --json", "json": true},
+            {"name": "tags", "args": "--index <path> --json [--folder <prefix>]...", "json": true},
             {"name": "links", "args": "--index <path> --from <note.md> --json", "json": true},
-            {"name": "backlinks", "args": "--index <path> --to <note.md> --json", "json": true},
-            {"name": "watch", "args": "--vault <path> --index <path> --debounce-ms 500", "json": false},
+            {"name": "backlinks", "args": "--index <path> --to <note.md> --json [--folder <prefix>]...", "json": true},
+            {"name": "grep", "args": "--index <path> --pattern <regex> --json [--fixed-strings] [--ignore-case] [--context <n>] [--limit 200] [--files] [--collection <name>] [--folder <prefix>]...", "json": true},
+            {"name": "related", "args": "--index <path> --path <note.md|#doc_id> --json [--limit 10] [--rrf-k 60] [--exclude-linked] [--vector-mode centroid|max-sim] [--collection <name>] [--folder <prefix>]...", "json": true},
+            {"name": "watch", "args": "--vault <path> --index <path> --debounce-ms 500 [--optimize-idle-secs <n>]", "json": false},
             {"name": "note-create", "args": "--vault <path> --path <rel.md> [--content <text>|--stdin] [--reindex]", "json": true},
             {"name": "note-append", "args": "--vault <path> --path <rel.md> [--content <text>|--stdin] [--reindex]", "json": true},
-            {"name": "stats", "args": "--index <path> --json", "json": true}
+            {"name": "stats", "args": "--index <path> --json", "json": true},
+            {"name": "verify", "args": "--vault <path> --index <path> [--collection <name>] [--repair] --json", "json": true},
+            {"name": "optimize", "args": "--index <path> --json", "json": true},
+            {"name": "export", "args": "--index <path> [--output <file.jsonl>]", "json": false},
+            {"name": "import", "args": "--index <path> [--input <file.jsonl>] [--vault <path>]", "json": true}
         ],
+        "query_syntax": {
+            "fields": {"title": "note title", "heading": "any heading (alias: headings)", "path": "words in the note path", "tag": "exact tag, case-insensitive (alias: tags)", "link": "words in link targets (alias: links)", "alias": "frontmatter aliases (alias: aliases)", "content": "note body", "code": "fenced code blocks and inline code"},
+            "default_fields": ["title", "content", "tags"],
+            "grammar": "term, \"phrase\", field:term, field:\"phrase\", +must, -must_not, AND/OR/NOT, (grouping)",
+            "examples": ["tag:project heading:roadmap", "path:daily -tag:draft", "alias:\"alpha note\"", "links:b AND title:plan"],
+            "boosts": "--boost title=2,heading=1.5 or [boosts] in ~/.obsidx/config.toml; MCP search/hybrid accept args.boost as an object or the same string",
+            "identifiers": "content and code index parseConfigFile, max_retry_count, retry-count and k8s.io/api whole and as their parts, so \"retry count\" and config match",
+            "typos": "--fuzzy <0-2> matches terms within that edit distance; --prefix matches word prefixes (MCP: args.fuzzy, args.prefix). Zero-hit searches return data.did_you_mean from the index vocabulary.",
+            "leniency": "CLI search/hybrid are strict: bad syntax fails with error code query_syntax and a character position. --lenient (the MCP default; args.lenient=false for strict) blanks out stray quotes, colons, parentheses and dangling +/- and reports each in data.query_issues[{message, position}]."
+        },
+        "analyzers": "--analyzer on init/index (or analyzer in ~/.obsidx/config.toml): default, english and other Snowball languages (stopwords + stemming), cjk (bigrams for Chinese/Japanese/Korean), auto (per-note language detection, falling back to auto_fallback, default english). Changing it rebuilds the index; the choice and detected languages are in <index>/manifest.json and stats.",
+        "folders": "--folder <prefix> (repeatable) keeps notes under that folder, relative to each collection root or absolute; '!<prefix>' excludes. Supported by search, embed-search, hybrid, tags, multi-get and backlinks (MCP: args.folder as string or array).",
+        "explain": "--explain (MCP: args.explain) adds results[].explain: search gives tantivy's BM25 Explanation tree; embed-search the metric, similarity and chunk rank; hybrid the original-query bm25/vector ranks, every expanded query and signal's RRF contribution, original_query_bonus and the fused score.",
+        "embeddings": "--embed-backend hash (default, placeholder), candle --embed-model <dir> (local BERT-family sentence-transformer: config.json, tokenizer.json, model.safetensors; mean-pooled, normalized, dims from the model), http ([embedding.http] url of an OpenAI/Ollama-compatible endpoint) or command ([embedding.command] program: {model, texts} JSON on stdin, {embeddings} on stdout); http/command take model, timeout_secs, retries and batch_size in ~/.obsidx/config.toml, --embed-model overrides model. Vectors are f32 BLOBs; embed-index --vector-encoding f16|int8 shrinks them (full rebuild to switch); JSON-text databases convert on the next embed-index (reads fail until then; malformed rows are dropped, reported on stderr and their notes re-embedded). MCP vector/hybrid default to the backend and vector backend the index was embedded with (args.embed_backend, args.embed_model, args.vector_backend override; unknown names fail that call). dims: embed-index --embed-dims <n> or [embedding] dims (hash uses it, other backends must match; default 256 for hash, else the model's), recorded per index and kept by --incremental; mismatched inserts, query embeddings or query backends fail.",
+        "vector_backends": "--vector-backend bruteforce (default: exact cosine over every chunk), vss (sqlite-vss extension, see docs/INSTALL-vss.md), vec (sqlite-vec extension from OBSIDX_VEC0 or [extensions] sqlite_vec; vec0 table sized to the index's dims, --collection and a single --folder filtered inside the KNN query; see docs/INSTALL-vec.md) or hnsw (built-in approximate graph in <index>/hnsw.bin; embed-index --hnsw-m 16 --hnsw-ef-construction 200 --hnsw-ef 64 tune it, --hnsw-ef on embed-search/hybrid or MCP args.hnsw_ef overrides the search breadth, widened to the number of results requested). Any embed-index run keeps an existing graph in sync and drops the nodes of deleted notes; verify reports its recall@10 against brute force and repairs it; optimize drops deleted nodes.",
+        "paging": "search, embed-search and hybrid return total_hits, offset, truncated and next_offset; pass next_offset back as --offset (same --sort) for the next page. Offsets are not stable: changes to the index between requests can shift or repeat hits. --all returns up to 10000 results per page.",
         "output_contract": "All --json commands return {version, timestamp, data} with stable schemas.",
-        "errors": "On failure, return data.error = {code, message} where possible."
+        "errors": "On failure, return data.error = {code, message} where possible. Codes: index_locked (another writer holds the index; retry or raise OBSIDX_LOCK_TIMEOUT_MS), query_syntax (strict query parsing failed; error.position and error.query locate it), exception."
     });
     let out = if pretty { serde_json::to_string_pretty(&spec)? } else { serde_json::to_string(&spec)? };
     println!("{out}");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::Document;
+
+    fn write_fixture_vault(root: &Path) {
+        for i in 0..300 {
+            let dir = root.join(format!("area{}", i % 5)).join(format!("sub{}", i % 3));
+            fs::create_dir_all(&dir).unwrap();
+            let body = format!(
+                "---\ntitle: Note {i}\nstatus: s{}\ntags: [fixture, t{}]\n---\n# Heading {i}\n\nLinks to [[note{}]] and [[note{}|alias]]. #inline{}\n\nSome body text about topic {}.\n",
+                i % 4,
+                i % 7,
+                (i + 1) % 300,
+                (i * 7) % 300,
+                i % 11,
+                i % 13,
+            );
+            fs::write(dir.join(format!("note{i}.md")), body).unwrap();
+        }
+    }
+
+    fn catalog_rows(index_dir: &str) -> Vec<String> {
+        let conn = open_catalog(index_dir).unwrap();
+        let queries = [
+            "SELECT path, doc_id, collection, title, mtime, language FROM notes",
+            "SELECT source, target FROM links",
+            "SELECT path, tag FROM tags",
+            "SELECT path, key, value FROM properties",
+        ];
+        let mut rows = Vec::new();
+        for sql in queries {
+            let mut stmt = conn.prepare(sql).unwrap();
+            let cols = stmt.column_count();
+            let mapped = stmt
+                .query_map([], |row| {
+                    let values: Vec<String> = (0..cols)
+                        .map(|c| format!("{:?}", row.get::<_, rusqlite::types::Value>(c).unwrap()))
+                        .collect();
+                    Ok(format!("{sql}: {}", values.join("|")))
+                })
+                .unwrap();
+            rows.extend(mapped.map(|r| r.unwrap()));
+        }
+        rows.sort();
+        rows
+    }
+
+    fn tantivy_docs(index_dir: &str) -> Vec<String> {
+        let index = Index::open_in_dir(index_dir).unwrap();
+        let schema = index.schema();
+        let searcher = index.reader().unwrap().searcher();
+        let mut docs = Vec::new();
+        for segment in searcher.segment_readers() {
+            let store = segment.get_store_reader(16).unwrap();
+            for doc_id in segment.doc_ids_alive() {
+                let doc: TantivyDocument = store.get(doc_id).unwrap();
+                docs.push(doc.to_json(&schema));
+            }
+        }
+        docs.sort();
+        docs
+    }
+
+    #[test]
+    fn parallel_scan_matches_sequential() {
+        let vault = tempfile::tempdir().unwrap();
+        write_fixture_vault(vault.path());
+        let vault_str = vault.path().to_str().unwrap();
+
+        let sequential = tempfile::tempdir().unwrap();
+        let parallel = tempfile::tempdir().unwrap();
+        let seq_dir = sequential.path().join("index");
+        let par_dir = parallel.path().join("index");
+        let seq_dir = seq_dir.to_str().unwrap();
+        let par_dir = par_dir.to_str().unwrap();
+
+        build_index(vault_str, seq_dir, false, None, None, &ScanOptions { jobs: 1, progress: None }).unwrap();
+        build_index(vault_str, par_dir, false, None, None, &ScanOptions { jobs: 8, progress: None }).unwrap();
+
+        let seq_rows = catalog_rows(seq_dir);
+        assert!(seq_rows.len() > 300);
+        assert_eq!(seq_rows, catalog_rows(par_dir));
+
+        let seq_docs = tantivy_docs(seq_dir);
+        assert_eq!(seq_docs.len(), 300);
+        assert_eq!(seq_docs, tantivy_docs(par_dir));
+    }
+
+    const SMALL_VAULT: &[(&str, &str)] = &[
+        (
+            "rust.md",
+            "---\nstatus: draft\ntags: [lang]\n---\n# Rust Ownership\n\nBorrowing and lifetimes keep memory safe. See [[python]].\n\n## Traits\n\nTraits describe shared behaviour. #systems\n",
+        ),
+        ("python.md", "# Python Scripting\n\nDynamic typing and quick scripts. Compare with [[rust]].\n"),
+        ("projects/alpha.md", "---\nstatus: active\n---\n# Alpha Project\n\nShip the alpha milestone. #project\n"),
+        ("projects/beta.md", "# Beta Project\n\nBeta depends on [[alpha]]. #project\n"),
+        ("journal/day.md", "# Daily Journal\n\nWorked on alpha and read about ownership.\n"),
+    ];
+
+    fn write_note(root: &Path, rel: &str, content: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn small_vault() -> tempfile::TempDir {
+        let vault = tempfile::tempdir().unwrap();
+        for (rel, content) in SMALL_VAULT {
+            write_note(vault.path(), rel, content);
+        }
+        vault
+    }
+
+    /// `<tmp>/index`, so the index directory itself starts out missing like a first run.
+    fn index_in(tmp: &tempfile::TempDir) -> String {
+        tmp.path().join("index").to_str().unwrap().to_string()
+    }
+
+    fn index_vault(vault: &Path, index_dir: &str, incremental: bool) {
+        build_index(vault.to_str().unwrap(), index_dir, incremental, None, None, &ScanOptions { jobs: 1, progress: None }).unwrap();
+    }
+
+    /// Moves a file's mtime forward so second-resolution incremental checks see it as changed.
+    fn touch_later(path: &Path) {
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(path).unwrap().set_modified(later).unwrap();
+    }
+
+    fn note_path(vault: &Path, rel: &str) -> String {
+        vault.join(rel).to_string_lossy().to_string()
+    }
+
+    fn catalog_query(index_dir: &str, sql: &str, path: &str) -> Vec<String> {
+        let conn = open_catalog(index_dir).unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut rows: Vec<String> = stmt.query_map(params![path], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+        rows.sort();
+        rows
+    }
+
+    #[test]
+    fn catalog_records_links_tags_and_properties() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+
+        let rust = note_path(vault.path(), "rust.md");
+        assert_eq!(catalog_query(&index_dir, "SELECT target FROM links WHERE source = ?1", &rust), ["python"]);
+        assert_eq!(catalog_query(&index_dir, "SELECT tag FROM tags WHERE path = ?1", &rust), ["lang", "systems"]);
+        assert_eq!(
+            catalog_query(&index_dir, "SELECT key || '=' || value FROM properties WHERE path = ?1", &rust),
+            ["status=\"draft\"", "tags=[\"lang\"]"]
+        );
+        assert_eq!(catalog_query(&index_dir, "SELECT title FROM notes WHERE path = ?1", &rust), ["Rust Ownership"]);
+        let backlinks = catalog_query(&index_dir, "SELECT source FROM links WHERE target = ?1", "alpha");
+        assert_eq!(backlinks, [note_path(vault.path(), "projects/beta.md")]);
+    }
+
+    #[test]
+    fn incremental_index_prunes_deleted_and_updates_changed_notes() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+
+        let beta = note_path(vault.path(), "projects/beta.md");
+        fs::remove_file(&beta).unwrap();
+        let alpha = vault.path().join("projects/alpha.md");
+        fs::write(&alpha, "---\nstatus: done\n---\n# Alpha Project\n\nShipped. #archive\n").unwrap();
+        touch_later(&alpha);
+        index_vault(vault.path(), &index_dir, true);
+
+        for sql in [
+            "SELECT path FROM notes WHERE path = ?1",
+            "SELECT target FROM links WHERE source = ?1",
+            "SELECT tag FROM tags WHERE path = ?1",
+            "SELECT key FROM properties WHERE path = ?1",
+        ] {
+            assert!(catalog_query(&index_dir, sql, &beta).is_empty(), "{sql} still has rows for the deleted note");
+        }
+        let alpha = alpha.to_string_lossy();
+        assert_eq!(catalog_query(&index_dir, "SELECT tag FROM tags WHERE path = ?1", &alpha), ["archive"]);
+        assert_eq!(catalog_query(&index_dir, "SELECT value FROM properties WHERE path = ?1", &alpha), ["\"done\""]);
+        assert_eq!(tantivy_docs(&index_dir).len(), SMALL_VAULT.len() - 1);
+    }
+
+    fn embed_vault(vault: &Path, index_dir: &str, incremental: bool, vector_backend: VectorBackend) {
+        let vault = vault.to_str().unwrap();
+        embed_index(vault, index_dir, 200, 20, incremental, None, EmbeddingBackend::Hash, None, None, vector_backend, None, &HnswOptions::default(), &ScanOptions::default()).unwrap();
+    }
+
+    fn embedded_paths(index_dir: &str) -> Vec<String> {
+        let conn = open_sqlite(&Path::new(index_dir).join("embeddings.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT DISTINCT path FROM chunks ORDER BY path").unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect()
+    }
+
+    fn reindex_request(vault: &str, collection: Option<&str>, max_chars: usize) -> ReindexRequest {
+        ReindexRequest {
+            path: format!("{vault}/note.md"),
+            vault: vault.to_string(),
+            collection: collection.map(str::to_string),
+            max_chars,
+            overlap: 0,
+        }
+    }
+
+    #[test]
+    fn queued_reindex_groups_by_collection() {
+        let reqs = [
+            reindex_request("/v", None, 100),
+            reindex_request("/v", Some("work"), 200),
+            reindex_request("/v", None, 300),
+        ];
+        let groups: Vec<(Option<String>, usize)> = reindex_groups(&reqs).into_iter().map(|r| (r.collection, r.max_chars)).collect();
+        assert_eq!(groups, [(None, 300), (Some("work".to_string()), 200)]);
+    }
+
+    #[test]
+    fn note_collection_uses_innermost_recorded_root() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        let conn = open_catalog(&index_dir).unwrap();
+        for (name, rel) in [("default", ""), ("projects", "projects"), ("alpha", "projects/alpha.md")] {
+            let root = vault.path().join(rel);
+            conn.execute("INSERT INTO collections (name, root, indexed_at) VALUES (?1, ?2, 0)", params![name, root.to_string_lossy()]).unwrap();
+        }
+        assert_eq!(note_collection(&index_dir, &vault.path().join("projects/beta.md")), Some("projects".to_string()));
+        assert_eq!(note_collection(&index_dir, &vault.path().join("projects/alpha.md")), Some("alpha".to_string()));
+        assert_eq!(note_collection(&index_dir, &vault.path().join("rust.md")), None);
+    }
+
+    #[test]
+    fn queued_reindex_indexes_and_embeds_written_notes() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Bruteforce);
+
+        write_note(vault.path(), "inbox/new.md", "# New Note\n\nWritten while watch was running.\n");
+        let new = vault.path().join("inbox/new.md");
+        let req = ReindexRequest { path: new.to_string_lossy().to_string(), vault: vault.path().to_string_lossy().to_string(), collection: None, max_chars: 120, overlap: 10 };
+        run_queued_reindex(&index_dir, &[req]);
+
+        assert_eq!(catalog_query(&index_dir, "SELECT title FROM notes WHERE path = ?1", &new.to_string_lossy()), ["New Note"]);
+        assert!(embedded_paths(&index_dir).contains(&new.to_string_lossy().to_string()));
+        assert_eq!(recorded_chunking(&index_dir), (120, 10));
+    }
+
+    #[test]
+    fn verify_reports_drift_and_repair_fixes_it() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Bruteforce);
+        assert!(verify_report(vault.path(), &index_dir, "default").unwrap().is_clean());
+
+        write_note(vault.path(), "inbox/new.md", "# New\n\nNot indexed yet.\n");
+        fs::remove_file(vault.path().join("python.md")).unwrap();
+        let rust = vault.path().join("rust.md");
+        touch_later(&rust);
+
+        let report = verify_report(vault.path(), &index_dir, "default").unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.vault_notes, SMALL_VAULT.len());
+        assert_eq!(report.tantivy.missing, [note_path(vault.path(), "inbox/new.md")]);
+        assert_eq!(report.tantivy.orphaned, [note_path(vault.path(), "python.md")]);
+        assert_eq!(report.tantivy.stale, [note_path(vault.path(), "rust.md")]);
+        assert_eq!(report.catalog.missing, report.tantivy.missing);
+        let embeddings = report.embeddings.as_ref().unwrap();
+        assert_eq!(embeddings.orphaned, [note_path(vault.path(), "python.md")]);
+
+        repair_index(vault.path().to_str().unwrap(), &index_dir, "default", &report).unwrap();
+        assert!(verify_report(vault.path(), &index_dir, "default").unwrap().is_clean());
+    }
+
+    #[test]
+    fn collection_root_is_recorded_canonical() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        let spelled = vault.path().join("projects").join("..");
+        index_vault(&spelled, &index_dir, false);
+
+        let canonical = fs::canonicalize(vault.path()).unwrap();
+        assert_eq!(collection_roots(&index_dir, Some("default")).unwrap(), vec![canonical.clone()]);
+        let conn = open_catalog(&index_dir).unwrap();
+        let outside: i64 = conn
+            .query_row("SELECT COUNT(*) FROM notes WHERE path NOT LIKE ?1", params![format!("{}/%", canonical.display())], |row| row.get(0))
+            .unwrap();
+        assert_eq!(outside, 0);
+        let changed = count_changed_notes(&conn, "default", &collection_roots(&index_dir, None).unwrap()[0]).unwrap();
+        assert_eq!(changed, 0);
+    }
+
+    #[test]
+    fn optimize_measures_sizes_with_wal_checkpointed() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+
+        // A long-lived connection (like `watch`) that never checkpoints leaves a large WAL.
+        let catalog = catalog_path(&index_dir);
+        let conn = open_sqlite(&catalog).unwrap();
+        conn.execute_batch("PRAGMA wal_autocheckpoint = 0; CREATE TABLE filler (blob BLOB);").unwrap();
+        for _ in 0..200 {
+            conn.execute("INSERT INTO filler VALUES (zeroblob(8192))", []).unwrap();
+        }
+        conn.execute_batch("DROP TABLE filler;").unwrap();
+        let wal = PathBuf::from(format!("{}-wal", catalog.display()));
+        assert!(fs::metadata(&wal).unwrap().len() > 1_000_000);
+
+        let summary = optimize_index(&index_dir).unwrap();
+        let bytes = &summary["bytes"]["catalog"];
+        assert_eq!(fs::metadata(&wal).map(|m| m.len()).unwrap_or(0), 0);
+        assert_eq!(bytes["after"].as_u64().unwrap(), sqlite_file_size(&catalog));
+        assert!(bytes["after"].as_u64().unwrap() < bytes["before"].as_u64().unwrap());
+        assert!(bytes["after"].as_u64().unwrap() < 1_000_000);
+    }
+
+    fn chunk_rows(index_dir: &str) -> Vec<(String, String, String)> {
+        let conn = open_sqlite(&Path::new(index_dir).join("embeddings.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT path, chunk_hash, hex(embedding) FROM chunks ORDER BY path, chunk_hash").unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn export_import_round_trip_into_moved_vault() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Bruteforce);
+        let dump = tmp.path().join("dump.jsonl");
+        export_index(&index_dir, dump.to_str()).unwrap();
+
+        let moved = small_vault();
+        for (rel, _) in SMALL_VAULT {
+            let mtime = fs::metadata(vault.path().join(rel)).unwrap().modified().unwrap();
+            fs::File::options().write(true).open(moved.path().join(rel)).unwrap().set_modified(mtime).unwrap();
+        }
+        let target = tempfile::tempdir().unwrap();
+        let imported = index_in(&target);
+        import_index(&imported, dump.to_str(), moved.path().to_str()).unwrap();
+
+        let rebase = |p: &str| p.replacen(&vault.path().to_string_lossy().to_string(), &moved.path().to_string_lossy(), 1);
+        let expected: Vec<String> = catalog_rows(&index_dir).iter().map(|r| rebase(r)).collect();
+        // doc_id hashes the path, so compare everything else.
+        let strip_ids = |rows: Vec<String>| -> Vec<String> {
+            rows.into_iter()
+                .map(|row| match row.strip_prefix("SELECT path, doc_id, ") {
+                    Some(_) => row.split('|').enumerate().filter(|(i, _)| *i != 1).map(|(_, v)| v).collect::<Vec<_>>().join("|"),
+                    None => row,
+                })
+                .collect()
+        };
+        assert_eq!(strip_ids(catalog_rows(&imported)), strip_ids(expected));
+        let expected_chunks: Vec<_> = chunk_rows(&index_dir).into_iter().map(|(p, h, e)| (rebase(&p), h, e)).collect();
+        assert_eq!(chunk_rows(&imported), expected_chunks);
+        assert!(verify_report(moved.path(), &imported, "default").unwrap().is_clean());
+    }
+
+    /// Indexes `SMALL_VAULT`, keeping both tempdirs alive for the caller.
+    fn small_index() -> (tempfile::TempDir, tempfile::TempDir, String) {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        (vault, tmp, index_dir)
+    }
+
+    #[test]
+    fn snippets_highlight_matches_under_their_heading() {
+        let (vault, _tmp, index_dir) = small_index();
+        let rust = note_path(vault.path(), "rust.md");
+        let python = note_path(vault.path(), "python.md");
+        let opts = SnippetOptions { len: 60, pre: "<b>".to_string(), post: "</b>".to_string() };
+        let snippets = path_snippets(&index_dir, "behaviour", &[rust.clone(), python.clone()], &opts, &QueryOptions::default()).unwrap();
+
+        let (snippet, heading) = &snippets[&rust];
+        let snippet = snippet.as_deref().unwrap();
+        assert!(snippet.contains("<b>behaviour</b>"), "{snippet}");
+        assert!(snippet.chars().count() <= 60 + "<b></b>".len());
+        assert_eq!(heading.as_deref(), Some("Traits"));
+        // A note without the term gets no fragment.
+        assert_eq!(snippets[&python], (None, None));
+
+        let disabled = SnippetOptions { len: 0, ..opts };
+        assert!(path_snippets(&index_dir, "behaviour", &[rust], &disabled, &QueryOptions::default()).unwrap().is_empty());
+    }
+
+    /// Feeds `requests` to the MCP loop and returns the errors it reported, in order.
+    /// Successful calls print their own responses and add nothing here.
+    fn mcp_errors(requests: &[serde_json::Value]) -> Vec<serde_json::Value> {
+        let input: String = requests.iter().map(|r| format!("{r}\n")).collect();
+        let mut out = Vec::new();
+        mcp_serve(input.as_bytes(), &mut out).unwrap();
+        serde_json::Deserializer::from_slice(&out)
+            .into_iter::<serde_json::Value>()
+            .map(|v| v.unwrap()["data"]["error"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn mcp_reports_invalid_query_options_per_request() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let search = |extra: serde_json::Value| {
+            let mut args = json!({"index": index_dir, "query": "alpha"});
+            args.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            json!({"tool": "search", "args": args})
+        };
+        let errors = mcp_errors(&[
+            search(json!({"fuzzy": 3})),
+            search(json!({})),
+            search(json!({"boost": "title=heavy"})),
+            search(json!({"fuzzy": 1, "boost": {"title": 2}})),
+            json!({"tool": "nope"}),
+        ]);
+        let messages: Vec<&str> = errors.iter().map(|e| e["message"].as_str().unwrap()).collect();
+        assert_eq!(messages.len(), 3, "{messages:?}");
+        assert!(messages[0].contains("--fuzzy"), "{}", messages[0]);
+        assert!(messages[1].contains("heavy"), "{}", messages[1]);
+        assert_eq!(messages[2], "Unknown tool: nope");
+    }
+
+    #[test]
+    fn facet_counts_by_tag_and_folder() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let names = parse_facet_names(Some("tags,folder")).unwrap();
+        let facets = facet_counts(&index_dir, "project OR ownership", None, &FolderScope::default(), &QueryOptions::default(), &names).unwrap().unwrap();
+        assert_eq!(facets["tags"], json!([{"value": "project", "count": 2}, {"value": "lang", "count": 1}, {"value": "systems", "count": 1}]));
+        assert_eq!(facets["folder"], json!([{"value": "projects", "count": 2}, {"value": "journal", "count": 1}]));
+    }
+
+    #[test]
+    fn mcp_reports_unknown_facet_per_request() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let errors = mcp_errors(&[
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "facets": ["tags", "colour"]}}),
+            json!({"tool": "hybrid", "args": {"index": index_dir, "query": "alpha", "facets": "colour"}}),
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "facets": ["tags"]}}),
+        ]);
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors.iter().all(|e| e["message"].as_str().unwrap().contains("colour")), "{errors:?}");
+    }
+
+    fn sorted_titles(index_dir: &str, query: &str, sort: SortOrder, want: usize) -> Vec<String> {
+        let index = open_existing_index(index_dir).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let q = parse_user_query(&index, query, &QueryOptions::default()).unwrap();
+        let matched = searcher.search(&q, &tantivy::collector::DocSetCollector).unwrap();
+        let title = index.schema().get_field("title").unwrap();
+        catalog_sorted_hits(index_dir, &searcher, &matched, sort, want)
+            .unwrap()
+            .into_iter()
+            .map(|addr| searcher.doc::<TantivyDocument>(addr).unwrap().get_first(title).unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn title_and_path_sort_use_catalog_keys() {
+        let (vault, _tmp, index_dir) = small_index();
+        let query = "project OR ownership OR python";
+        assert_eq!(sorted_titles(&index_dir, query, SortOrder::Title, 10), ["Alpha Project", "Beta Project", "Daily Journal", "Python Scripting", "Rust Ownership"]);
+        assert_eq!(sorted_titles(&index_dir, query, SortOrder::Path, 2), ["Daily Journal", "Alpha Project"]);
+
+        // A hit the catalog lost still comes back, after the ones it knows.
+        let conn = open_catalog(&index_dir).unwrap();
+        conn.execute("DELETE FROM notes WHERE path = ?1", params![note_path(vault.path(), "projects/alpha.md")]).unwrap();
+        assert_eq!(sorted_titles(&index_dir, query, SortOrder::Title, 10), ["Beta Project", "Daily Journal", "Python Scripting", "Rust Ownership", "Alpha Project"]);
+    }
+
+    #[test]
+    fn mcp_reports_unknown_sort_per_request() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let errors = mcp_errors(&[
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "sort": "colour"}}),
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "sort": "title", "offset": 1}}),
+            json!({"tool": "vector", "args": {"index": index_dir, "query": "alpha", "sort": "random"}}),
+        ]);
+        let messages: Vec<&str> = errors.iter().map(|e| e["message"].as_str().unwrap()).collect();
+        assert_eq!(messages, ["Unknown sort: colour", "Unknown sort: random"]);
+    }
+
+    #[test]
+    fn did_you_mean_corrects_long_words_only() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let index = open_existing_index(&index_dir).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let suggest = |q: &str| did_you_mean(&searcher, &index.schema(), q).unwrap();
+
+        assert_eq!(suggest("b ownrship").as_deref(), Some("b ownership"));
+        assert_eq!(suggest("+alpa -lifetmes").as_deref(), Some("+alpha -lifetimes"));
+        // Short words stay as typed even when a one-letter edit would hit a term.
+        assert_eq!(suggest("b"), None);
+        assert_eq!(suggest("shp"), None);
+        // Known words and words nothing is close to need no rewrite.
+        assert_eq!(suggest("ownership"), None);
+        assert_eq!(suggest("zzzzzzzz"), None);
+    }
+
+    /// Exports `index_dir` and imports it into a fresh index next to the same vault.
+    fn export_reimport(vault: &Path, index_dir: &str, target: &tempfile::TempDir) -> String {
+        let dump = target.path().join("dump.jsonl");
+        export_index(index_dir, dump.to_str()).unwrap();
+        let imported = index_in(target);
+        import_index(&imported, dump.to_str(), vault.to_str()).unwrap();
+        imported
+    }
+
+    #[test]
+    fn export_import_preserves_analyzer() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        let english = Analyzer::Stemmed(Language::English);
+        build_index(vault.path().to_str().unwrap(), &index_dir, false, None, Some(english), &ScanOptions::default()).unwrap();
+
+        let target = tempfile::tempdir().unwrap();
+        let imported = export_reimport(vault.path(), &index_dir, &target);
+        assert_eq!(Analyzer::from_schema(&open_existing_index(&imported).unwrap().schema()), english);
+        // "borrow" only reaches "Borrowing" through the stemmer.
+        let hits = bm25_search(&imported, "borrow", 10, None, &FolderScope::default(), &QueryOptions::default()).unwrap();
+        assert_eq!(hit_paths(&hits, vault.path()), ["rust.md"]);
+    }
+
+    #[test]
+    fn export_import_preserves_auto_fallback_and_languages() {
+        let vault = small_vault();
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        fs::create_dir_all(&index_dir).unwrap();
+        let seeded = IndexManifest { fallback_analyzer: "german".to_string(), ..Default::default() };
+        fs::write(Path::new(&index_dir).join(MANIFEST_FILE), serde_json::to_string(&seeded).unwrap()).unwrap();
+        build_index(vault.path().to_str().unwrap(), &index_dir, false, None, Some(Analyzer::Auto), &ScanOptions::default()).unwrap();
+        let source = read_manifest(Path::new(&index_dir)).unwrap();
+        assert_eq!(source.fallback_analyzer, "german");
+
+        let target = tempfile::tempdir().unwrap();
+        let imported = export_reimport(vault.path(), &index_dir, &target);
+        assert_eq!(Analyzer::from_schema(&open_existing_index(&imported).unwrap().schema()), Analyzer::Auto);
+        let manifest = read_manifest(Path::new(&imported)).unwrap();
+        assert_eq!(manifest.fallback_analyzer, "german");
+        assert_eq!(manifest.languages, source.languages);
+    }
+
+    #[test]
+    fn identifier_parts_split_joiners_camel_case_and_acronyms() {
+        let parts = |ident: &str| -> Vec<String> { identifier_parts(ident).into_iter().map(|(a, b)| ident[a..b].to_string()).collect() };
+        assert_eq!(parts("parseHTTPResponse"), ["parse", "HTTP", "Response"]);
+        assert_eq!(parts("snake_case-name.rs"), ["snake", "case", "name", "rs"]);
+        assert_eq!(parts("sha256sum"), ["sha256sum"]);
+        assert_eq!(parts("plain"), ["plain"]);
+    }
+
+    #[test]
+    fn code_tokens_keep_whole_identifier_and_parts() {
+        let mut analyzer = Analyzer::Default.text_analyzer(Analyzer::Default, true);
+        let mut stream = analyzer.token_stream("call getUserId() then x.");
+        let mut tokens = Vec::new();
+        while stream.advance() {
+            let t = stream.token();
+            tokens.push((t.text.clone(), t.position, t.position_length));
+        }
+        let expected = [("call", 0, 1), ("getuserid", 1, 3), ("get", 1, 1), ("user", 2, 1), ("id", 3, 1), ("then", 4, 1), ("x", 5, 1)];
+        let expected: Vec<(String, usize, usize)> = expected.iter().map(|(t, p, l)| (t.to_string(), *p, *l)).collect();
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn code_identifiers_are_searchable_by_part_and_in_code_field() {
+        let vault = small_vault();
+        write_note(vault.path(), "code.md", "# Loader\n\nThe loader reads settings.\n\n```rust\nfn parse_config_file() {}\n```\n\nCall `HttpServer::bind` first.\n");
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        let search = |q: &str| hit_paths(&bm25_search(&index_dir, q, 10, None, &FolderScope::default(), &QueryOptions::default()).unwrap(), vault.path());
+
+        assert_eq!(search("config"), ["code.md"]);
+        assert_eq!(search("parse_config_file"), ["code.md"]);
+        assert_eq!(search("\"http server\""), ["code.md"]);
+        assert_eq!(search("code:server"), ["code.md"]);
+        // Prose is not code.
+        assert!(search("code:loader").is_empty());
+    }
+
+    #[test]
+    fn hybrid_explain_adds_up_to_the_fused_score() {
+        let mut fusion = RrfFusion::new(60);
+        fusion.add("rust", "bm25", 2.0, [("a.md".to_string(), 3.5), ("b.md".to_string(), 1.0)]);
+        fusion.add("rust", "vector", 2.0, [("b.md".to_string(), 0.9)]);
+        fusion.add("rust lang", "bm25", 1.0, [("b.md".to_string(), 2.0), ("a.md".to_string(), 1.5)]);
+        let scores: HashMap<String, f32> = fusion.scores().into_iter().collect();
+
+        let explain = hybrid_explain(&fusion.contributions["b.md"], "rust", 60, scores["b.md"]);
+        assert_eq!((explain.bm25_rank, explain.vector_rank), (Some(2), Some(1)));
+        let total: f32 = explain.contributions.iter().map(|c| c.rrf).sum();
+        assert!((total - explain.score).abs() < 1e-6);
+        let expected = 2.0 / 62.0 + 2.0 / 61.0 + 1.0 / 61.0;
+        assert!((explain.score - expected).abs() < 1e-6);
+        assert!((explain.original_query_bonus - (1.0 / 62.0 + 1.0 / 61.0)).abs() < 1e-6);
+
+        let explain = hybrid_explain(&fusion.contributions["a.md"], "rust", 60, scores["a.md"]);
+        assert_eq!((explain.bm25_rank, explain.vector_rank), (Some(1), None));
+    }
+
+    #[test]
+    fn bm25_explanation_matches_hit_score() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let index = open_existing_index(&index_dir).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let q = parse_user_query(&index, "project alpha", &QueryOptions::default()).unwrap();
+        let hits = searcher.search(&q, &TopDocs::with_limit(10)).unwrap();
+        assert!(!hits.is_empty());
+        for (score, addr) in hits {
+            let explanation = q.explain(&searcher, addr).unwrap();
+            assert!((explanation.value() - score).abs() < 1e-5);
+            let json = serde_json::to_value(&explanation).unwrap();
+            assert!(json["details"].as_array().is_some_and(|d| !d.is_empty()), "{json}");
+        }
+    }
+
+    #[test]
+    fn related_by_embedding_ranks_near_duplicates_first() {
+        let vault = small_vault();
+        write_note(vault.path(), "archive/rust-copy.md", SMALL_VAULT[0].1);
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Bruteforce);
+        let rust = note_path(vault.path(), "rust.md");
+        let copy = note_path(vault.path(), "archive/rust-copy.md");
+
+        for mode in [RelatedVectorMode::Centroid, RelatedVectorMode::MaxSim] {
+            let ranked = related_by_embedding(&index_dir, &rust, None, &FolderScope::default(), mode).unwrap();
+            assert_eq!(ranked.len(), SMALL_VAULT.len());
+            assert_eq!(ranked[0].0, copy);
+            assert!(ranked.iter().all(|(p, _)| *p != rust));
+            assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+        }
+        let scope = FolderScope::resolve(&index_dir, &["!archive".to_string()]).unwrap();
+        let ranked = related_by_embedding(&index_dir, &rust, None, &scope, RelatedVectorMode::Centroid).unwrap();
+        assert!(ranked.iter().all(|(p, _)| *p != copy));
+    }
+
+    #[test]
+    fn linked_note_paths_resolve_wikilinks_by_name() {
+        let (vault, _tmp, index_dir) = small_index();
+        let linked = |rel: &str| {
+            let mut paths: Vec<String> = linked_note_paths(&index_dir, &note_path(vault.path(), rel)).unwrap().into_iter().collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(linked("projects/beta.md"), [note_path(vault.path(), "projects/alpha.md")]);
+        assert_eq!(linked("rust.md"), [note_path(vault.path(), "python.md")]);
+        assert!(linked("journal/day.md").is_empty());
+    }
+
+    fn hit_paths(hits: &[SearchResult], vault: &Path) -> Vec<String> {
+        let mut paths: Vec<String> = hits.iter().map(|h| h.path.strip_prefix(&format!("{}/", vault.display())).unwrap().to_string()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn folder_scope_includes_and_excludes_prefixes() {
+        let (vault, _tmp, index_dir) = small_index();
+        let query = "project OR ownership";
+        let search = |folders: &[&str]| {
+            let folders: Vec<String> = folders.iter().map(|f| f.to_string()).collect();
+            let scope = FolderScope::resolve(&index_dir, &folders).unwrap();
+            hit_paths(&bm25_search(&index_dir, query, 20, None, &scope, &QueryOptions::default()).unwrap(), vault.path())
+        };
+        assert_eq!(search(&[]), ["journal/day.md", "projects/alpha.md", "projects/beta.md", "rust.md"]);
+        assert_eq!(search(&["projects"]), ["projects/alpha.md", "projects/beta.md"]);
+        assert_eq!(search(&["!projects"]), ["journal/day.md", "rust.md"]);
+        assert_eq!(search(&["projects/", "journal"]), ["journal/day.md", "projects/alpha.md", "projects/beta.md"]);
+        // A sibling sharing the prefix as a string is not inside the folder.
+        assert!(search(&["proj"]).is_empty());
+        assert!(FolderScope::resolve(&index_dir, &["!".to_string()]).is_err());
+    }
+
+    #[test]
+    fn mcp_reports_bad_folder_per_request() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let errors = mcp_errors(&[
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "folder": 7}}),
+            json!({"tool": "multi_get", "args": {"index": index_dir, "glob": "*.md", "folder": ["projects", ""]}}),
+            json!({"tool": "grep", "args": {"index": index_dir, "pattern": "alpha", "folder": ["projects", false]}}),
+            json!({"tool": "search", "args": {"index": index_dir, "query": "alpha", "folder": "projects"}}),
+        ]);
+        let messages: Vec<&str> = errors.iter().map(|e| e["message"].as_str().unwrap()).collect();
+        assert_eq!(
+            messages,
+            ["folder must be a path prefix or an array of them, got 7", "Empty folder prefix: \"\"", "folder must be a path prefix or an array of them, got false"]
+        );
+    }
+
+    #[test]
+    fn required_literals_skip_optional_and_alternated_parts() {
+        assert_eq!(required_literals("ERR-4021 timeout", false), ["err", "4021", "timeout"]);
+        assert_eq!(required_literals("a.b", false), Vec::<String>::new());
+        assert_eq!(required_literals(r"fooBar\d+baz", true), ["foobar", "baz"]);
+        assert_eq!(required_literals("colou?r done", true), ["colo", "done"]);
+        assert_eq!(required_literals("(alpha)+ beta[xyz]gamma", true), ["beta", "gamma"]);
+        assert_eq!(required_literals("items{0,3}left", true), ["item", "left"]);
+        assert_eq!(required_literals("alpha|beta", true), Vec::<String>::new());
+    }
+
+    #[test]
+    fn body_line_offset_skips_frontmatter() {
+        let vault = small_vault();
+        let path = note_path(vault.path(), "rust.md");
+        let body = "# Rust Ownership\n\nBorrowing and lifetimes keep memory safe.";
+        assert_eq!(body_line_offset(&path, body), 4);
+        assert_eq!(body_line_offset(&path, "not in the file"), 0);
+        assert_eq!(body_line_offset(&note_path(vault.path(), "gone.md"), body), 0);
+    }
+
+    #[test]
+    fn mcp_reports_invalid_grep_pattern_per_request() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let errors = mcp_errors(&[
+            json!({"tool": "grep", "args": {"index": index_dir, "pattern": "(alpha"}}),
+            json!({"tool": "grep", "args": {"index": index_dir, "pattern": "(alpha", "fixed_strings": true}}),
+        ]);
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(errors[0]["message"].as_str().unwrap().starts_with("Invalid pattern: (alpha"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn syntax_error_offset_finds_unclosed_constructs() {
+        assert_eq!(syntax_error_offset("alpha beta"), None);
+        assert_eq!(syntax_error_offset("title:alpha (beta OR \"x y\")"), None);
+        assert_eq!(syntax_error_offset("say \"hello"), Some(4));
+        assert_eq!(syntax_error_offset("(alpha beta"), Some(0));
+        assert_eq!(syntax_error_offset("alpha) beta"), Some(5));
+        assert_eq!(syntax_error_offset("title: alpha"), Some(5));
+        assert_eq!(syntax_error_offset("a:b:c"), Some(3));
+        assert_eq!(syntax_error_offset("alpha - beta"), Some(6));
+        assert_eq!(syntax_error_offset("alpha -beta"), None);
+    }
+
+    #[test]
+    fn lenient_parse_recovers_and_strict_reports_position() {
+        let (_vault, _tmp, index_dir) = small_index();
+        let index = open_existing_index(&index_dir).unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let count = |q: &dyn tantivy::query::Query| searcher.search(q, &tantivy::collector::Count).unwrap();
+        let lenient = QueryOptions { lenient: true, ..QueryOptions::default() };
+
+        let (q, issues) = parse_user_query_reporting(&index, "\"milestone", &lenient).unwrap();
+        assert_eq!(count(q.as_ref()), 1);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].position, Some(0));
+
+        let (q, issues) = parse_user_query_reporting(&index, "nofield:ownership", &lenient).unwrap();
+        assert_eq!(count(q.as_ref()), 2);
+        assert_eq!(issues[0].position, Some(7));
+
+        let (q, issues) = parse_user_query_reporting(&index, "alpha", &lenient).unwrap();
+        assert_eq!((count(q.as_ref()), issues.len()), (3, 0));
+
+        let err = parse_user_query_reporting(&index, "ünï \"alpha", &QueryOptions::default()).err().unwrap();
+        let json = error_json(&err);
+        assert_eq!(json["error"]["code"], "query_syntax");
+        assert_eq!(json["error"]["position"], 4);
+        assert_eq!(json["error"]["query"], "ünï \"alpha");
+    }
+
+    #[test]
+    fn mcp_vector_options_default_to_recorded_and_fail_per_request() {
+        let (vault, _tmp, index_dir) = small_index();
+        assert!(matches!(mcp_vector_backend(&index_dir, &json!({})).unwrap(), VectorBackend::Bruteforce));
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Hnsw);
+        assert!(matches!(mcp_vector_backend(&index_dir, &json!({})).unwrap(), VectorBackend::Hnsw));
+        assert!(matches!(mcp_vector_backend(&index_dir, &json!({"vector_backend": "bruteforce"})).unwrap(), VectorBackend::Bruteforce));
+
+        let errors = mcp_errors(&[
+            json!({"tool": "vector", "args": {"index": index_dir, "query": "alpha", "embed_backend": "onnx"}}),
+            json!({"tool": "hybrid", "args": {"index": index_dir, "query": "alpha", "vector_backend": "faiss"}}),
+            json!({"tool": "vector", "args": {"index": index_dir, "query": "alpha"}}),
+        ]);
+        let messages: Vec<&str> = errors.iter().map(|e| e["message"].as_str().unwrap()).collect();
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(messages[0].starts_with("Unknown embed_backend onnx"), "{}", messages[0]);
+        assert!(messages[1].starts_with("Unknown vector_backend faiss"), "{}", messages[1]);
+    }
+
+    #[test]
+    fn json_embedding_migration_drops_malformed_rows() {
+        let (vault, _tmp, index_dir) = small_index();
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Bruteforce);
+        let before = chunk_rows(&index_dir);
+
+        // Rewrite the database the way releases before BLOB storage left it.
+        let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+        let rows: Vec<(i64, String, Vec<u8>)> = conn
+            .prepare("SELECT id, path, embedding FROM chunks")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        let python = note_path(vault.path(), "python.md");
+        let journal = note_path(vault.path(), "journal/day.md");
+        let mut malformed = Vec::new();
+        for (id, path, blob) in &rows {
+            let text = if *path == python {
+                "[0.1, oops".to_string()
+            } else if *path == journal {
+                "[]".to_string()
+            } else {
+                serde_json::to_string(&VectorEncoding::F32.decode(blob)).unwrap()
+            };
+            if *path == python || *path == journal {
+                malformed.push(*id);
+            }
+            conn.execute("UPDATE chunks SET embedding = ?1 WHERE id = ?2", params![text, id]).unwrap();
+        }
+        conn.execute("DELETE FROM meta WHERE key = 'vector_encoding'", []).unwrap();
+        drop(conn);
+
+        let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+        assert_eq!(migrate_json_embeddings(&conn).unwrap(), malformed);
+        let texts: i64 = conn.query_row("SELECT COUNT(*) FROM chunks WHERE typeof(embedding) != 'blob'", [], |row| row.get(0)).unwrap();
+        assert_eq!(texts, 0);
+        assert!(!embedded_paths(&index_dir).contains(&python));
+        assert_eq!(get_meta(&conn, "vector_encoding").as_deref(), Some("f32"));
+        assert!(migrate_json_embeddings(&conn).unwrap().is_empty());
+
+        // The dropped notes lost their mtimes, so an incremental run embeds them again.
+        embed_vault(vault.path(), &index_dir, true, VectorBackend::Bruteforce);
+        assert_eq!(chunk_rows(&index_dir), before);
+    }
+
+    fn vector_hits(index_dir: &str, vector_backend: VectorBackend, hnsw_ef: Option<usize>) -> Vec<VectorResult> {
+        embed_search_results(index_dir, "alpha ownership", MAX_PAGE_RESULTS, None, &FolderScope::default(), EmbeddingBackend::Hash, None, vector_backend, hnsw_ef).unwrap()
+    }
+
+    #[test]
+    fn hnsw_returns_as_many_hits_as_brute_force() {
+        let vault = small_vault();
+        for i in 0..120 {
+            write_note(vault.path(), &format!("gen/note{i}.md"), &format!("# Note {i}\n\nGenerated note number {i} about topic {}.\n", i % 7));
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let index_dir = index_in(&tmp);
+        index_vault(vault.path(), &index_dir, false);
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Hnsw);
+
+        let exact = vector_hits(&index_dir, VectorBackend::Bruteforce, None);
+        assert!(exact.len() > HnswOptions::default().ef, "{}", exact.len());
+        for ef in [None, Some(8)] {
+            let approx = vector_hits(&index_dir, VectorBackend::Hnsw, ef);
+            assert_eq!(approx.len(), exact.len(), "hnsw_ef {ef:?}");
+        }
+        let first = embed_search_results(&index_dir, "alpha ownership", 5, None, &FolderScope::default(), EmbeddingBackend::Hash, None, VectorBackend::Hnsw, Some(64)).unwrap();
+        assert_eq!(first.len(), 5);
+    }
+
+    #[test]
+    fn incremental_embed_drops_deleted_notes_and_graph_nodes() {
+        let (vault, _tmp, index_dir) = small_index();
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Hnsw);
+        let python = note_path(vault.path(), "python.md");
+        assert!(embedded_paths(&index_dir).contains(&python));
+
+        fs::remove_file(&python).unwrap();
+        index_vault(vault.path(), &index_dir, true);
+        embed_vault(vault.path(), &index_dir, true, VectorBackend::Hnsw);
+        assert!(!embedded_paths(&index_dir).contains(&python));
+        let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+        let notes: i64 = conn.query_row("SELECT COUNT(*) FROM notes WHERE path = ?1", params![python], |row| row.get(0)).unwrap();
+        assert_eq!(notes, 0);
+        let chunks: usize = conn.query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get::<_, i64>(0)).unwrap() as usize;
+        let graph = Hnsw::load(&hnsw_path(&index_dir)).unwrap().unwrap();
+        assert_eq!(graph.len(), chunks);
+
+        let hits = vector_hits(&index_dir, VectorBackend::Hnsw, None);
+        assert_eq!(hits.len(), chunks);
+        assert!(hits.iter().all(|h| h.path != python));
+    }
+
+    #[test]
+    fn single_folder_range_covers_only_that_folder() {
+        let (vault, _tmp, index_dir) = small_index();
+        let scope = FolderScope::resolve(&index_dir, &["projects/".to_string()]).unwrap();
+        let (start, end) = scope.single_folder_range().unwrap();
+        let inside = |rel: &str| {
+            let path = note_path(vault.path(), rel);
+            start <= path && path < end
+        };
+        assert!(inside("projects/alpha.md") && inside("projects/deep/gamma.md"));
+        assert!(!inside("projects.md") && !inside("projects-old/a.md") && !inside("python.md"));
+
+        for folders in [vec![], vec!["projects", "journal"], vec!["projects", "!projects/beta.md"]] {
+            let folders: Vec<String> = folders.iter().map(|f| f.to_string()).collect();
+            assert!(FolderScope::resolve(&index_dir, &folders).unwrap().single_folder_range().is_none(), "{folders:?}");
+        }
+    }
+
+    /// Needs the sqlite-vec extension; runs when `OBSIDX_VEC0` points at it.
+    #[test]
+    fn vec_backend_matches_brute_force_and_filters_in_knn() {
+        if env::var("OBSIDX_VEC0").is_err() {
+            return;
+        }
+        let (vault, _tmp, index_dir) = small_index();
+        embed_vault(vault.path(), &index_dir, false, VectorBackend::Vec);
+        let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+        load_vec_extension(&conn).unwrap();
+        let (rows, chunks): (i64, i64) = conn
+            .query_row("SELECT (SELECT COUNT(*) FROM vec_chunks), (SELECT COUNT(*) FROM chunks)", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap();
+        assert_eq!(rows, chunks);
+
+        let paths = |hits: Vec<VectorResult>| hits.into_iter().map(|h| h.path).collect::<Vec<_>>();
+        assert_eq!(paths(vector_hits(&index_dir, VectorBackend::Vec, None)), paths(vector_hits(&index_dir, VectorBackend::Bruteforce, None)));
+
+        let scope = FolderScope::resolve(&index_dir, &["projects".to_string()]).unwrap();
+        let hits = embed_search_results(&index_dir, "alpha ownership", 1, None, &scope, EmbeddingBackend::Hash, None, VectorBackend::Vec, None).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].path.starts_with(&note_path(vault.path(), "projects/")), "{}", hits[0].path);
+    }
+
+    #[test]
+    fn embedding_dims_are_recorded_and_enforced() {
+        let (vault, _tmp, index_dir) = small_index();
+        let embed = |incremental: bool, dims: Option<usize>| {
+            let vault = vault.path().to_str().unwrap();
+            embed_index(vault, &index_dir, 200, 20, incremental, None, EmbeddingBackend::Hash, None, dims, VectorBackend::Bruteforce, None, &HnswOptions::default(), &ScanOptions::default())
+        };
+        let stored = || {
+            let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+            let lengths: Vec<i64> = conn.prepare("SELECT DISTINCT length(embedding) FROM chunks").unwrap().query_map([], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect();
+            (index_dims(&conn), lengths)
+        };
+
+        embed(false, None).unwrap();
+        assert_eq!(stored(), (Some(DEFAULT_EMBED_DIMS), vec![DEFAULT_EMBED_DIMS as i64 * 4]));
+        embed(false, Some(48)).unwrap();
+        assert_eq!(stored(), (Some(48), vec![48 * 4]));
+
+        // --incremental keeps the recorded size and refuses to switch it.
+        touch_later(Path::new(&note_path(vault.path(), "python.md")));
+        embed(true, None).unwrap();
+        assert_eq!(stored(), (Some(48), vec![48 * 4]));
+        let err = embed(true, Some(32)).err().unwrap();
+        assert!(err.to_string().contains("Index stores 48-dimensional embeddings"), "{err}");
+        assert!(embed(false, Some(0)).is_err());
+
+        let conn = open_sqlite(&Path::new(&index_dir).join("embeddings.db")).unwrap();
+        let err = insert_chunk(&conn, "x.md", "default", "x", "h", 0, &[0.5; 32], 48, &VectorBackend::Bruteforce, VectorEncoding::F32).err().unwrap();
+        assert!(err.to_string().contains("has 32 dimensions, index expects 48"), "{err}");
+
+        let hits = embed_search_results(&index_dir, "alpha", 3, None, &FolderScope::default(), EmbeddingBackend::Hash, None, VectorBackend::Bruteforce, None).unwrap();
+        assert_eq!(hits.len(), 3);
+        assert!(hits[0].score > 0.0);
+    }
+
+    type Requests = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// Serves HTTP on a local port, answering each request with `respond(body, n)`
+    /// where `n` counts requests from 0. Returns the URL and the bodies received.
+    fn mock_server(respond: impl Fn(&serde_json::Value, usize) -> (u16, String) + Send + 'static) -> (String, Requests) {
+        use std::io::{BufRead, BufReader};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/embeddings", listener.local_addr().unwrap());
+        let requests: Requests = Arc::default();
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                let n = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(body.clone());
+                    seen.len() - 1
+                };
+                let (status, reply) = respond(&body, n);
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reply}",
+                    reply.len()
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn http_embedder(url: String, retries: u32, batch_size: usize) -> HttpEmbedder {
+        HttpEmbedder::new(HttpEmbeddingConfig { url, model: Some("mock".into()), api_key_env: None, timeout_secs: 5, retries, batch_size })
+    }
+
+    fn command_embedder(script: &str, timeout_secs: u64) -> CommandEmbedder {
+        CommandEmbedder {
+            config: CommandEmbeddingConfig {
+                program: "sh".into(),
+                args: vec!["-c".into(), script.into()],
+                model: None,
+                timeout_secs,
+                retries: 0,
+                batch_size: 32,
+            },
+        }
+    }
+
+    #[test]
+    fn http_retries_server_errors() {
+        let (url, requests) = mock_server(|_, n| match n {
+            0 => (503, "busy".into()),
+            _ => (200, json!({"embeddings": [[1.0, 0.0]]}).to_string()),
+        });
+        let vectors = http_embedder(url, 2, 32).embed_batch(&["a"]).unwrap();
+        assert_eq!(vectors, vec![vec![1.0, 0.0]]);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn http_does_not_retry_client_errors() {
+        let (url, requests) = mock_server(|_, _| (400, "bad model".into()));
+        let err = http_embedder(url, 2, 32).embed_batch(&["a"]).unwrap_err();
+        assert!(err.downcast_ref::<NoRetry>().is_some(), "{err}");
+        assert!(err.to_string().contains("HTTP 400"), "{err}");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn http_batches_and_reorders_openai_data() {
+        // Answers OpenAI-style with data[] reversed; vector is [batch request, text length].
+        let (url, requests) = mock_server(|body, n| {
+            let input = body["input"].as_array().unwrap();
+            let data: Vec<_> = input
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, text)| json!({"index": i, "embedding": [n as f32, text.as_str().unwrap().len() as f32]}))
+                .collect();
+            (200, json!({"data": data}).to_string())
+        });
+        let texts = ["a", "bb", "ccc", "dddd", "eeeee"];
+        let vectors = http_embedder(url, 0, 2).embed_batch(&texts).unwrap();
+        let expected: Vec<Vec<f32>> = vec![vec![0.0, 1.0], vec![0.0, 2.0], vec![1.0, 3.0], vec![1.0, 4.0], vec![2.0, 5.0]];
+        assert_eq!(vectors, expected);
+        let sizes: Vec<usize> = requests.lock().unwrap().iter().map(|b| b["input"].as_array().unwrap().len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        assert_eq!(requests.lock().unwrap()[0]["model"], "mock");
+    }
+
+    #[test]
+    fn command_backend_round_trip() {
+        let embedder = command_embedder(r#"cat >/dev/null; echo '{"embeddings": [[0.5, 0.5], [1, 0]]}'"#, 5);
+        assert_eq!(embedder.embed_batch(&["a", "b"]).unwrap(), vec![vec![0.5, 0.5], vec![1.0, 0.0]]);
+    }
+
+    #[test]
+    fn command_backend_kills_on_timeout() {
+        let start = std::time::Instant::now();
+        let err = command_embedder("exec sleep 30", 1).embed_batch(&["a"]).unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn command_backend_reports_non_zero_exit() {
+        let err = command_embedder("cat >/dev/null; exit 3", 5).embed_batch(&["a"]).unwrap_err();
+        assert!(err.to_string().contains("failed with"), "{err}");
+    }
+
+    #[test]
+    fn parse_embeddings_checks_count_and_dims() {
+        let two = json!({"embeddings": [[1.0, 2.0], [3.0, 4.0]]});
+        assert_eq!(parse_embeddings(&two, 2).unwrap().len(), 2);
+        assert!(parse_embeddings(&two, 3).unwrap_err().to_string().contains("2 vectors for 3 texts"));
+        let mixed = json!([[1.0, 2.0], [3.0]]);
+        assert!(parse_embeddings(&mixed, 2).unwrap_err().to_string().contains("mixed-size"));
+        assert!(parse_embeddings(&json!({"vectors": []}), 0).is_err());
+    }
+
+    /// Deterministic pseudo-random vectors (splitmix64), so graph tests are reproducible.
+    fn seeded_vectors(count: usize, dims: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            ((z ^ (z >> 31)) >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+        };
+        (0..count).map(|_| (0..dims).map(|_| next()).collect()).collect()
+    }
+
+    fn exact_top_k(vectors: &[Vec<f32>], query: &[f32], k: usize, filter: impl Fn(i64) -> bool) -> Vec<i64> {
+        let q = normalized(query);
+        let mut scored: Vec<(f32, i64)> = vectors
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| filter(*i as i64))
+            .map(|(i, v)| (dot(&q, &normalized(v)), i as i64))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(k).map(|(_, id)| id).collect()
+    }
+
+    fn build_graph(vectors: &[Vec<f32>]) -> Hnsw {
+        let mut graph = Hnsw::new(HnswOptions::default());
+        for (id, v) in vectors.iter().enumerate() {
+            graph.insert(id as i64, v, 0).unwrap();
+        }
+        graph
+    }
+
+    fn recall(graph: &Hnsw, vectors: &[Vec<f32>], queries: &[Vec<f32>], filter: &dyn Fn(i64) -> bool) -> f32 {
+        let (mut found, mut expected) = (0, 0);
+        for q in queries {
+            let truth = exact_top_k(vectors, q, 10, filter);
+            let approx = graph.search(q, 10, graph.opts.ef, filter);
+            found += approx.iter().filter(|(id, _)| truth.contains(id)).count();
+            expected += truth.len();
+        }
+        found as f32 / expected as f32
+    }
+
+    #[test]
+    fn hnsw_recall_against_exact_search() {
+        let vectors = seeded_vectors(2000, 24, 7);
+        let queries = seeded_vectors(50, 24, 99);
+        let graph = build_graph(&vectors);
+        assert_eq!(graph.len(), 2000);
+        let r = recall(&graph, &vectors, &queries, &|_| true);
+        assert!(r >= 0.9, "recall@10 {r}");
+    }
+
+    #[test]
+    fn hnsw_filtered_search() {
+        let vectors = seeded_vectors(1000, 16, 3);
+        let queries = seeded_vectors(20, 16, 4);
+        let graph = build_graph(&vectors);
+        let even = |id: i64| id % 2 == 0;
+        for q in &queries {
+            let hits = graph.search(q, 10, graph.opts.ef, &even);
+            assert_eq!(hits.len(), 10);
+            assert!(hits.iter().all(|(id, _)| even(*id)));
+            assert!(hits.windows(2).all(|w| w[0].1 >= w[1].1));
+        }
+        let r = recall(&graph, &vectors, &queries, &even);
+        assert!(r >= 0.85, "filtered recall@10 {r}");
+    }
+
+    #[test]
+    fn hnsw_sync_removes_and_compacts() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE chunks (id INTEGER PRIMARY KEY, embedding BLOB);").unwrap();
+        let vectors = seeded_vectors(200, 16, 11);
+        for (id, v) in vectors.iter().enumerate() {
+            conn.execute("INSERT INTO chunks (id, embedding) VALUES (?1, ?2)", params![id as i64, VectorEncoding::F32.encode(v)]).unwrap();
+        }
+        let mut graph = Hnsw::new(HnswOptions::default());
+        let sync = graph.sync(&conn).unwrap();
+        assert_eq!((sync.added, sync.removed, sync.compacted), (200, 0, false));
+
+        // A few deletes only leave tombstones.
+        conn.execute("DELETE FROM chunks WHERE id < 20", []).unwrap();
+        let sync = graph.sync(&conn).unwrap();
+        assert_eq!((sync.added, sync.removed, sync.compacted), (0, 20, false));
+        assert_eq!((graph.len(), graph.deleted), (180, 20));
+
+        // Crossing a quarter of the graph rebuilds it without them.
+        conn.execute("DELETE FROM chunks WHERE id < 60", []).unwrap();
+        let sync = graph.sync(&conn).unwrap();
+        assert_eq!((sync.removed, sync.compacted), (40, true));
+        assert_eq!((graph.len(), graph.deleted, graph.nodes.len()), (140, 0, 140));
+
+        // A re-embedded row is picked up by its changed fingerprint.
+        conn.execute("UPDATE chunks SET embedding = ?1 WHERE id = 100", params![VectorEncoding::F32.encode(&vectors[0])]).unwrap();
+        let sync = graph.sync(&conn).unwrap();
+        assert_eq!(sync.added, 1);
+        assert_eq!(graph.search(&vectors[0], 1, 64, &|_| true)[0].0, 100);
+        for q in seeded_vectors(10, 16, 12) {
+            assert!(graph.search(&q, 10, 64, &|_| true).iter().all(|(id, _)| *id >= 60));
+        }
+    }
+
+    #[test]
+    fn hnsw_save_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HNSW_FILE);
+        assert!(Hnsw::load(&path).unwrap().is_none());
+
+        let vectors = seeded_vectors(300, 16, 21);
+        let mut graph = build_graph(&vectors);
+        graph.remove(5);
+        graph.save(&path).unwrap();
+        let loaded = Hnsw::load(&path).unwrap().unwrap();
+        assert_eq!((loaded.len(), loaded.dims, loaded.deleted, loaded.entry), (graph.len(), graph.dims, graph.deleted, graph.entry));
+        for q in seeded_vectors(10, 16, 22) {
+            assert_eq!(loaded.search(&q, 10, 64, &|_| true), graph.search(&q, 10, 64, &|_| true));
+        }
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 7]).unwrap();
+        let err = Hnsw::load(&path).err().unwrap();
+        assert!(err.to_string().contains("Corrupt HNSW graph"), "{err}");
+        fs::write(&path, b"not a graph").unwrap();
+        assert!(Hnsw::load(&path).is_err());
+    }
+}